@@ -0,0 +1,49 @@
+//! Times the interpreter on a loop-heavy program. Run with `cargo bench`.
+//!
+//! Set `RVM_BASELINE` to another rusty-vm binary, e.g. a release build of
+//! the commit before instructions were pre-decoded, to time it on the same
+//! program as a baseline.
+
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+const RUNS: usize = 5;
+
+fn run_once(binary: &str, flags: &[&str], program: &str) -> Duration {
+    let start = Instant::now();
+    let status = Command::new(binary)
+        .args(flags)
+        .arg(program)
+        .stdout(std::process::Stdio::null())
+        .status()
+        .expect("failed to run rusty-vm");
+    assert!(status.success());
+    start.elapsed()
+}
+
+fn bench(name: &str, binary: &str, flags: &[&str], program: &str) {
+    // Warm up the page cache and the binary before measuring
+    run_once(binary, flags, program);
+
+    let mut times: Vec<Duration> = (0..RUNS).map(|_| run_once(binary, flags, program)).collect();
+    times.sort();
+    println!(
+        "{}: best {:.1} ms, median {:.1} ms over {} runs",
+        name,
+        times[0].as_secs_f64() * 1e3,
+        times[RUNS / 2].as_secs_f64() * 1e3,
+        RUNS
+    );
+}
+
+fn main() {
+    let program = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/loop.vm");
+    let binary = env!("CARGO_BIN_EXE_rusty-vm");
+    if let Ok(baseline) = std::env::var("RVM_BASELINE") {
+        bench("benches/loop.vm (baseline)", &baseline, &[], program);
+    }
+    bench("benches/loop.vm", binary, &[], program);
+    bench("benches/loop.vm --no-fuse", binary, &["--no-fuse"], program);
+    #[cfg(feature = "jit")]
+    bench("benches/loop.vm --jit", binary, &["--jit"], program);
+}
@@ -1,19 +1,353 @@
+use std::collections::HashMap;
 use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::time::{Duration, Instant};
 
+mod rvm_aot;
+mod rvm_coverage;
+mod rvm_debug;
 mod rvm_file;
+mod rvm_fuse;
+mod rvm_gas;
+mod rvm_gdb;
+mod rvm_history;
 mod rvm_htab;
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+mod rvm_jit;
 mod rvm_lex;
 mod rvm_preprocessor;
 mod rvm_memory;
+mod rvm_peephole;
 mod rvm_prog;
+mod rvm_profile;
+mod rvm_snapshot;
+mod rvm_trace;
+mod rvm_word;
 mod rvm;
 
+use rvm::RvmRunResult;
+use rvm_coverage::RvmCoverage;
+use rvm_gas::RvmGas;
+use rvm_profile::RvmProfiler;
+use rvm_trace::{RvmTraceFormat, RvmTracer};
+
+type Opts = HashMap<String, String>;
+
+enum Mode {
+    Run(Opts),
+    Debug,
+    Asm(Opts),
+    Aot(Opts),
+    GdbServer(String),
+    Trace(Opts),
+    Profile(Opts),
+    Coverage(Opts),
+}
+
+fn usage() -> ! {
+    eprintln!("Usage: rusty-vm [--no-fuse | --jit] [--gas n [--gas-costs file]] [--timeout ms]");
+    eprintln!("                [--restore snapshot] [--save snapshot] <file.vm>");
+    eprintln!("       rusty-vm asm [-O] [-o file] <file.vm>");
+    eprintln!("       rusty-vm aot [-o file.rs] <file.vm>");
+    eprintln!("       rusty-vm debug <file.vm>");
+    eprintln!("       rusty-vm gdbserver <host:port | unix:path> <file.vm>");
+    eprintln!("       rusty-vm trace [--format json|bin] [--from label] [--to label] [-o file] <file.vm>");
+    eprintln!("       rusty-vm profile [--folded file] [-o file] <file.vm>");
+    eprintln!("       rusty-vm coverage [-o file.info] <file.vm>");
+    process::exit(1);
+}
+
+// Split `[flag value | switch]... file` into the options and the file name;
+// switches take no value and are stored with an empty one
+fn parse_opts<'a>(args: &'a [String], flags: &[&str], switches: &[&str]) -> (Opts, &'a String) {
+    let mut opts = Opts::new();
+    let mut i = 0;
+    while i + 1 < args.len() {
+        if switches.contains(&args[i].as_str()) {
+            opts.insert(args[i].clone(), String::new());
+            i += 1;
+            continue;
+        }
+        if !flags.contains(&args[i].as_str()) {
+            usage();
+        }
+        opts.insert(args[i].clone(), args[i + 1].clone());
+        i += 2;
+    }
+    match args.get(i) {
+        Some(filename) if i + 1 == args.len() => (opts, filename),
+        _ => usage(),
+    }
+}
+
+// "-" (the default) means stderr, since stdout carries the program output
+fn open_output(opts: &Opts, flag: &str) -> io::Result<Box<dyn Write>> {
+    match opts.get(flag).map(String::as_str) {
+        None | Some("-") => Ok(Box::new(io::stderr())),
+        Some(path) => Ok(Box::new(File::create(path)?)),
+    }
+}
+
+// Like open_output, but for commands whose output is the point: "-" means stdout
+fn open_listing(opts: &Opts) -> io::Result<Box<dyn Write>> {
+    match opts.get("-o").map(String::as_str) {
+        None | Some("-") => Ok(Box::new(io::stdout())),
+        Some(path) => Ok(Box::new(File::create(path)?)),
+    }
+}
+
+// Print the assembled program, optimized first with -O
+fn assemble(vm: &mut rvm::RvmCtx, opts: &Opts) -> io::Result<()> {
+    if opts.contains_key("-O") {
+        let report = rvm_peephole::rvm_peephole(&mut vm.prog);
+        report.rvm_peephole_report(&mut io::stderr())?;
+    }
+    vm.prog.rvm_prog_listing(&mut open_listing(opts)?)
+}
+
+/* Run the program with `run` until it halts or stops for good: yields and
+ * breakpoints are resumed and input the program waits for is read from
+ * stdin, one number per line. */
+fn drive(vm: &mut rvm::RvmCtx, mut run: impl FnMut(&mut rvm::RvmCtx) -> RvmRunResult) -> io::Result<RvmRunResult> {
+    let stdin = io::stdin();
+    let mut line = String::new();
+    loop {
+        match run(vm) {
+            RvmRunResult::Yielded | RvmRunResult::Breakpoint => {}
+            RvmRunResult::WaitingForInput => {
+                line.clear();
+                if stdin.lock().read_line(&mut line)? == 0 {
+                    return Err(io::Error::other(format!("out of input at instruction {}", vm.rvm_vm_eip())));
+                }
+                let val = line.trim().parse()
+                    .map_err(|_| io::Error::other(format!("invalid input: {}", line.trim())))?;
+                vm.rvm_vm_feed_input(val);
+            }
+            result => return Ok(result),
+        }
+    }
+}
+
+#[cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+fn run_jit(vm: &mut rvm::RvmCtx) -> io::Result<RvmRunResult> {
+    let jit = rvm_jit::RvmJit::new(vm)?;
+    drive(vm, |vm| jit.rvm_jit_run(vm))
+}
+
+#[cfg(not(all(feature = "jit", target_arch = "x86_64", target_os = "linux")))]
+fn run_jit(_vm: &mut rvm::RvmCtx) -> io::Result<RvmRunResult> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "not built with the jit feature on x86-64 Linux"))
+}
+
+/* Run the program, from a --restore snapshot if given, metered with --gas,
+ * limited by --timeout, fused unless --no-fuse, native with --jit. If it
+ * stops without halting, its state is written to the --save snapshot. */
+fn run(vm: &mut rvm::RvmCtx, opts: &Opts) -> Result<RvmRunResult, String> {
+    if let Some(path) = opts.get("--restore") {
+        File::open(path)
+            .and_then(|mut file| rvm_snapshot::rvm_snapshot_restore(vm, &mut file))
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    let result = run_from_eip(vm, opts)?;
+    if let Some(path) = opts.get("--save")
+        && !matches!(result, RvmRunResult::Halted(_)) {
+        File::create(path)
+            .and_then(|mut file| rvm_snapshot::rvm_snapshot_save(vm, &mut file))
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(result)
+}
+
+fn run_from_eip(vm: &mut rvm::RvmCtx, opts: &Opts) -> Result<RvmRunResult, String> {
+    if opts.contains_key("--gas-costs") && !opts.contains_key("--gas") {
+        return Err("--gas-costs needs a --gas budget".to_string());
+    }
+    if opts.contains_key("--jit") {
+        if let Some(flag) = ["--gas", "--timeout"].into_iter().find(|flag| opts.contains_key(*flag)) {
+            return Err(format!("{} cannot be combined with --jit", flag));
+        }
+        return run_jit(vm).map_err(|e| format!("jit: {}", e));
+    }
+    if let Some(budget) = opts.get("--gas") {
+        let budget = budget.parse().map_err(|_| format!("invalid gas budget: {}", budget))?;
+        let mut gas = RvmGas::new(budget);
+        if let Some(path) = opts.get("--gas-costs") {
+            gas.rvm_gas_load_costs(path)?;
+        }
+        vm.gas = Some(gas);
+    }
+    // The other modes report on every instruction, so only plain runs are fused
+    if !opts.contains_key("--no-fuse") {
+        rvm_fuse::rvm_fuse(&mut vm.prog);
+    }
+    let result = match opts.get("--timeout") {
+        Some(ms) => {
+            let ms = ms.parse().map_err(|_| format!("invalid timeout: {}", ms))?;
+            // Time spent waiting for input counts too
+            let deadline = Instant::now() + Duration::from_millis(ms);
+            drive(vm, |vm| vm.rvm_vm_run_for(deadline.saturating_duration_since(Instant::now())))
+        }
+        None => drive(vm, rvm::RvmCtx::rvm_vm_run),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+fn run_traced(vm: &mut rvm::RvmCtx, opts: &Opts) -> io::Result<RvmRunResult> {
+    let format = match opts.get("--format").map(String::as_str) {
+        None | Some("json") | Some("jsonl") => RvmTraceFormat::Json,
+        Some("bin") => RvmTraceFormat::Binary,
+        Some(_) => usage(),
+    };
+    let mut location = |opt: &str, default: i32| match opts.get(opt) {
+        Some(loc) => vm.rvm_resolve_location(loc).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e)),
+        None => Ok(default),
+    };
+    let from = location("--from", 0)?;
+    let to = location("--to", i32::MAX)?;
+    let mut tracer = RvmTracer::new(open_output(opts, "-o")?, format, from, to);
+    tracer.rvm_trace_begin()?;
+    let result = drive(vm, |vm| vm.rvm_vm_run_hooked(&mut tracer))?;
+    tracer.rvm_trace_finish()?;
+    Ok(result)
+}
+
+fn run_profiled(vm: &mut rvm::RvmCtx, opts: &Opts) -> io::Result<RvmRunResult> {
+    let mut profiler = RvmProfiler::new(vm);
+    let result = drive(vm, |vm| vm.rvm_vm_run_hooked(&mut profiler))?;
+    profiler.rvm_profile_report(vm, &mut open_output(opts, "-o")?)?;
+    if let Some(path) = opts.get("--folded") {
+        profiler.rvm_profile_folded(vm, &mut File::create(path)?)?;
+    }
+    Ok(result)
+}
+
+fn run_covered(vm: &mut rvm::RvmCtx, opts: &Opts) -> io::Result<RvmRunResult> {
+    let mut coverage = RvmCoverage::new(vm);
+    let result = drive(vm, |vm| vm.rvm_vm_run_hooked(&mut coverage))?;
+    coverage.rvm_coverage_lcov(vm, &mut open_output(opts, "-o")?)?;
+    Ok(result)
+}
+
+// Report why the program stopped, if it did not simply halt, and exit with a matching status
+fn finish(vm: &rvm::RvmCtx, result: RvmRunResult) {
+    match result {
+        RvmRunResult::Halted(0) => {}
+        RvmRunResult::Halted(code) => process::exit(code),
+        RvmRunResult::OutOfGas => {
+            let gas = vm.gas.as_ref().unwrap();
+            eprintln!("Out of gas at instruction {} after {} instructions ({} gas)",
+                vm.rvm_vm_eip(), gas.instructions, gas.used);
+            process::exit(2);
+        }
+        RvmRunResult::Interrupted => {
+            eprintln!("Timed out at instruction {}", vm.rvm_vm_eip());
+            process::exit(3);
+        }
+        RvmRunResult::Fault(fault) => {
+            eprintln!("Fault at instruction {}: {}", vm.rvm_vm_eip(), fault);
+            process::exit(4);
+        }
+        // drive resumes these
+        RvmRunResult::Yielded | RvmRunResult::Breakpoint | RvmRunResult::WaitingForInput => unreachable!(),
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
+    let (mode, filename) = match args.get(1).map(String::as_str) {
+        Some("aot") => {
+            let (opts, filename) = parse_opts(&args[2..], &["-o"], &[]);
+            (Mode::Aot(opts), filename)
+        }
+        Some("debug") if args.len() == 3 => (Mode::Debug, &args[2]),
+        Some("asm") => {
+            let (opts, filename) = parse_opts(&args[2..], &["-o"], &["-O"]);
+            (Mode::Asm(opts), filename)
+        }
+        Some("gdbserver") if args.len() == 4 => (Mode::GdbServer(args[2].clone()), &args[3]),
+        Some("trace") => {
+            let (opts, filename) = parse_opts(&args[2..], &["--format", "--from", "--to", "-o"], &[]);
+            (Mode::Trace(opts), filename)
+        }
+        Some("profile") => {
+            let (opts, filename) = parse_opts(&args[2..], &["--folded", "-o"], &[]);
+            (Mode::Profile(opts), filename)
+        }
+        Some("coverage") => {
+            let (opts, filename) = parse_opts(&args[2..], &["-o"], &[]);
+            (Mode::Coverage(opts), filename)
+        }
+        _ => {
+            let (opts, filename) = parse_opts(&args[1..], &["--gas", "--gas-costs", "--timeout", "--restore", "--save"], &["--no-fuse", "--jit"]);
+            (Mode::Run(opts), filename)
+        }
+    };
+
     let mut vm = rvm::RvmCtx::new();
 
-    if vm.rvm_vm_interpret(args[1].as_str()) == 0 {
-        vm.rvm_vm_run();
+    if vm.rvm_vm_interpret(filename) != 0 {
+        process::exit(1);
+    }
+
+    match mode {
+        Mode::Run(opts) => match run(&mut vm, &opts) {
+            Ok(result) => finish(&vm, result),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        },
+        Mode::Aot(opts) => {
+            if let Err(e) = open_listing(&opts).and_then(|mut out| rvm_aot::rvm_aot_rust(&vm, &mut out)) {
+                eprintln!("aot: {}", e);
+                process::exit(1);
+            }
+        }
+        Mode::Debug => rvm_debug::rvm_dbg_repl(&mut vm),
+        Mode::Asm(opts) => {
+            if let Err(e) = assemble(&mut vm, &opts) {
+                eprintln!("asm: {}", e);
+                process::exit(1);
+            }
+        }
+        Mode::GdbServer(addr) => {
+            let res = rvm_gdb::rvm_gdb_check(&vm)
+                .and_then(|_| rvm_gdb::rvm_gdb_accept(&addr))
+                .and_then(|stream| rvm_gdb::RvmGdbServer::new(stream).rvm_gdb_serve(&mut vm));
+            if let Err(e) = res {
+                eprintln!("gdbserver: {}", e);
+                process::exit(1);
+            }
+        }
+        Mode::Trace(opts) => {
+            match run_traced(&mut vm, &opts) {
+                Ok(result) => finish(&vm, result),
+                Err(e) => {
+                    eprintln!("trace: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        Mode::Profile(opts) => {
+            match run_profiled(&mut vm, &opts) {
+                Ok(result) => finish(&vm, result),
+                Err(e) => {
+                    eprintln!("profile: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        Mode::Coverage(opts) => {
+            match run_covered(&mut vm, &opts) {
+                Ok(result) => finish(&vm, result),
+                Err(e) => {
+                    eprintln!("coverage: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
     }
 }
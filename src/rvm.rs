@@ -1,45 +1,350 @@
 use crate::rvm_file;
-use crate::rvm_htab;
+use crate::rvm_gas::{rvm_gas_parts_done, RvmGas};
 use crate::rvm_lex;
+use crate::rvm_memory::RvmAccess;
 use crate::rvm_memory::RvmMem;
 use crate::rvm_memory::RvmRegU;
+use crate::rvm_memory::RVM_DATA_BASE;
 use crate::rvm_preprocessor;
-use crate::rvm_prog::RvmProg;
-use std::collections::HashMap;
+use crate::rvm_prog::{RvmArg, RvmInstr, RvmJumpCond, RvmMode, RvmProg, RvmRegPart, RvmRep, RvmSection, RvmSrcLoc};
+use crate::rvm_word::RvmWord;
+use std::collections::VecDeque;
+use std::fmt;
 use std::fs::File;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
 
-const RvmOpcodeMap : [&str; 32] = [
+#[allow(non_upper_case_globals)]
+pub const RvmOpcodeMap : [&str; 67] = [
     "nop", "int", "mov",
     "push", "pop", "pushf", "popf",
     "inc", "dec", "add", "sub", "mul", "div", "mod", "rem",
     "not", "xor", "or", "and", "shl", "shr",
     "cmp", "jmp", "call", "ret",
     "je", "jne", "jg", "jge", "jl", "jle",
-    "prn"
+    "prn",
+    // Not in TinyVM
+    "movsx", "neg", "sar", "rol", "ror",
+    "test", "xchg", "lea", "umul", "udiv", "umod",
+    "enter", "leave",
+    "loop", "loopz", "loopnz",
+    "movsb", "movsw", "stosb", "stosw",
+    "cmpsb", "cmpsw", "scasb", "scasw",
+    "fld", "fst", "fadd", "fsub", "fmul", "fdiv", "fsqrt",
+    "fcmp", "itof", "ftoi", "fprn"
 ];
 
-const RvmRegisterMap : [&str; 17] = [
+/* Other names accepted for some instructions */
+#[allow(non_upper_case_globals)]
+pub const RvmOpcodeAliases : [(&str, &str); 4] = [
+    ("imul", "mul"), ("idiv", "div"), ("loope", "loopz"), ("loopne", "loopnz")
+];
+
+#[allow(non_upper_case_globals)]
+pub const RvmRegisterMap : [&str; 17] = [
     "eax", "ebx", "ecx", "edx",
     "esi", "edi", "esp", "ebp",
     "eip", "r08", "r09", "r10", "r11",
     "r12", "r13", "r14", "r15"
 ];
+
+/* The same registers in 64-bit mode, where the names above are their
+ * low halves */
+#[allow(non_upper_case_globals)]
+pub const RvmRegister64Map : [&str; 17] = [
+    "rax", "rbx", "rcx", "rdx",
+    "rsi", "rdi", "rsp", "rbp",
+    "rip", "r8", "r9", "r10", "r11",
+    "r12", "r13", "r14", "r15"
+];
+
+#[allow(non_upper_case_globals)]
+pub const RvmFloatRegisterMap : [&str; 16] = [
+    "f0", "f1", "f2", "f3", "f4", "f5", "f6", "f7",
+    "f8", "f9", "f10", "f11", "f12", "f13", "f14", "f15"
+];
+
+// Registers the stack frame instructions maintain
+const RVM_REG_ESP: usize = 0x6;
+const RVM_REG_EBP: usize = 0x7;
+// Registers the loop and string instructions count and step with
+const RVM_REG_ECX: usize = 0x2;
+const RVM_REG_ESI: usize = 0x4;
+const RVM_REG_EDI: usize = 0x5;
+
+// Directives that lay out the data sections
+const TOK_SECTION: &str = "section";
+const TOK_TIMES: &str = "times";
+const TOK_ALIGN: &str = "align";
+
+/* Directives that store values, and those that reserve zeroed units,
+ * with how many bytes each value or unit takes */
+#[allow(non_upper_case_globals)]
+pub const RvmDataMap : [(&str, usize); 3] = [("db", 1), ("dw", 2), ("dd", 4)];
+#[allow(non_upper_case_globals)]
+pub const RvmReserveMap : [(&str, usize); 3] = [("resb", 1), ("resw", 2), ("resd", 4)];
+
+/* 16- and 8-bit names for parts of the registers above, and the 32-bit
+ * ones only 64-bit mode has */
+#[allow(non_upper_case_globals)]
+pub const RvmSubRegisterMap : [(&str, usize, RvmRegPart); 48] = [
+    ("ax", 0x0, RvmRegPart::Word), ("al", 0x0, RvmRegPart::Low), ("ah", 0x0, RvmRegPart::High),
+    ("bx", 0x1, RvmRegPart::Word), ("bl", 0x1, RvmRegPart::Low), ("bh", 0x1, RvmRegPart::High),
+    ("cx", 0x2, RvmRegPart::Word), ("cl", 0x2, RvmRegPart::Low), ("ch", 0x2, RvmRegPart::High),
+    ("dx", 0x3, RvmRegPart::Word), ("dl", 0x3, RvmRegPart::Low), ("dh", 0x3, RvmRegPart::High),
+    ("si", 0x4, RvmRegPart::Word), ("di", 0x5, RvmRegPart::Word),
+    ("sp", 0x6, RvmRegPart::Word), ("bp", 0x7, RvmRegPart::Word),
+    ("r08w", 0x9, RvmRegPart::Word), ("r08b", 0x9, RvmRegPart::Low),
+    ("r09w", 0xA, RvmRegPart::Word), ("r09b", 0xA, RvmRegPart::Low),
+    ("r10w", 0xB, RvmRegPart::Word), ("r10b", 0xB, RvmRegPart::Low),
+    ("r11w", 0xC, RvmRegPart::Word), ("r11b", 0xC, RvmRegPart::Low),
+    ("r12w", 0xD, RvmRegPart::Word), ("r12b", 0xD, RvmRegPart::Low),
+    ("r13w", 0xE, RvmRegPart::Word), ("r13b", 0xE, RvmRegPart::Low),
+    ("r14w", 0xF, RvmRegPart::Word), ("r14b", 0xF, RvmRegPart::Low),
+    ("r15w", 0x10, RvmRegPart::Word), ("r15b", 0x10, RvmRegPart::Low),
+    ("eax", 0x0, RvmRegPart::Dword), ("ebx", 0x1, RvmRegPart::Dword),
+    ("ecx", 0x2, RvmRegPart::Dword), ("edx", 0x3, RvmRegPart::Dword),
+    ("esi", 0x4, RvmRegPart::Dword), ("edi", 0x5, RvmRegPart::Dword),
+    ("esp", 0x6, RvmRegPart::Dword), ("ebp", 0x7, RvmRegPart::Dword),
+    ("r8d", 0x9, RvmRegPart::Dword), ("r9d", 0xA, RvmRegPart::Dword),
+    ("r10d", 0xB, RvmRegPart::Dword), ("r11d", 0xC, RvmRegPart::Dword),
+    ("r12d", 0xD, RvmRegPart::Dword), ("r13d", 0xE, RvmRegPart::Dword),
+    ("r14d", 0xF, RvmRegPart::Dword), ("r15d", 0x10, RvmRegPart::Dword),
+];
+/* Observes every instruction executed by rvm_vm_run_hooked, together
+ * with the register and memory accesses it made. */
+pub trait RvmHook {
+    fn rvm_hook_step(&mut self, vm: &RvmCtx, instr_idx: i32, accesses: &[RvmAccess]);
+}
+
+/* Bits of the flags register. `cmp` sets the first two and clears the
+ * others; arithmetic sets carry and overflow and keeps the first two. */
+pub const RVM_FLAG_EQUAL: u32 = 0x1;
+pub const RVM_FLAG_GREATER: u32 = 0x2;
+pub const RVM_FLAG_CARRY: u32 = 0x4;
+pub const RVM_FLAG_OVERFLOW: u32 = 0x8;
+
+// Flags left by `cmp`: bit 0 set when equal, bit 1 when greater
+pub fn rvm_cmp_flags<T: PartialOrd>(val1: T, val2: T) -> u32 {
+    (if val1 == val2 { RVM_FLAG_EQUAL } else { 0 }) | (if val1 > val2 { RVM_FLAG_GREATER } else { 0 })
+}
+
+// Flags left by `fcmp`: like `cmp`, or only carry when either side is NaN
+pub fn rvm_fcmp_flags(val1: f64, val2: f64) -> u32 {
+    if val1.is_nan() || val2.is_nan() {
+        return RVM_FLAG_CARRY;
+    }
+    (if val1 == val2 { RVM_FLAG_EQUAL } else { 0 }) | (if val1 > val2 { RVM_FLAG_GREATER } else { 0 })
+}
+
+/* Instructions computed by rvm_alu */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RvmAluOp {
+    Add,
+    Sub,
+    Mul,
+    Shl,
+    Shr,
+    Sar,
+    Rol,
+    Ror,
+    Umul,
+}
+
+/* `a op b` on operands `width` bits wide, wrapping around at that width.
+ * Returns the result, zero-extended, and the carry and overflow flags it
+ * sets:
+ *
+ *   add       carry on unsigned overflow, overflow on signed overflow
+ *   sub       carry on borrow, overflow on signed overflow
+ *   mul       both when the signed product does not fit
+ *   umul      both when the unsigned product does not fit
+ *   shl, shr  carry is the last bit shifted out, overflow when the sign changed
+ *   sar       carry is the last bit shifted out, overflow is clear
+ *   rol, ror  carry is the bit rotated into the low (rol) or high (ror) end,
+ *             overflow when the sign changed
+ *
+ * Shift and rotate counts are masked to 5 bits, or 6 for 64-bit operands,
+ * and rotations then go round modulo the width. A count of 0 leaves the flags
+ * alone and returns None for them. */
+macro_rules! rvm_alu_fn {
+    ($name:ident, $int:ty, $uint:ty, $wide:ty, $swide:ty, $count_mask:expr) => {
+        pub fn $name(op: RvmAluOp, a: $int, b: $int, width: u32) -> ($int, Option<u32>) {
+            let mask: $wide = (1 << width) - 1;
+            let sign: $wide = 1 << (width - 1);
+            let (ua, ub) = (a as $uint as $wide & mask, b as $uint as $wide & mask);
+            let signed = |val: $wide| (val ^ sign).wrapping_sub(sign) as $swide;
+            let count = b as u32 & ($count_mask)(width);
+
+            let (res, carry, overflow) = match op {
+                RvmAluOp::Add => {
+                    let res = (ua + ub) & mask;
+                    (res, ua + ub > mask, (ua ^ res) & (ub ^ res) & sign != 0)
+                }
+                RvmAluOp::Sub => {
+                    let res = ua.wrapping_sub(ub) & mask;
+                    (res, ua < ub, (ua ^ ub) & (ua ^ res) & sign != 0)
+                }
+                RvmAluOp::Mul => {
+                    let product = signed(ua) * signed(ub);
+                    let res = product as $wide & mask;
+                    let lost = product != signed(res);
+                    (res, lost, lost)
+                }
+                RvmAluOp::Umul => {
+                    let product = ua * ub;
+                    (product & mask, product > mask, product > mask)
+                }
+                _ if count == 0 => return (ua as $uint as $int, None),
+                RvmAluOp::Shl => {
+                    let wide = ua << count;
+                    let res = wide & mask;
+                    (res, (wide >> width) & 1 != 0, (ua ^ res) & sign != 0)
+                }
+                RvmAluOp::Shr => {
+                    let res = ua >> count;
+                    (res, (ua >> (count - 1)) & 1 != 0, (ua ^ res) & sign != 0)
+                }
+                RvmAluOp::Sar => {
+                    let val = signed(ua);
+                    ((val >> count) as $wide & mask, (val >> (count - 1)) & 1 != 0, false)
+                }
+                RvmAluOp::Rol | RvmAluOp::Ror => {
+                    let by = if op == RvmAluOp::Rol { count % width } else { (width - count % width) % width };
+                    let res = ((ua << by) | (ua >> ((width - by) % width))) & mask;
+                    let carry = if op == RvmAluOp::Rol { res & 1 } else { res & sign };
+                    (res, carry != 0, (ua ^ res) & sign != 0)
+                }
+            };
+            let flags = if carry { RVM_FLAG_CARRY } else { 0 } | if overflow { RVM_FLAG_OVERFLOW } else { 0 };
+            (res as $uint as $int, Some(flags))
+        }
+    };
+}
+
+// For 32-bit programs, and for 64-bit ones with intermediates twice as wide
+rvm_alu_fn!(rvm_alu, i32, u32, u64, i64, |_| 31);
+rvm_alu_fn!(rvm_alu64, i64, u64, u128, i128, |width| if width == 64 { 63 } else { 31 });
+
+// Instructions run between checks for an interrupt
+const RVM_INTERRUPT_INTERVAL: u32 = 1024;
+
+/* Services a program asks the host for with `int n` */
+pub const RVM_INT_EXIT: i32 = 0;    // end the program with the exit code in eax
+pub const RVM_INT_YIELD: i32 = 1;   // hand control back to the host
+pub const RVM_INT_READ: i32 = 2;    // read the next input value into eax
+pub const RVM_INT_BREAK: i32 = 3;   // stop as if at a breakpoint
+
+// Returned by rvm_exec when the instruction left a trap to report instead of a next index
+const RVM_TRAP: i32 = -1;
+
+/* An instruction that cannot be executed; eip stays on it */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RvmFault {
+    DivideByZero,
+    /* The most negative value divided by -1, whose quotient does not fit */
+    DivideOverflow,
+    /* Control was transferred to an index outside the program */
+    BadJump(i32),
+    BadInterrupt(i32),
+    /* A load, store or stack slot at least partly outside memory */
+    BadAddress(usize),
+}
+
+impl fmt::Display for RvmFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RvmFault::DivideByZero => write!(f, "division by zero"),
+            RvmFault::DivideOverflow => write!(f, "division overflow"),
+            RvmFault::BadJump(target) => write!(f, "jump to invalid instruction {}", target),
+            RvmFault::BadInterrupt(n) => write!(f, "unknown interrupt {}", n),
+            RvmFault::BadAddress(addr) => write!(f, "access to invalid address {}", addr),
+        }
+    }
+}
+
+/* Why rvm_vm_run returned. Except for Halted and Fault, the program can be
+ * resumed by running it again; eip is left at the next instruction to run. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RvmRunResult {
+    /* The program ran off its end or asked to exit, with this exit code */
+    Halted(i32),
+    Breakpoint,
+    Yielded,
+    OutOfGas,
+    Fault(RvmFault),
+    /* `int 2` found no input; feed some with rvm_vm_feed_input and resume */
+    WaitingForInput,
+    /* Stopped through an RvmInterruptHandle or at the end of rvm_vm_run_for */
+    Interrupted,
+}
+
+/* Stops a running rvm_vm_run from any thread. The run returns
+ * RvmRunResult::Interrupted within a few instructions, with eip at the next
+ * instruction to execute, and picks up from there when run again. An
+ * interrupt sent while nothing runs stops the next run instead. */
+#[derive(Clone)]
+pub struct RvmInterruptHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl RvmInterruptHandle {
+    pub fn rvm_interrupt(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+}
+
+// RvmArg::rvm_width in the mode whose words are W
+fn rvm_width_in<W: RvmWord>(arg: &RvmArg) -> u32 {
+    match arg {
+        RvmArg::Sub(..) => arg.rvm_width(RvmMode::Bits32),
+        _ => W::BITS,
+    }
+}
+
 pub struct RvmCtx {
     pub prog: RvmProg,
-    pub mem: RvmMem
+    pub mem: RvmMem,
+    /* Instruction budget; runs are unmetered without one */
+    pub gas: Option<RvmGas>,
+    /* Set by `int 0`; a program that runs off its end exits with 0 */
+    pub exit_code: i32,
+    /* Values waiting to be read by `int 2` */
+    pub input: VecDeque<i32>,
+    /* What the last instruction trapped with, and where to resume */
+    trap: Option<(RvmRunResult, i32)>,
+    interrupt: Arc<AtomicBool>
 }
 
 impl RvmCtx {
     pub fn new() -> Self {
+        RvmCtx::new_with_mode(RvmMode::Bits32)
+    }
+
+    // A VM for programs of the given mode; `%bits 64` in the source overrides it
+    pub fn new_with_mode(mode: RvmMode) -> Self {
+        let mut prog = RvmProg::new();
+        prog.mode = mode;
         let mut ctx = RvmCtx {
-            prog: RvmProg::new(),
-            mem: RvmMem::new()
+            prog,
+            mem: RvmMem::new(),
+            gas: None,
+            exit_code: 0,
+            input: VecDeque::new(),
+            trap: None,
+            interrupt: Arc::new(AtomicBool::new(false))
         };
         ctx.mem.rvm_stack_create();
         ctx
     }
 
-    fn instr_to_opcode(instr: &str) -> i32 {
+    pub fn instr_to_opcode(instr: &str) -> i32 {
+        let instr = RvmOpcodeAliases.iter()
+            .find(|(alias, _)| *alias == instr)
+            .map_or(instr, |(_, name)| name);
         let mut opcode = -1;
         for (i, op) in RvmOpcodeMap.iter().enumerate() {
             if instr == *op {
@@ -50,56 +355,173 @@ impl RvmCtx {
         opcode
     }
 
-    fn token_to_register(&mut self, tok: &str) -> Option<i32> {
-        for (i, r) in RvmRegisterMap.iter().enumerate() {
-            if tok == *r {
-                if let RvmRegU::I32(reg_val) = self.mem.registers[i] {
-                    return Some(reg_val);
-                } else {
-                    return None;
-                }
-            }
+    pub fn token_to_register(tok: &str, mode: RvmMode) -> Option<usize> {
+        let names = match mode {
+            RvmMode::Bits32 => &RvmRegisterMap,
+            RvmMode::Bits64 => &RvmRegister64Map,
+        };
+        names.iter().position(|r| tok == *r)
+    }
+
+    // A whole register or a named part of one
+    pub fn token_to_register_arg(tok: &str, mode: RvmMode) -> Option<RvmArg> {
+        if let Some(reg) = RvmCtx::token_to_register(tok, mode) {
+            return Some(RvmArg::Reg(reg));
         }
-        None
+        RvmSubRegisterMap.iter()
+            .find(|(name, _, part)| *name == tok && (mode == RvmMode::Bits64 || *part != RvmRegPart::Dword))
+            .map(|(_, reg, part)| RvmArg::Sub(*reg, *part))
     }
 
-    pub fn rvm_add_value(&mut self, val: i32) -> i32 {
-        self.prog.values.push(val); 
-        val
+    // A float literal like 1.5, -0.25 or 2e10; anything else is not one
+    pub fn rvm_parse_float_value(s: &str) -> Option<f64> {
+        let digits = s.strip_prefix('-').unwrap_or(s);
+        if !digits.starts_with(|c: char| c.is_ascii_digit()) || !digits.contains(['.', 'e', 'E']) {
+            return None;
+        }
+        s.parse().ok()
     }
 
-    pub fn rvm_parse_value(&mut self, s: &str) -> i32 {
-        let res: Result<i32, std::num::ParseIntError>;
-        if let Some(delimiter_index) = s.find('|') {
-            let identifier = &s[delimiter_index + 1..];
-    
-            let base = match identifier.chars().next() {
-                Some('h') => 16, 
-                Some('b') => 2,  
-                _ => 10,        
-            };
-            // Parse the value using the determined base
-            res = i32::from_str_radix(&s[..delimiter_index], base);
-        } 
-        else {
-            res = s.parse::<i32>();
+    /* A number: decimal, hexadecimal as `0x1f`, `1fh` or TinyVM's `1f|h`,
+     * binary as `0b101` or `101|b`, octal as `0o17`, with `_` between
+     * digits (`1_000`) and an optional `-` in front; or a character or
+     * string literal, which stands for its bytes, little-endian */
+    pub fn rvm_parse_number(s: &str) -> Result<i128, String> {
+        if let Some(bytes) = rvm_lex::rvm_unquote(s) {
+            let bytes = bytes?;
+            if bytes.len() > 8 {
+                return Err(format!("{} is longer than 8 bytes", s));
+            }
+            let mut word = [0; 8];
+            word[..bytes.len()].copy_from_slice(&bytes);
+            return Ok(u64::from_le_bytes(word) as i128);
+        }
+
+        let (negative, body) = match s.strip_prefix('-') {
+            Some(body) => (true, body),
+            None => (false, s),
+        };
+        // Apart from TinyVM's, numbers start with a digit
+        if !body.contains('|') && !body.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(format!("unknown name {}", s));
         }
-        match res {
-            Ok(value) => value,
-            Err(_) => {
-                // Handle the error case, e.g., return a default value or panic
-                println!("Error parsing value: {}", s);
-                0
+        let lower = body.to_ascii_lowercase();
+        let (digits, base) = if let Some((digits, suffix)) = lower.split_once('|') {
+            match suffix {
+                "h" => (digits, 16),
+                "b" => (digits, 2),
+                _ => return Err(format!("malformed number {}", s)),
             }
+        } else if let Some(digits) = lower.strip_prefix("0x") {
+            (digits, 16)
+        } else if let Some(digits) = lower.strip_prefix("0b") {
+            (digits, 2)
+        } else if let Some(digits) = lower.strip_prefix("0o") {
+            (digits, 8)
+        } else if let Some(digits) = lower.strip_suffix('h') {
+            (digits, 16)
+        } else {
+            (lower.as_str(), 10)
+        };
+
+        // Separators only go between digits
+        if digits.is_empty() || digits.starts_with('_') || digits.ends_with('_')
+            || !digits.chars().all(|c| c == '_' || c.is_digit(base)) {
+            return Err(format!("malformed number {}", s));
+        }
+        let magnitude = u128::from_str_radix(&digits.replace('_', ""), base)
+            .ok()
+            .and_then(|val| i128::try_from(val).ok())
+            .ok_or(format!("{} does not fit in 64 bits", s))?;
+        Ok(if negative { -magnitude } else { magnitude })
+    }
+
+    /* A number that fits in 32 bits, signed or not; the unsigned ones
+     * wrap around, so 0xffffffff is -1 */
+    pub fn rvm_parse_value(&mut self, s: &str) -> Result<i32, String> {
+        match RvmCtx::rvm_parse_number(s)? {
+            val if (i32::MIN as i128..=u32::MAX as i128).contains(&val) => Ok(val as i32),
+            _ => Err(format!("{} does not fit in 32 bits", s)),
+        }
+    }
+
+    // Like rvm_parse_value, for the immediates of 64-bit programs
+    pub fn rvm_parse_value64(&mut self, s: &str) -> Result<i64, String> {
+        match RvmCtx::rvm_parse_number(s)? {
+            val if (i64::MIN as i128..=u64::MAX as i128).contains(&val) => Ok(val as i64),
+            _ => Err(format!("{} does not fit in 64 bits", s)),
+        }
+    }
+
+    // An immediate operand, as wide as the registers
+    pub fn rvm_parse_immediate(&mut self, s: &str) -> Result<i64, String> {
+        match self.prog.mode {
+            RvmMode::Bits32 => self.rvm_parse_value(s).map(i64::from),
+            RvmMode::Bits64 => self.rvm_parse_value64(s),
+        }
+    }
+
+    /* What goes between brackets: an address, or a register or data label
+     * plus or minus a displacement */
+    pub fn rvm_parse_address(&mut self, s: &str) -> Result<RvmArg, String> {
+        let (base, disp) = s.split_at(s.find(['+', '-']).unwrap_or(s.len()));
+        let disp_of = |ctx: &mut RvmCtx| match disp.split_at_checked(1) {
+            Some(("-", val)) => ctx.rvm_parse_value(val).map(i32::wrapping_neg),
+            Some((_, val)) => ctx.rvm_parse_value(val),
+            None => Ok(0),
+        };
+        // A data label, which is an address of its own
+        if let Some(addr) = self.prog.data_labels.rvm_htab_find(base) {
+            return Ok(RvmArg::Mem(addr.wrapping_add(disp_of(self)?) as u32 as usize));
+        }
+        let Some(reg) = RvmCtx::token_to_register(base, self.prog.mode) else {
+            return Ok(RvmArg::Mem(self.rvm_parse_value(s)? as u32 as usize));
+        };
+        Ok(RvmArg::Ptr(reg, disp_of(self)?))
+    }
+
+    // The first fixed address in `instr` without room for a whole operand in memory
+    fn rvm_outside_memory(&self, instr: &RvmInstr) -> Option<usize> {
+        instr.rvm_args().iter().find_map(|arg| match *arg {
+            RvmArg::Mem(addr) => match addr.checked_add(arg.rvm_width(self.prog.mode) as usize / 8) {
+                Some(end) if end <= self.mem.mem_space.len() => None,
+                _ => Some(addr),
+            },
+            _ => None,
+        })
+    }
+
+    // Resolve a label name or a plain instruction index
+    pub fn rvm_resolve_location(&mut self, loc: &str) -> Result<i32, String> {
+        match self.prog.labels.rvm_htab_find(loc) {
+            Some(idx) => Ok(idx),
+            None => self.rvm_parse_value(loc),
         }
     }
 
-    pub fn rvm_parse_labels(&mut self, tokens: &Vec<Vec<String>>) -> i32 {
+    pub fn rvm_parse_labels(&mut self, tokens: &[Vec<String>], locs: &[RvmSrcLoc]) -> i32 {
         let mut num_instr : u32 = 0;
-        for i in 0..tokens.len() {
+        let mut section = RvmSection::Text;
+        // .bss follows all of .data, so its labels are placed once that is laid out
+        let mut bss_labels: Vec<(String, usize)> = Vec::new();
+        for (line, loc) in tokens.iter().zip(locs) {
+            let words: Vec<&str> = line.iter().map(String::as_str).filter(|tok| !tok.is_empty()).collect();
+
+            // Switch sections
+            if words.first() == Some(&TOK_SECTION) {
+                match words[1..] {
+                    [name] if let Some(next) = RvmSection::rvm_section_of(name) => section = next,
+                    _ => {
+                        println!("Error: line {}: unknown section {}", loc.line, words[1..].join(" "));
+                        return 1;
+                    }
+                }
+                continue;
+            }
+
             let mut valid_instruction : bool = false;
-            for j in 0..tokens[i].len() {
-                let mut tok = tokens[i][j].clone();
+            for line_tok in line {
+                let mut tok = line_tok.clone();
                 
                 // If the token is empty skip it
                 if tok.is_empty() {
@@ -111,404 +533,699 @@ impl RvmCtx {
                     valid_instruction = true;
                 }
 
-                // Check for a label delimiter 
-                if let Some(label_delimiter_pos) = tok.find(':') {
+                // Check for a label delimiter, which a literal may hold as well
+                if !rvm_lex::rvm_is_literal(&tok)
+                    && let Some(label_delimiter_pos) = tok.find(':') {
                     tok.truncate(label_delimiter_pos);
 
-                    // If the label is "start" make it the entry point
-                    if tok == "start" {
-                        // Set the entry point to the current instruction
-                        self.prog.start = num_instr as i32;
-                    }
-
                     // Check if the label already exists
-                    if let Some(label_addr) = self.prog.labels.rvm_htab_find(&tok) {
+                    if self.prog.labels.rvm_htab_find(&tok).is_some()
+                        || self.prog.data_labels.rvm_htab_find(&tok).is_some()
+                        || bss_labels.iter().any(|(name, _)| *name == tok) {
                         println!("Error: Duplicate label found: {}", tok);
                         return 1;
                     } 
 
-                    // Add the label to the hash table
-                    self.prog.labels.rvm_htab_add(&tok, num_instr as i32, "");
+                    match section {
+                        RvmSection::Text => {
+                            // If the label is "start" make it the entry point
+                            if tok == "start" {
+                                // Set the entry point to the current instruction
+                                self.prog.start = num_instr as i32;
+                            }
+
+                            // Add the label to the hash table
+                            self.prog.labels.rvm_htab_add(&tok, num_instr as i32, "");
+                        }
+                        RvmSection::Data => {
+                            let addr = RVM_DATA_BASE + self.prog.data.len();
+                            self.prog.data_labels.rvm_htab_add(&tok, addr as i32, "");
+                        }
+                        RvmSection::Bss => bss_labels.push((tok, self.prog.bss_size)),
+                    }
                 } else {
                     continue;
                 }
             }
-            if (valid_instruction) {
+
+            // What is left of the line after its labels
+            let words: Vec<&str> = words.into_iter()
+                .filter(|tok| rvm_lex::rvm_is_literal(tok) || !tok.contains(':'))
+                .collect();
+            if section != RvmSection::Text {
+                if valid_instruction {
+                    println!("Error: line {}: instructions belong in .text", loc.line);
+                    return 1;
+                }
+                if let Err(e) = self.rvm_parse_data(&words, section) {
+                    println!("Error: line {}: {}", loc.line, e);
+                    return 1;
+                }
+                continue;
+            }
+            if valid_instruction {
                 num_instr += 1;
+            } else if let Some(directive) = words.first().filter(|tok| RvmCtx::rvm_is_data_directive(tok)) {
+                println!("Error: line {}: {} belongs in a data section", loc.line, directive);
+                return 1;
             }
         }
+        for (name, offset) in bss_labels {
+            let addr = RVM_DATA_BASE + self.prog.data.len() + offset;
+            self.prog.data_labels.rvm_htab_add(&name, addr as i32, "");
+        }
         0
     }
 
-    pub fn rvm_parse_instr(&mut self, instr_toks: & Vec<String>) -> (i32, usize) {
+    fn rvm_is_data_directive(tok: &str) -> bool {
+        [TOK_TIMES, TOK_ALIGN].contains(&tok)
+            || RvmDataMap.iter().chain(&RvmReserveMap).any(|(name, _)| *name == tok)
+    }
+
+    // How many bytes a section holds so far
+    fn rvm_section_len(&self, section: RvmSection) -> usize {
+        match section {
+            RvmSection::Bss => self.prog.bss_size,
+            _ => self.prog.data.len(),
+        }
+    }
+
+    // A count for times, the res directives or align
+    fn rvm_parse_count(&mut self, s: Option<&&str>) -> Result<usize, String> {
+        let s = s.ok_or("missing count")?;
+        usize::try_from(RvmCtx::rvm_parse_number(s)?).map_err(|_| format!("invalid count {}", s))
+    }
+
+    /* Lay out one line of a data section: `[times n] directive`, where the
+     * directive is `db`/`dw`/`dd` with a list of values and strings,
+     * `resb`/`resw`/`resd` with a count of units to reserve, or `align n` */
+    fn rvm_parse_data(&mut self, words: &[&str], section: RvmSection) -> Result<(), String> {
+        let Some((&directive, args)) = words.split_first() else {
+            return Ok(());
+        };
+        let start = self.rvm_section_len(section);
+
+        if directive == TOK_TIMES {
+            let count = self.rvm_parse_count(args.first())?;
+            self.rvm_parse_data(&args[1..], section)?;
+            // Repeat what the directive laid out once
+            let size = self.rvm_section_len(section) - start;
+            let total = size.checked_mul(count).ok_or("data sections do not fit in memory")?;
+            if RVM_DATA_BASE + self.prog.data.len() + self.prog.bss_size - size + total > self.mem.mem_space.len() {
+                return Err("data sections do not fit in memory".to_string());
+            }
+            match section {
+                RvmSection::Bss => self.prog.bss_size = start + total,
+                _ => {
+                    let once = self.prog.data.split_off(start);
+                    self.prog.data.extend(once.repeat(count));
+                }
+            }
+            return Ok(());
+        }
+
+        let grow = if let Some((_, size)) = RvmDataMap.iter().find(|(name, _)| *name == directive) {
+            if section == RvmSection::Bss {
+                return Err(format!("{} cannot initialize .bss", directive));
+            }
+            if args.is_empty() {
+                return Err(format!("{} needs at least one value", directive));
+            }
+            let bits = *size as u32 * 8;
+            for arg in args {
+                // A string stores its bytes, padded with zeros to a whole unit
+                if arg.starts_with('"')
+                    && let Some(Ok(bytes)) = rvm_lex::rvm_unquote(arg) {
+                    let padded = bytes.len().next_multiple_of(*size);
+                    self.prog.data.extend_from_slice(&bytes);
+                    self.prog.data.resize(self.prog.data.len() + padded - bytes.len(), 0);
+                    continue;
+                }
+                let val = RvmCtx::rvm_parse_number(arg)?;
+                if val < -(1 << (bits - 1)) || val >= 1 << bits {
+                    return Err(format!("{} does not fit in {}", arg, directive));
+                }
+                self.prog.data.extend_from_slice(&val.to_le_bytes()[..*size]);
+            }
+            0
+        } else if let Some((_, size)) = RvmReserveMap.iter().find(|(name, _)| *name == directive) {
+            let count = self.rvm_parse_count(args.first())?;
+            count.checked_mul(*size).ok_or("data sections do not fit in memory")?
+        } else if directive == TOK_ALIGN {
+            let align = self.rvm_parse_count(args.first())?;
+            if align == 0 {
+                return Err("cannot align to 0 bytes".to_string());
+            }
+            start.next_multiple_of(align) - start
+        } else {
+            return Err(format!("unknown data directive {}", directive));
+        };
+
+        if RVM_DATA_BASE + self.prog.data.len() + self.prog.bss_size + grow > self.mem.mem_space.len() {
+            return Err("data sections do not fit in memory".to_string());
+        }
+        match section {
+            RvmSection::Bss => self.prog.bss_size += grow,
+            _ => self.prog.data.resize(self.prog.data.len() + grow, 0),
+        }
+        Ok(())
+    }
+
+    pub fn rvm_parse_instr(&mut self, instr_toks: &[String]) -> (i32, usize, RvmRep) {
+        let mut rep = RvmRep::Once;
         // Find the instruction in the opcode map
-        for mut i in 0..instr_toks.len() {
-            // Check if instr_toks[i] is empty 
-            if instr_toks[i].is_empty() {
+        for (i, tok) in instr_toks.iter().enumerate() {
+            // Check if the token is empty 
+            if tok.is_empty() {
                 continue;
             }
-            let opcode = RvmCtx::instr_to_opcode(&instr_toks[i]);
+
+            // Remember a rep prefix for the instruction after it
+            if let Some(prefix) = RvmRep::rvm_rep_of(tok) {
+                rep = prefix;
+                continue;
+            }
+
+            let opcode = RvmCtx::instr_to_opcode(tok);
 
             if opcode == -1 {
                 continue
             }
 
-            return (opcode, i); 
+            return (opcode, i, rep); 
         }
-        (-1, 0)
+        (-1, 0, rep)
     }
 
-    pub fn rvm_parse_args(&mut self, instr_toks: & Vec<String>, instr_place : usize) -> Vec<i32> {
+    pub fn rvm_parse_args(&mut self, instr_toks: &[String], instr_place : usize) -> Result<Vec<RvmArg>, String> {
         let mut args = Vec::new();
-        for i in instr_place + 1..instr_toks.len() {
-            if instr_toks[i].is_empty() {
+        for tok in instr_toks.iter().skip(instr_place + 1) {
+            if tok.is_empty() {
                 continue;
             }
             
-            let mut token = instr_toks[i].clone();
+            let mut token = tok.clone();
             if let Some(newline_pos) = token.find('\n') {
                 token.truncate(newline_pos);
             }
 
-            // Check if the token specifies a register
-            let reg: Option<i32> = self.token_to_register(&token);
-            if let Some(regp) = reg {
-                args.push(regp);
+            // Check if the token specifies a register or part of one
+            if let Some(reg) = RvmCtx::token_to_register_arg(&token, self.prog.mode) {
+                args.push(reg);
+                continue;
+            }
+
+            // Check if the token specifies a float register
+            if let Some(reg) = RvmFloatRegisterMap.iter().position(|r| token == *r) {
+                args.push(RvmArg::Freg(reg));
                 continue;
             }
 
             // Check to see whether the token specifies an address
-            if token.starts_with('[') {
-                if let Some(end_pos) = token.find(']') {
-                    token.truncate(end_pos);
-                    let addr_val = self.rvm_parse_value(&token);
-                    // Get the value at the address from the memory
-                    args.push(self.mem.mem_space[addr_val as usize] as i32);
-                    continue;
-                } 
+            if token.starts_with('[')
+                && let Some(end_pos) = token.find(']') {
+                let addr = self.rvm_parse_address(&token[1..end_pos])?;
+                args.push(addr);
+                continue;
             }
 
-            // Check if the argument is a label
-            if let Some(addr) = self.prog.labels.rvm_htab_find(&token) {
-                args.push(self.rvm_add_value(addr));
+            // Check if the argument is a label, either in the code or in the data
+            if let Some(addr) = self.prog.labels.rvm_htab_find(&token)
+                .or_else(|| self.prog.data_labels.rvm_htab_find(&token)) {
+                args.push(RvmArg::Val(addr as i64));
+                continue;
+            }
+
+            // Check if the token is a float literal
+            if let Some(val) = RvmCtx::rvm_parse_float_value(&token) {
+                args.push(RvmArg::Fval(val));
+                continue;
             }
 
             // Otherwise, parse the token as a value
-            let tok_val = self.rvm_parse_value(&token);
-            args.push(self.rvm_add_value(tok_val));
+            let tok_val = self.rvm_parse_immediate(&token)?;
+            args.push(RvmArg::Val(tok_val));
         }
-        args
+        Ok(args)
     }
 
-    pub fn rvm_parse_program(&mut self, tokens: &Vec<Vec<String>>) -> i32{
-        for i in 0..tokens.len() {
-            let (opcode, instr_place) = self.rvm_parse_instr(&tokens[i]);
+    pub fn rvm_parse_program(&mut self, tokens: &[Vec<String>], locs: &[RvmSrcLoc]) -> i32{
+        for (line, loc) in tokens.iter().zip(locs) {
+            let (opcode, instr_place, rep) = self.rvm_parse_instr(line);
             
             if opcode == -1 {
+                if rep != RvmRep::Once {
+                    println!("Error: line {}: {} needs a string instruction", loc.line, rep.rvm_rep_mnemonic());
+                    return 1;
+                }
                 continue;
             }
 
-            let args = self.rvm_parse_args(&tokens[i], instr_place);
+            let args = match self.rvm_parse_args(line, instr_place) {
+                Ok(args) => args,
+                Err(e) => {
+                    println!("Error: line {}: {}", loc.line, e);
+                    return 1;
+                }
+            };
 
-            if args.len() == 0 {
-                continue;
+            // Check the operands once here so the interpreter does not have to
+            let Some(instr) = RvmInstr::rvm_decode(opcode, &args) else {
+                println!("Error: line {}: wrong number of operands for {}", loc.line, RvmOpcodeMap[opcode as usize]);
+                return 1;
+            };
+
+            // A jump target is the last operand; a code label anywhere else is a value
+            let target = instr.rvm_jump_target().map(|_| args.len() - 1);
+            let label_value = line.iter().skip(instr_place + 1)
+                .filter_map(|tok| tok.split('\n').next().filter(|tok| !tok.is_empty()))
+                .enumerate()
+                .any(|(pos, tok)| Some(pos) != target && self.prog.labels.rvm_htab_find(tok).is_some());
+            self.prog.label_values |= label_value;
+            let instr = match rep {
+                RvmRep::Once => instr,
+                _ => match instr.rvm_with_rep(rep) {
+                    Some(instr) => instr,
+                    None => {
+                        println!("Error: line {}: {} cannot prefix {}", loc.line, rep.rvm_rep_mnemonic(), RvmOpcodeMap[opcode as usize]);
+                        return 1;
+                    }
+                },
+            };
+            if !instr.rvm_operands_typed() {
+                println!("Error: line {}: wrong operand types for {}", loc.line, RvmOpcodeMap[opcode as usize]);
+                return 1;
+            }
+            if matches!(instr.rvm_dest(), Some(RvmArg::Val(_))) || matches!(instr, RvmInstr::Xchg(_, RvmArg::Val(_))) {
+                println!("Error: line {}: {} cannot store into an immediate", loc.line, RvmOpcodeMap[opcode as usize]);
+                return 1;
+            }
+            if let RvmInstr::Lea(_, src) = instr
+                && !matches!(src, RvmArg::Mem(_) | RvmArg::Ptr(..)) {
+                println!("Error: line {}: lea needs a memory operand", loc.line);
+                return 1;
+            }
+            if let Some(addr) = self.rvm_outside_memory(&instr) {
+                println!("Error: line {}: address {} is outside memory", loc.line, addr);
+                return 1;
             }
 
             // Add the instruction to the program
-            self.prog.instructions.push(opcode);
+            self.prog.code.push(instr);
+            self.prog.locs.push(*loc);
+        }
+        // Sentinel instruction
+        self.prog.code.push(RvmInstr::Halt);
+        self.prog.locs.push(locs.last().copied().unwrap_or(RvmSrcLoc { file: 0, line: 0 }));
+        0
+    }
+
+    // Set the flags from comparing a with b
+    fn rvm_cmp_exec<W: RvmWord>(mem: &mut RvmMem, a: RvmArg, b: RvmArg) -> Result<(), RvmFault> {
+        let flags = rvm_cmp_flags(mem.rvm_load::<W>(&a)?, mem.rvm_load::<W>(&b)?);
+        mem.rvm_flags_write(flags);
+        Ok(())
+    }
 
-            // Add the arguments to the program
-            self.prog.args.push(args);
+    /* Stop at `part_idx`, the instruction a superinstruction replaced that
+     * faulted, which is where the unfused program would have stopped */
+    fn rvm_fused_trap(&mut self, fault: RvmFault, part_idx: i32) -> Result<i32, RvmFault> {
+        self.trap = Some((RvmRunResult::Fault(fault), part_idx));
+        Ok(RVM_TRAP)
+    }
+
+    // The conditional jump at `jump_idx` that ends a superinstruction
+    fn rvm_fused_jump<W: RvmWord>(&mut self, cond: RvmJumpCond, target: RvmArg, jump_idx: i32)
+        -> Result<i32, RvmFault> {
+        if !cond.rvm_cond_holds(self.mem.flags) {
+            return Ok(jump_idx + 1);
+        }
+        match self.mem.rvm_load::<W>(&target).map(W::rvm_narrow) {
+            Ok(next) if self.rvm_vm_in_range(next) => Ok(next),
+            Ok(next) => self.rvm_fused_trap(RvmFault::BadJump(next), jump_idx),
+            Err(fault) => self.rvm_fused_trap(fault, jump_idx),
         }
-        // Sentinel instructions
-        self.prog.args.push(vec![]); 
-        self.prog.instructions.push(-0x1); 
-        return 0
     }
 
-    pub fn rvm_step(&mut self, instr_idx : i32) -> i32 {
-        let mut new_idx = instr_idx; 
-        let args = &self.prog.args[instr_idx as usize];
-
-        match self.prog.instructions[instr_idx as usize] {
-            0x0 => {
-                // NO_OP
-            }
-            0x1 => {
-                // INT (unimplemented)
-            } 
-            0x2 => {
-                // MOV
-                if args.len() == 2 {
-                    let dest = args[0];
-                    let src = args[1];
-                    self.mem.registers[dest as usize] = RvmRegU::I32(src);
-                }
-            } 
-            0x3 => {
-                // PUSH
-                if args.len() == 1 {
-                    let src = args[0];
-                    self.mem.rvm_stack_push(src);
-                }
+    /* dest = dest op src, replacing the `affected` flags with those the
+     * operation sets */
+    fn rvm_alu_exec<W: RvmWord>(mem: &mut RvmMem, op: RvmAluOp, dest: RvmArg, src: RvmArg, affected: u32)
+        -> Result<(), RvmFault> {
+        let (val, flags) = W::rvm_alu(op, mem.rvm_load(&dest)?, mem.rvm_load(&src)?, rvm_width_in::<W>(&dest));
+        mem.rvm_store(&dest, val)?;
+        if let Some(flags) = flags {
+            mem.rvm_flags_write((mem.flags & !affected) | (flags & affected));
+        }
+        Ok(())
+    }
+
+    // Logic instructions clear carry and overflow
+    fn rvm_logic_exec<W: RvmWord>(mem: &mut RvmMem, dest: RvmArg, val: W) -> Result<(), RvmFault> {
+        mem.rvm_store(&dest, val)?;
+        mem.rvm_flags_write(mem.flags & !(RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW));
+        Ok(())
+    }
+
+    /* One iteration of a string instruction, stepping esi and edi past
+     * the bytes it touched. Compared values are zero-extended, so they
+     * compare as unsigned. */
+    fn rvm_string_exec<W: RvmWord>(mem: &mut RvmMem, instr: RvmInstr) -> Result<(), RvmFault> {
+        let (si, di) = (mem.rvm_reg_get::<W>(RVM_REG_ESI), mem.rvm_reg_get::<W>(RVM_REG_EDI));
+        let (src, dest) = (si.rvm_addr(), di.rvm_addr());
+        let (size, steps_si) = match instr {
+            RvmInstr::Movs(size, _) => {
+                let val = mem.rvm_mem_read_n(src, size)?;
+                mem.rvm_mem_write_n(dest, size, val)?;
+                (size, true)
             }
-            0x4 => {
-                // POP
-                if args.len() == 1 {
-                    let dest = args[0];
-                    let val = self.mem.rvm_stack_pop();
-                    self.mem.registers[dest as usize] = RvmRegU::I32(val);
-                }
+            RvmInstr::Stos(size, _) => {
+                let val = mem.rvm_reg_read(0);
+                mem.rvm_mem_write_n(dest, size, val)?;
+                (size, false)
             }
-            0x5 => {
-                // PUSHF
-                self.mem.rvm_stack_push(self.mem.flags as i32);
+            RvmInstr::Cmps(size, _) => {
+                let (val1, val2) = (mem.rvm_mem_read_n(src, size)?, mem.rvm_mem_read_n(dest, size)?);
+                mem.rvm_flags_write(rvm_cmp_flags(val1, val2));
+                (size, true)
             }
-            0x6 => {
-                // POPF
-                let val = self.mem.rvm_stack_pop();
-                self.mem.flags = val as u32;
-                
+            RvmInstr::Scas(size, _) => {
+                let mask = (1 << (8 * size)) - 1;
+                let (val1, val2) = (mem.rvm_reg_read(0) & mask, mem.rvm_mem_read_n(dest, size)?);
+                mem.rvm_flags_write(rvm_cmp_flags(val1, val2));
+                (size, false)
             }
-            0x7 => {
-                // INC
-                if args.len() == 1 {
-                    let reg = args[0];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[reg as usize] {
-                        *val += 1;
-                    }
-                }
+            _ => unreachable!("not a string instruction"),
+        };
+        let size = W::rvm_from_i64(size as i64);
+        if steps_si {
+            mem.rvm_reg_set(RVM_REG_ESI, si.rvm_wrapping_add(size));
+        }
+        mem.rvm_reg_set(RVM_REG_EDI, di.rvm_wrapping_add(size));
+        Ok(())
+    }
+
+    // dest = dest op src on floats
+    fn rvm_float_exec<W: RvmWord>(mem: &mut RvmMem, dest: RvmArg, src: RvmArg, op: fn(f64, f64) -> f64)
+        -> Result<(), RvmFault> {
+        let val = op(mem.rvm_fload::<W>(&dest)?, mem.rvm_fload::<W>(&src)?);
+        mem.rvm_fstore::<W>(&dest, val)
+    }
+
+    pub fn rvm_step(&mut self, instr_idx : i32) -> i32 {
+        match self.prog.mode {
+            RvmMode::Bits32 => self.rvm_step_in::<i32>(instr_idx),
+            RvmMode::Bits64 => self.rvm_step_in::<i64>(instr_idx),
+        }
+    }
+
+    fn rvm_step_in<W: RvmWord>(&mut self, instr_idx : i32) -> i32 {
+        self.rvm_exec::<W>(self.prog.code[instr_idx as usize], instr_idx)
+    }
+
+    // Execute `instr` as if it were at `instr_idx`, returning the index to continue at
+    fn rvm_exec<W: RvmWord>(&mut self, instr: RvmInstr, instr_idx : i32) -> i32 {
+        match self.rvm_exec_instr::<W>(instr, instr_idx) {
+            Ok(next) => next,
+            Err(fault) => {
+                self.trap = Some((RvmRunResult::Fault(fault), instr_idx));
+                RVM_TRAP
             }
-            0x8 => {
-                // DEC
-                if args.len() == 1 {
-                    let reg = args[0];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[reg as usize] {
-                        *val -= 1;
+        }
+    }
+
+    fn rvm_exec_instr<W: RvmWord>(&mut self, instr: RvmInstr, instr_idx : i32) -> Result<i32, RvmFault> {
+        let mem = &mut self.mem;
+        match instr {
+            RvmInstr::Nop => {}
+            RvmInstr::Int(n) => {
+                let n = mem.rvm_load::<W>(&n)?.rvm_narrow();
+                let trap = match n {
+                    RVM_INT_EXIT => {
+                        self.exit_code = mem.rvm_reg_read(0);
+                        (RvmRunResult::Halted(self.exit_code), self.prog.code.len() as i32 - 1)
                     }
-                }
+                    RVM_INT_YIELD => (RvmRunResult::Yielded, instr_idx + 1),
+                    RVM_INT_BREAK => (RvmRunResult::Breakpoint, instr_idx + 1),
+                    RVM_INT_READ => match self.input.pop_front() {
+                        Some(val) => {
+                            mem.rvm_store(&RvmArg::Reg(0), W::rvm_from_i64(val as i64))?;
+                            return Ok(instr_idx + 1);
+                        }
+                        None => (RvmRunResult::WaitingForInput, instr_idx),
+                    },
+                    _ => (RvmRunResult::Fault(RvmFault::BadInterrupt(n)), instr_idx),
+                };
+                self.trap = Some(trap);
+                return Ok(RVM_TRAP);
             }
-            0x9 => {
-                // ADD
-                if args.len() == 2 {
-                    let dest = args[0];
-                    let src = args[1];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[dest as usize] {
-                        *val += src;
-                    }
-                }
+            RvmInstr::Mov(dest, src) => {
+                let val = mem.rvm_load::<W>(&src)?;
+                mem.rvm_store(&dest, val)?;
             }
-            0xA => {
-                // SUB
-                if args.len() == 2 {
-                    let dest = args[0];
-                    let src = args[1];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[dest as usize] {
-                        *val -= src;
-                    }
-                }
+            RvmInstr::Push(src) => {
+                let val = mem.rvm_load::<W>(&src)?;
+                mem.rvm_stack_push(val)?;
             }
-            0xB => {
-                // MUL
-                if args.len() == 2 {
-                    let dest = args[0];
-                    let src = args[1];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[dest as usize] {
-                        *val *= src;
-                    }
-                }
+            RvmInstr::Pop(dest) => {
+                let val = mem.rvm_stack_pop::<W>()?;
+                mem.rvm_store(&dest, val)?;
             }
-            0xC => {
-                // DIV
-                if args.len() == 2 {
-                    let dest = args[0];
-                    let src = args[1];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[dest as usize] {
-                        *val /= src;
-                    }
-                }
+            RvmInstr::Pushf => mem.rvm_stack_push(W::rvm_from_i64(mem.flags as i64))?,
+            RvmInstr::Popf => {
+                let val = mem.rvm_stack_pop::<W>()?;
+                mem.rvm_flags_write(val.rvm_to_i64() as u32);
             }
-            0xD => {
-                // MOD
-                if args.len() == 2 {
-                    let dest = args[0];
-                    let src = args[1];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[dest as usize] {
-                        self.mem.remainder = *val % src;
-                    }
-                }
+            // inc and dec leave carry alone, like on x86
+            RvmInstr::Inc(dest) => Self::rvm_alu_exec::<W>(mem, RvmAluOp::Add, dest, RvmArg::Val(1), RVM_FLAG_OVERFLOW)?,
+            RvmInstr::Dec(dest) => Self::rvm_alu_exec::<W>(mem, RvmAluOp::Sub, dest, RvmArg::Val(1), RVM_FLAG_OVERFLOW)?,
+            RvmInstr::Add(dest, src) => {
+                Self::rvm_alu_exec::<W>(mem, RvmAluOp::Add, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW)?;
             }
-            0xE => {
-                // REM
-                if args.len() == 2 {
-                    let dest = args[0];
-                    let src = args[1];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[dest as usize] {
-                        *val = self.mem.remainder;
-                    }
-                }
+            RvmInstr::Sub(dest, src) => {
+                Self::rvm_alu_exec::<W>(mem, RvmAluOp::Sub, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW)?;
             }
-            0xF => {
-                // NOT
-                if args.len() == 1 {
-                    let dest = args[0];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[dest as usize] {
-                        *val = !*val;
-                    }
-                }
+            RvmInstr::Mul(dest, src) => {
+                Self::rvm_alu_exec::<W>(mem, RvmAluOp::Mul, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW)?;
             }
-            0x10 => {
-                // XOR
-                if args.len() == 2 {
-                    let dest = args[0];
-                    let src = args[1];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[dest as usize] {
-                        *val ^= src;
-                    }
+            RvmInstr::Div(dest, src) => {
+                let (val1, val2) = (mem.rvm_load::<W>(&dest)?, mem.rvm_load::<W>(&src)?);
+                if let Some(fault) = W::rvm_divide_fault(val1, val2) {
+                    return Err(fault);
                 }
+                mem.rvm_store(&dest, val1 / val2)?;
             }
-            0x11 => {
-                // OR
-                if args.len() == 2 {
-                    let dest = args[0];
-                    let src = args[1];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[dest as usize] {
-                        *val |= src;
-                    }
+            RvmInstr::Mod(a, b) => {
+                let (val1, val2) = (mem.rvm_load::<W>(&a)?, mem.rvm_load::<W>(&b)?);
+                if let Some(fault) = W::rvm_divide_fault(val1, val2) {
+                    return Err(fault);
                 }
+                mem.rvm_remainder_write((val1 % val2).rvm_to_i64());
             }
-            0x12 => {
-                // AND
-                if args.len() == 2 {
-                    let dest = args[0];
-                    let src = args[1];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[dest as usize] {
-                        *val &= src;
-                    }
-                }
+            RvmInstr::Rem(dest) => {
+                let val = W::rvm_from_i64(mem.remainder);
+                mem.rvm_store(&dest, val)?;
             }
-            0x13 => {
-                // SHL
-                if args.len() == 2 {
-                    let dest = args[0];
-                    let src = args[1];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[dest as usize] {
-                        *val <<= src;
-                    }
-                }
+            RvmInstr::Not(dest) => {
+                let val = mem.rvm_load::<W>(&dest)?;
+                mem.rvm_store(&dest, !val)?;
             }
-            0x14 => {
-                // SHR
-                if args.len() == 2 {
-                    let dest = args[0];
-                    let src = args[1];
-                    if let RvmRegU::I32(ref mut val) = self.mem.registers[dest as usize] {
-                        *val >>= src;
-                    }
-                }
+            RvmInstr::Xor(dest, src) => {
+                let val = mem.rvm_load::<W>(&dest)? ^ mem.rvm_load::<W>(&src)?;
+                Self::rvm_logic_exec::<W>(mem, dest, val)?;
             }
-            0x15 => {
-                // CMP
-                if args.len() == 2 {
-                    let reg1 = args[0];
-                    let reg2 = args[1];
-                    if let RvmRegU::I32(val1) = self.mem.registers[reg1 as usize] {
-                        if let RvmRegU::I32(val2) = self.mem.registers[reg2 as usize] {
-                            self.mem.flags = ((val1 == val2) as u32) | (((val1 > val2) as u32) << 1);
-                        }
-                    }
-                }
+            RvmInstr::Or(dest, src) => {
+                let val = mem.rvm_load::<W>(&dest)? | mem.rvm_load::<W>(&src)?;
+                Self::rvm_logic_exec::<W>(mem, dest, val)?;
             }
-            0x16 => {
-                // JMP
-                if args.len() == 1 {
-                    let addr = args[0];
-                    new_idx = addr;
-                }
+            RvmInstr::And(dest, src) => {
+                let val = mem.rvm_load::<W>(&dest)? & mem.rvm_load::<W>(&src)?;
+                Self::rvm_logic_exec::<W>(mem, dest, val)?;
             }
-            0x17 => {
-                // CALL
-                if args.len() == 1 {
-                    let addr = args[0];
-                    self.mem.rvm_stack_push(instr_idx);
-                    new_idx = addr;
-                }
+            RvmInstr::Shl(dest, src) => {
+                Self::rvm_alu_exec::<W>(mem, RvmAluOp::Shl, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW)?;
             }
-            0x18 => {
-                // RET
-                if args.len() == 0 {
-                    new_idx = self.mem.rvm_stack_pop();
-                }
+            RvmInstr::Shr(dest, src) => {
+                Self::rvm_alu_exec::<W>(mem, RvmAluOp::Shr, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW)?;
             }
-            0x19 => {
-                // JE
-                if args.len() == 1 {
-                    let addr = args[0];
-                    if self.mem.flags & 0x1 != 0 {
-                        new_idx = addr-1;
-                    }
-                }
+            RvmInstr::Sar(dest, src) => {
+                Self::rvm_alu_exec::<W>(mem, RvmAluOp::Sar, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW)?;
             }
-            0x1A => {
-                // JNE
-                if args.len() == 1 {
-                    let addr = args[0];
-                    if self.mem.flags & 0x1 == 0 {
-                        new_idx = addr-1;
-                    }
+            RvmInstr::Rol(dest, src) => {
+                Self::rvm_alu_exec::<W>(mem, RvmAluOp::Rol, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW)?;
+            }
+            RvmInstr::Ror(dest, src) => {
+                Self::rvm_alu_exec::<W>(mem, RvmAluOp::Ror, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW)?;
+            }
+            RvmInstr::Umul(dest, src) => {
+                Self::rvm_alu_exec::<W>(mem, RvmAluOp::Umul, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW)?;
+            }
+            RvmInstr::Neg(dest) => {
+                let (val, flags) = W::rvm_alu(RvmAluOp::Sub, W::ZERO, mem.rvm_load(&dest)?, rvm_width_in::<W>(&dest));
+                mem.rvm_store(&dest, val)?;
+                mem.rvm_flags_write((mem.flags & !(RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW)) | flags.unwrap_or(0));
+            }
+            RvmInstr::Udiv(dest, src) => {
+                let (val1, val2) = (mem.rvm_load::<W>(&dest)?, mem.rvm_load::<W>(&src)?);
+                if val2 == W::ZERO {
+                    return Err(RvmFault::DivideByZero);
                 }
+                mem.rvm_store(&dest, val1.rvm_udiv(val2))?;
             }
-            0x1B => {
-                // JG
-                if args.len() == 1 {
-                    let addr = args[0];
-                    if self.mem.flags & 0x2 != 0 {
-                        new_idx = addr-1;
-                    }
+            RvmInstr::Umod(a, b) => {
+                let (val1, val2) = (mem.rvm_load::<W>(&a)?, mem.rvm_load::<W>(&b)?);
+                if val2 == W::ZERO {
+                    return Err(RvmFault::DivideByZero);
                 }
+                mem.rvm_remainder_write(val1.rvm_urem(val2).rvm_to_i64());
             }
-            0x1C => {
-                // JGE
-                if args.len() == 1 {
-                    let addr = args[0];
-                    if self.mem.flags & 0x3 == 0  {
-                        new_idx = addr-1;
-                    }
+            // test sets the flags cmp would for (a & b) against 0
+            RvmInstr::Test(a, b) => {
+                let flags = rvm_cmp_flags(mem.rvm_load::<W>(&a)? & mem.rvm_load::<W>(&b)?, W::ZERO);
+                mem.rvm_flags_write(flags);
+            }
+            RvmInstr::Xchg(a, b) => {
+                let (val1, val2) = (mem.rvm_load::<W>(&a)?, mem.rvm_load::<W>(&b)?);
+                mem.rvm_store(&a, val2)?;
+                mem.rvm_store(&b, val1)?;
+            }
+            RvmInstr::Lea(dest, src) => {
+                let addr = match src {
+                    RvmArg::Mem(addr) => addr,
+                    RvmArg::Ptr(reg, disp) => mem.rvm_ptr_addr::<W>(reg, disp),
+                    _ => unreachable!("checked when assembling"),
+                };
+                mem.rvm_store(&dest, W::rvm_from_i64(addr as i64))?;
+            }
+            RvmInstr::Cmp(a, b) => Self::rvm_cmp_exec::<W>(mem, a, b)?,
+            RvmInstr::Jmp(addr) => return Ok(mem.rvm_load::<W>(&addr)?.rvm_narrow()),
+            // call pushes the index to return to, the instruction after it
+            RvmInstr::Call(addr) => {
+                let addr = mem.rvm_load::<W>(&addr)?.rvm_narrow();
+                mem.rvm_stack_push(W::rvm_from_i64(instr_idx as i64 + 1))?;
+                return Ok(addr);
+            }
+            RvmInstr::Ret => return Ok(mem.rvm_stack_pop::<W>()?.rvm_narrow()),
+            RvmInstr::RetN(n) => {
+                let n = mem.rvm_load::<W>(&n)?;
+                let addr = mem.rvm_stack_pop::<W>()?.rvm_narrow();
+                let sp = mem.rvm_reg_get::<W>(RVM_REG_ESP);
+                mem.rvm_reg_set(RVM_REG_ESP, sp.rvm_wrapping_add(n));
+                return Ok(addr);
+            }
+            // enter n: push ebp; mov ebp, esp; sub esp, n
+            RvmInstr::Enter(n) => {
+                let n = mem.rvm_load::<W>(&n)?;
+                let bp = mem.rvm_reg_get::<W>(RVM_REG_EBP);
+                mem.rvm_stack_push(bp)?;
+                let sp = mem.rvm_reg_get::<W>(RVM_REG_ESP);
+                mem.rvm_reg_set(RVM_REG_EBP, sp);
+                mem.rvm_reg_set(RVM_REG_ESP, sp.rvm_wrapping_sub(n));
+            }
+            // leave: mov esp, ebp; pop ebp
+            RvmInstr::Leave => {
+                let bp = mem.rvm_reg_get::<W>(RVM_REG_EBP);
+                mem.rvm_reg_set(RVM_REG_ESP, bp);
+                let bp = mem.rvm_stack_pop::<W>()?;
+                mem.rvm_reg_set(RVM_REG_EBP, bp);
+            }
+            RvmInstr::Loop(addr) | RvmInstr::Loopz(addr) | RvmInstr::Loopnz(addr) => {
+                let count = mem.rvm_reg_get::<W>(RVM_REG_ECX).rvm_wrapping_sub(W::ONE);
+                mem.rvm_reg_set(RVM_REG_ECX, count);
+                let equal = mem.flags & RVM_FLAG_EQUAL != 0;
+                let taken = count != W::ZERO && match instr {
+                    RvmInstr::Loopz(_) => equal,
+                    RvmInstr::Loopnz(_) => !equal,
+                    _ => true,
+                };
+                if taken {
+                    return Ok(mem.rvm_load::<W>(&addr)?.rvm_narrow());
                 }
             }
-            0x1D => {
-                // JL
-                if args.len() == 1 {
-                    let addr = args[0];
-                    if self.mem.flags & 0x3 != 0 {
-                        new_idx = addr-1;
-                    }
+            RvmInstr::Movs(_, RvmRep::Once) | RvmInstr::Stos(_, RvmRep::Once)
+            | RvmInstr::Cmps(_, RvmRep::Once) | RvmInstr::Scas(_, RvmRep::Once) => {
+                Self::rvm_string_exec::<W>(mem, instr)?;
+            }
+            /* A repeated string instruction runs one iteration per step and
+             * stays where it is until it is done, so it can be stopped and
+             * metered like a loop */
+            RvmInstr::Movs(_, rep) | RvmInstr::Stos(_, rep) | RvmInstr::Cmps(_, rep) | RvmInstr::Scas(_, rep) => {
+                let count = mem.rvm_reg_get::<W>(RVM_REG_ECX);
+                if count == W::ZERO {
+                    return Ok(instr_idx + 1);
+                }
+                Self::rvm_string_exec::<W>(mem, instr)?;
+                mem.rvm_reg_set(RVM_REG_ECX, count.rvm_wrapping_sub(W::ONE));
+                let equal = mem.flags & RVM_FLAG_EQUAL != 0;
+                let done = count == W::ONE || (rep == RvmRep::Repe && !equal) || (rep == RvmRep::Repne && equal);
+                if !done {
+                    return Ok(instr_idx);
                 }
             }
-            0x1E => {
-                // JLE
-                if args.len() == 1 {
-                    let addr = args[0];
-                    if self.mem.flags & 0x2 != 0 {
-                        new_idx = addr-1;
-                    }
+            RvmInstr::Fld(dest, src) | RvmInstr::Fst(dest, src) => {
+                let val = mem.rvm_fload::<W>(&src)?;
+                mem.rvm_fstore::<W>(&dest, val)?;
+            }
+            RvmInstr::Fadd(dest, src) => Self::rvm_float_exec::<W>(mem, dest, src, |a, b| a + b)?,
+            RvmInstr::Fsub(dest, src) => Self::rvm_float_exec::<W>(mem, dest, src, |a, b| a - b)?,
+            RvmInstr::Fmul(dest, src) => Self::rvm_float_exec::<W>(mem, dest, src, |a, b| a * b)?,
+            RvmInstr::Fdiv(dest, src) => Self::rvm_float_exec::<W>(mem, dest, src, |a, b| a / b)?,
+            RvmInstr::Fsqrt(dest) => {
+                let val = mem.rvm_fload::<W>(&dest)?.sqrt();
+                mem.rvm_fstore::<W>(&dest, val)?;
+            }
+            RvmInstr::Fcmp(a, b) => {
+                let flags = rvm_fcmp_flags(mem.rvm_fload::<W>(&a)?, mem.rvm_fload::<W>(&b)?);
+                mem.rvm_flags_write(flags);
+            }
+            RvmInstr::Itof(dest, src) => {
+                let val = mem.rvm_load::<W>(&src)?.rvm_to_f64();
+                mem.rvm_fstore::<W>(&dest, val)?;
+            }
+            RvmInstr::Ftoi(dest, src) => {
+                let val = W::rvm_from_f64(mem.rvm_fload::<W>(&src)?);
+                mem.rvm_store(&dest, val)?;
+            }
+            RvmInstr::Fprn(src) => println!("{:?}", mem.rvm_fload::<W>(&src)?),
+            RvmInstr::Je(_) | RvmInstr::Jne(_) | RvmInstr::Jg(_)
+            | RvmInstr::Jge(_) | RvmInstr::Jl(_) | RvmInstr::Jle(_) => {
+                let (cond, addr) = RvmJumpCond::rvm_cond_of(&instr).unwrap();
+                if cond.rvm_cond_holds(mem.flags) {
+                    return Ok(mem.rvm_load::<W>(&addr)?.rvm_narrow());
                 }
             }
-            0x1F => {
-                // PRN
-                if args.len() == 1 {
-                    let reg = args[0];
-                    if let RvmRegU::I32(val) = self.mem.registers[reg as usize] {
-                        println!("{}", val);
-                    }
-                }   
+            RvmInstr::Prn(src) => println!("{}", mem.rvm_load::<W>(&src)?),
+            RvmInstr::Movsx(dest, src) => {
+                let val = mem.rvm_load::<W>(&src)?;
+                let val = match src {
+                    RvmArg::Sub(_, part) => W::rvm_from_i64(part.rvm_part_sign_extend(val.rvm_to_i64())),
+                    _ => val,
+                };
+                mem.rvm_store(&dest, val)?;
             }
-            _ => {
-                panic!("Unknown opcode: {}", self.prog.instructions[instr_idx as usize]);
+            RvmInstr::Halt => return Ok(instr_idx),
+            /* Superinstructions fall through past every instruction they
+             * replaced, and fault at the part that faulted */
+            RvmInstr::CmpJcc(cond, a, b, target) => {
+                Self::rvm_cmp_exec::<W>(mem, a, b)?;
+                return self.rvm_fused_jump::<W>(cond, target, instr_idx + 1);
+            }
+            RvmInstr::IncCmpJcc(cond, a, b, target) => {
+                Self::rvm_alu_exec::<W>(mem, RvmAluOp::Add, a, RvmArg::Val(1), RVM_FLAG_OVERFLOW)?;
+                if let Err(fault) = Self::rvm_cmp_exec::<W>(mem, a, b) {
+                    return self.rvm_fused_trap(fault, instr_idx + 1);
+                }
+                return self.rvm_fused_jump::<W>(cond, target, instr_idx + 2);
+            }
+            RvmInstr::PushPop(src, dest) => {
+                let val = mem.rvm_load::<W>(&src)?;
+                mem.rvm_stack_push(val)?;
+                if let Err(fault) = mem.rvm_stack_pop::<W>().and_then(|val| mem.rvm_store(&dest, val)) {
+                    return self.rvm_fused_trap(fault, instr_idx + 1);
+                }
+                return Ok(instr_idx + 2);
             }
         }
-        
-        new_idx
+        Ok(instr_idx + 1)
     }
 
     pub fn rvm_vm_interpret(&mut self, filename: &str) -> i32 {
@@ -538,6 +1255,9 @@ impl RvmCtx {
 
         let mut source = rvm_file::rvm_fcopy(filp.as_mut().unwrap()).unwrap();
 
+        // Let the lexer attribute lines to this file
+        source.insert_str(0, &format!("{} 1 {}\n", rvm_preprocessor::TOK_LINE, filename));
+
         let mut preprocessor = rvm_preprocessor::RvmPreprocessor::new();
 
         let err = preprocessor.rvm_preprocess(&mut source);
@@ -546,33 +1266,190 @@ impl RvmCtx {
         }
 
         self.prog.defines = preprocessor.defines;
+        if let Some(mode) = preprocessor.mode {
+            self.prog.mode = mode;
+        }
 
         let mut lexer_ctx = rvm_lex::RvmLexerCtx::new();
 
-        lexer_ctx.rvm_lex(&source, &self.prog.defines);
+        if lexer_ctx.rvm_lex(&source, &self.prog.defines) != 0 {
+            return 1;
+        }
 
-        if self.rvm_parse_labels(&lexer_ctx.tokens) != 0 {
+        if self.rvm_parse_labels(&lexer_ctx.tokens, &lexer_ctx.locs) != 0 {
             return 1;
         }
         
-        if self.rvm_parse_program(&lexer_ctx.tokens) != 0 {
+        if self.rvm_parse_program(&lexer_ctx.tokens, &lexer_ctx.locs) != 0 {
             return 1;
         }
+        self.prog.files = lexer_ctx.files;
+
+        // Lay the data sections out in memory; .bss is already zero
+        self.mem.mem_space[RVM_DATA_BASE..RVM_DATA_BASE + self.prog.data.len()].copy_from_slice(&self.prog.data);
 
-        return 0;
+        // Point the instruction register at the entry point
+        self.mem.registers[0x8] = RvmRegU::I64(self.prog.start as i64);
+
+        0
     }
 
-    pub fn rvm_vm_run(&mut self) {
-        // Set the index for the instruction to be executed
-        self.mem.registers[0x8] = RvmRegU::I32(self.prog.start);
-        let mut instr_idx = self.prog.start;
-        loop {
-            if self.prog.instructions[instr_idx as usize] == -0x1 {
-                break;
+    pub fn rvm_vm_eip(&self) -> i32 {
+        self.mem.rvm_reg_read(0x8)
+    }
+
+    pub fn rvm_vm_set_eip(&mut self, instr_idx: i32) {
+        self.mem.registers[0x8] = RvmRegU::I64(instr_idx as i64);
+    }
+
+    // Resolve an operand as the program would, without recording the access
+    pub fn rvm_vm_peek(&self, arg: &RvmArg) -> i64 {
+        match self.prog.mode {
+            RvmMode::Bits32 => self.mem.rvm_peek::<i32>(arg) as i64,
+            RvmMode::Bits64 => self.mem.rvm_peek::<i64>(arg),
+        }
+    }
+
+    pub fn rvm_vm_halted(&self) -> bool {
+        self.prog.code[self.rvm_vm_eip() as usize] == RvmInstr::Halt
+    }
+
+    pub fn rvm_interrupt_handle(&self) -> RvmInterruptHandle {
+        RvmInterruptHandle { flag: Arc::clone(&self.interrupt) }
+    }
+
+    // Queue a value for `int 2` to read
+    pub fn rvm_vm_feed_input(&mut self, val: i32) {
+        self.input.push_back(val);
+    }
+
+    // Run from eip until the program stops, see RvmRunResult
+    pub fn rvm_vm_run(&mut self) -> RvmRunResult {
+        let mut instr_idx = self.rvm_vm_eip();
+        let result = loop {
+            let stop;
+            (instr_idx, stop) = match (self.gas.is_some(), self.prog.mode) {
+                (true, RvmMode::Bits32) => self.rvm_vm_run_metered::<i32>(instr_idx),
+                (true, RvmMode::Bits64) => self.rvm_vm_run_metered::<i64>(instr_idx),
+                (false, RvmMode::Bits32) => self.rvm_vm_run_slice::<i32>(instr_idx),
+                (false, RvmMode::Bits64) => self.rvm_vm_run_slice::<i64>(instr_idx),
+            };
+            if let Some(result) = stop {
+                break result;
+            }
+            if self.interrupt.swap(false, Ordering::Relaxed) {
+                break RvmRunResult::Interrupted;
             }
-            instr_idx = self.rvm_step(instr_idx);
+        };
+        self.rvm_vm_set_eip(instr_idx);
+        result
+    }
+
+    // Execute the instruction at eip and move eip on, returning why the program stopped if it did
+    pub fn rvm_vm_step(&mut self) -> Option<RvmRunResult> {
+        let instr_idx = self.rvm_vm_eip();
+        if self.prog.code[instr_idx as usize] == RvmInstr::Halt {
+            return Some(RvmRunResult::Halted(self.exit_code));
         }
+        let (next, stop) = match self.rvm_step(instr_idx) {
+            next if self.rvm_vm_in_range(next) => (next, None),
+            next => {
+                let (next, result) = self.rvm_vm_stopped(instr_idx, next);
+                (next, Some(result))
+            }
+        };
+        self.rvm_vm_set_eip(next);
+        stop
+    }
+
+    fn rvm_vm_in_range(&self, instr_idx: i32) -> bool {
+        (instr_idx as usize) < self.prog.code.len()
     }
-}
 
+    /* The instruction at `instr_idx` did not continue at a valid index:
+     * either it trapped or it jumped out of the program. Returns where to
+     * resume and why we stopped. */
+    fn rvm_vm_stopped(&mut self, instr_idx: i32, next: i32) -> (i32, RvmRunResult) {
+        match self.trap.take() {
+            Some((result, resume)) => (resume, result),
+            None => (instr_idx, RvmRunResult::Fault(RvmFault::BadJump(next))),
+        }
+    }
 
+    // Like rvm_vm_run, but interrupt the program if it is still running after `limit`
+    pub fn rvm_vm_run_for(&mut self, limit: Duration) -> RvmRunResult {
+        let handle = self.rvm_interrupt_handle();
+        let (done, finished) = mpsc::channel::<()>();
+        let watchdog = thread::spawn(move || {
+            let expired = finished.recv_timeout(limit) == Err(RecvTimeoutError::Timeout);
+            if expired {
+                handle.rvm_interrupt();
+            }
+            expired
+        });
+        let result = self.rvm_vm_run();
+        drop(done);
+        // A deadline passing just as the program stopped by itself must not stop the next run
+        if watchdog.join().unwrap_or(false) && result != RvmRunResult::Interrupted {
+            self.interrupt.store(false, Ordering::Relaxed);
+        }
+        result
+    }
+
+    // Run up to RVM_INTERRUPT_INTERVAL instructions from `instr_idx`
+    fn rvm_vm_run_slice<W: RvmWord>(&mut self, mut instr_idx: i32) -> (i32, Option<RvmRunResult>) {
+        for _ in 0..RVM_INTERRUPT_INTERVAL {
+            if self.prog.code[instr_idx as usize] == RvmInstr::Halt {
+                return (instr_idx, Some(RvmRunResult::Halted(self.exit_code)));
+            }
+            let next = self.rvm_step_in::<W>(instr_idx);
+            if !self.rvm_vm_in_range(next) {
+                let (next, result) = self.rvm_vm_stopped(instr_idx, next);
+                return (next, Some(result));
+            }
+            instr_idx = next;
+        }
+        (instr_idx, None)
+    }
+
+    fn rvm_vm_run_metered<W: RvmWord>(&mut self, mut instr_idx: i32) -> (i32, Option<RvmRunResult>) {
+        for _ in 0..RVM_INTERRUPT_INTERVAL {
+            let instr = self.prog.code[instr_idx as usize];
+            if instr == RvmInstr::Halt {
+                return (instr_idx, Some(RvmRunResult::Halted(self.exit_code)));
+            }
+            let Some(run) = self.gas.as_ref().unwrap().rvm_gas_affordable(&instr) else {
+                return (instr_idx, Some(RvmRunResult::OutOfGas));
+            };
+            let next = self.rvm_exec::<W>(run, instr_idx);
+            if !self.rvm_vm_in_range(next) {
+                let (next, result) = self.rvm_vm_stopped(instr_idx, next);
+                self.gas.as_mut().unwrap().rvm_gas_pay(&run, rvm_gas_parts_done(instr_idx, next));
+                return (next, Some(result));
+            }
+            self.gas.as_mut().unwrap().rvm_gas_pay(&run, usize::MAX);
+            instr_idx = next;
+        }
+        (instr_idx, None)
+    }
+
+    /* Like rvm_vm_run, but reports each executed instruction to `hook`.
+     * An instruction that stopped the program without completing, such as
+     * one that faulted, is not reported. */
+    pub fn rvm_vm_run_hooked(&mut self, hook: &mut dyn RvmHook) -> RvmRunResult {
+        let mut accesses = Vec::new();
+        loop {
+            let instr_idx = self.rvm_vm_eip();
+            self.mem.journal = Some(accesses);
+            let stop = self.rvm_vm_step();
+            accesses = self.mem.journal.take().unwrap_or_default();
+            if stop.is_none() || self.rvm_vm_eip() != instr_idx {
+                hook.rvm_hook_step(self, instr_idx, &accesses);
+            }
+            accesses.clear();
+            if let Some(result) = stop {
+                break result;
+            }
+        }
+    }
+}
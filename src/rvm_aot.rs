@@ -0,0 +1,501 @@
+/* Ahead-of-time translation to Rust.
+ *
+ * The program becomes a standalone Rust source file that needs nothing
+ * but rustc to build. Its memory model is the one RvmMem implements: a
+ * flat little-endian memory space of the same size, the same register
+ * file with the stack growing down from the same address, and the same
+ * flags and remainder. Arithmetic goes through an `alu` helper that
+ * mirrors rvm_alu, so it wraps, sets flags and faults exactly where the
+ * interpreter does.
+ * Interrupts behave as they do under `rusty-vm`: yields and breakpoints
+ * carry on, input is read from stdin, and faults and `int 0` exit with
+ * the same status.
+ *
+ * Each basic block becomes one arm of a `match` on the program counter.
+ * A jump sets the counter and goes back to the match. When the program
+ * jumps through a register, a memory operand or `ret`, control may land
+ * in the middle of a block, so every instruction that does not start a
+ * block also gets an arm running it alone.
+ */
+use std::io::{self, Write};
+
+use crate::rvm::{RvmAluOp, RvmCtx, RvmFault, RvmFloatRegisterMap, RvmRegisterMap};
+use crate::rvm::{RVM_FLAG_CARRY, RVM_FLAG_EQUAL, RVM_FLAG_GREATER, RVM_FLAG_OVERFLOW};
+use crate::rvm::{RVM_INT_BREAK, RVM_INT_EXIT, RVM_INT_READ, RVM_INT_YIELD};
+use crate::rvm_memory::RVM_DATA_BASE;
+use crate::rvm_prog::{RvmArg, RvmInstr, RvmJumpCond, RvmMode, RvmRegPart, RvmRep};
+
+fn rvm_aot_load(arg: &RvmArg) -> String {
+    match *arg {
+        RvmArg::Reg(reg) => format!("m.regs[{}]", reg),
+        RvmArg::Mem(addr) => format!("m.read({})", addr),
+        RvmArg::Val(val) if val < 0 => format!("({})", val),
+        RvmArg::Val(val) => val.to_string(),
+        RvmArg::Sub(reg, part) => {
+            let (shift, mask) = rvm_aot_part(part);
+            format!("((m.regs[{}] as u32 >> {}) & {:#x}) as i32", reg, shift, mask)
+        }
+        RvmArg::Ptr(reg, disp) => format!("m.read({})", rvm_aot_ptr(reg, disp)),
+        RvmArg::Freg(_) | RvmArg::Fval(_) => unreachable!("checked when assembling"),
+    }
+}
+
+// A float operand; literals go through their bits so every value survives
+fn rvm_aot_fload(arg: &RvmArg) -> String {
+    match *arg {
+        RvmArg::Freg(reg) => format!("m.fregs[{}]", reg),
+        RvmArg::Fval(val) => format!("f64::from_bits({:#x})", val.to_bits()),
+        RvmArg::Mem(addr) => format!("m.readf({})", addr),
+        RvmArg::Ptr(reg, disp) => format!("m.readf({})", rvm_aot_ptr(reg, disp)),
+        _ => unreachable!("checked when assembling"),
+    }
+}
+
+fn rvm_aot_fstore(arg: &RvmArg, val: &str) -> String {
+    match *arg {
+        RvmArg::Freg(reg) => format!("m.fregs[{}] = {};", reg, val),
+        RvmArg::Mem(addr) => format!("m.writef({}, {});", addr, val),
+        RvmArg::Ptr(reg, disp) => format!("let at = {}; m.writef(at, {});", rvm_aot_ptr(reg, disp), val),
+        _ => unreachable!("checked when assembling"),
+    }
+}
+
+// `dest = dest <op> src` on floats
+fn rvm_aot_fbinop(dest: &RvmArg, src: &RvmArg, op: &str) -> String {
+    format!("let v = {} {} {}; {}", rvm_aot_fload(dest), op, rvm_aot_fload(src), rvm_aot_fstore(dest, "v"))
+}
+
+// The address of [reg+disp]
+fn rvm_aot_ptr(reg: usize, disp: i32) -> String {
+    format!("m.regs[{}].wrapping_add({}) as u32 as usize", reg, disp)
+}
+
+// Where a part of a register sits, as a shift and a mask
+fn rvm_aot_part(part: RvmRegPart) -> (u32, u32) {
+    match part {
+        RvmRegPart::Word => (0, 0xffff),
+        RvmRegPart::Low => (0, 0xff),
+        RvmRegPart::High => (8, 0xff),
+        RvmRegPart::Dword => unreachable!("only 64-bit programs name it"),
+    }
+}
+
+fn rvm_aot_store(arg: &RvmArg, val: &str) -> String {
+    match *arg {
+        RvmArg::Reg(reg) => format!("m.regs[{}] = {};", reg, val),
+        RvmArg::Mem(addr) => format!("m.write({}, {});", addr, val),
+        RvmArg::Val(_) => String::new(),
+        RvmArg::Sub(reg, part) => {
+            let (shift, mask) = rvm_aot_part(part);
+            format!("m.regs[{reg}] = ((m.regs[{reg}] as u32 & !{:#x}) | (({val} as u32 & {mask:#x}) << {shift})) as i32;",
+                mask << shift)
+        }
+        RvmArg::Ptr(reg, disp) => format!("let at = {}; m.write(at, {});", rvm_aot_ptr(reg, disp), val),
+        RvmArg::Freg(_) | RvmArg::Fval(_) => unreachable!("checked when assembling"),
+    }
+}
+
+// `dest = dest <op> src`, loading both operands before storing like rvm_step
+fn rvm_aot_binop(dest: &RvmArg, src: &RvmArg, op: &str) -> String {
+    format!("let v = {} {} {}; {}", rvm_aot_load(dest), op, rvm_aot_load(src), rvm_aot_store(dest, "v"))
+}
+
+// `dest = dest <op> src` for and, or and xor, which clear carry and overflow
+fn rvm_aot_logic(dest: &RvmArg, src: &RvmArg, op: &str) -> String {
+    format!("{} m.flags &= !{:#x};", rvm_aot_binop(dest, src, op), RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW)
+}
+
+// `dest = dest <op> src` through Mem::alu, replacing the `affected` flags
+fn rvm_aot_alu(op: RvmAluOp, dest: &RvmArg, src: &RvmArg, affected: u32) -> String {
+    let val = format!("m.alu({}, {}, {}, {}, {:#x})",
+        op as u8, rvm_aot_load(dest), rvm_aot_load(src), dest.rvm_width(RvmMode::Bits32), affected);
+    format!("let v = {}; {}", val, rvm_aot_store(dest, "v"))
+}
+
+// `dest = dest <op> src` for the divisions, which fault on a zero divisor and, when signed, on i32::MIN / -1
+fn rvm_aot_divide(a: &RvmArg, b: &RvmArg, idx: i32, signed: bool, store: &str) -> String {
+    let overflow = if signed {
+        format!("if a == i32::MIN && b == -1 {{ m.fault({}, \"{}\"); }} ", idx, RvmFault::DivideOverflow)
+    } else {
+        String::new()
+    };
+    format!("let (a, b) = ({}, {}); if b == 0 {{ m.fault({}, \"{}\"); }} {}{}",
+        rvm_aot_load(a), rvm_aot_load(b), idx, RvmFault::DivideByZero, overflow, store)
+}
+
+// Continue at `target`, faulting like the interpreter when it is outside the program
+fn rvm_aot_jump(target: &RvmArg, idx: i32, instrs: usize) -> String {
+    match *target {
+        RvmArg::Val(target) if (target as usize) < instrs => format!("pc = {}; continue;", target),
+        _ => format!("pc = m.jump({}, {}); continue;", idx, rvm_aot_load(target)),
+    }
+}
+
+// The test of the flags a conditional jump makes, as RvmJumpCond::rvm_cond_holds does
+fn rvm_aot_cond(cond: RvmJumpCond) -> String {
+    let (mask, op) = match cond {
+        RvmJumpCond::E => (RVM_FLAG_EQUAL, "!="),
+        RvmJumpCond::Ne => (RVM_FLAG_EQUAL, "=="),
+        RvmJumpCond::G => (RVM_FLAG_GREATER, "!="),
+        RvmJumpCond::Ge => (RVM_FLAG_EQUAL | RVM_FLAG_GREATER, "!="),
+        RvmJumpCond::L => (RVM_FLAG_EQUAL | RVM_FLAG_GREATER, "=="),
+        RvmJumpCond::Le => (RVM_FLAG_GREATER, "=="),
+    };
+    format!("m.flags & {:#x} {} 0", mask, op)
+}
+
+// Whether `instr` may load or store memory, and so fault on a bad address
+fn rvm_aot_touches_memory(instr: &RvmInstr) -> bool {
+    match instr {
+        RvmInstr::Lea(..) => false,
+        RvmInstr::Push(_) | RvmInstr::Pop(_) | RvmInstr::Pushf | RvmInstr::Popf | RvmInstr::PushPop(..)
+        | RvmInstr::Call(_) | RvmInstr::Ret | RvmInstr::RetN(_) | RvmInstr::Enter(_) | RvmInstr::Leave
+        | RvmInstr::Movs(..) | RvmInstr::Stos(..) | RvmInstr::Cmps(..) | RvmInstr::Scas(..) => true,
+        _ => instr.rvm_args().iter().any(|arg| matches!(arg, RvmArg::Mem(_) | RvmArg::Ptr(..))),
+    }
+}
+
+// Rust statements for one instruction of a program of `instrs` instructions
+// One iteration of a string instruction, or all of them under a rep prefix
+fn rvm_aot_string(instr: &RvmInstr) -> String {
+    let (size, rep, step) = match *instr {
+        RvmInstr::Movs(size, rep) => (size, rep, format!(
+            "let v = m.read_n(m.regs[4] as u32 as usize, {0}); m.write_n(m.regs[5] as u32 as usize, {0}, v);", size)),
+        RvmInstr::Stos(size, rep) => (size, rep, format!(
+            "let v = m.regs[0]; m.write_n(m.regs[5] as u32 as usize, {}, v);", size)),
+        RvmInstr::Cmps(size, rep) => (size, rep, format!(
+            "let (a, b) = (m.read_n(m.regs[4] as u32 as usize, {0}), m.read_n(m.regs[5] as u32 as usize, {0})); \
+            m.flags = ((a == b) as u32) | (((a > b) as u32) << 1);", size)),
+        RvmInstr::Scas(size, rep) => (size, rep, format!(
+            "let (a, b) = (m.regs[0] & {:#x}, m.read_n(m.regs[5] as u32 as usize, {})); \
+            m.flags = ((a == b) as u32) | (((a > b) as u32) << 1);", (1u32 << (8 * size)) - 1, size)),
+        _ => unreachable!("not a string instruction"),
+    };
+    let steps_si = matches!(instr, RvmInstr::Movs(..) | RvmInstr::Cmps(..));
+    let mut stmts = step;
+    if steps_si {
+        stmts += &format!(" m.regs[4] = m.regs[4].wrapping_add({});", size);
+    }
+    stmts += &format!(" m.regs[5] = m.regs[5].wrapping_add({});", size);
+    let stop = match rep {
+        RvmRep::Once => return stmts,
+        RvmRep::Rep => String::new(),
+        RvmRep::Repe => format!(" if m.flags & {:#x} == 0 {{ break; }}", RVM_FLAG_EQUAL),
+        RvmRep::Repne => format!(" if m.flags & {:#x} != 0 {{ break; }}", RVM_FLAG_EQUAL),
+    };
+    format!("while m.regs[2] != 0 {{ {} m.regs[2] = m.regs[2].wrapping_sub(1);{} }}", stmts, stop)
+}
+
+fn rvm_aot_instr(instr: &RvmInstr, idx: i32, instrs: usize) -> String {
+    match instr {
+        RvmInstr::Nop => String::new(),
+        RvmInstr::Int(n) => format!("m.int({}, {});", idx, rvm_aot_load(n)),
+        RvmInstr::Mov(dest, src) => format!("let v = {}; {}", rvm_aot_load(src), rvm_aot_store(dest, "v")),
+        RvmInstr::Push(src) => format!("let v = {}; m.push(v);", rvm_aot_load(src)),
+        RvmInstr::Pop(dest) => format!("let v = m.pop(); {}", rvm_aot_store(dest, "v")),
+        RvmInstr::Pushf => "m.push(m.flags as i32);".to_string(),
+        RvmInstr::Popf => "m.flags = m.pop() as u32;".to_string(),
+        RvmInstr::Inc(dest) => rvm_aot_alu(RvmAluOp::Add, dest, &RvmArg::Val(1), RVM_FLAG_OVERFLOW),
+        RvmInstr::Dec(dest) => rvm_aot_alu(RvmAluOp::Sub, dest, &RvmArg::Val(1), RVM_FLAG_OVERFLOW),
+        RvmInstr::Add(dest, src) => rvm_aot_alu(RvmAluOp::Add, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW),
+        RvmInstr::Sub(dest, src) => rvm_aot_alu(RvmAluOp::Sub, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW),
+        RvmInstr::Mul(dest, src) => rvm_aot_alu(RvmAluOp::Mul, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW),
+        RvmInstr::Div(dest, src) => rvm_aot_divide(dest, src, idx, true, &rvm_aot_store(dest, "a / b")),
+        RvmInstr::Mod(a, b) => rvm_aot_divide(a, b, idx, true, "m.remainder = a % b;"),
+        RvmInstr::Rem(dest) => format!("let v = m.remainder; {}", rvm_aot_store(dest, "v")),
+        RvmInstr::Not(dest) => format!("let v = !{}; {}", rvm_aot_load(dest), rvm_aot_store(dest, "v")),
+        RvmInstr::Xor(dest, src) => rvm_aot_logic(dest, src, "^"),
+        RvmInstr::Or(dest, src) => rvm_aot_logic(dest, src, "|"),
+        RvmInstr::And(dest, src) => rvm_aot_logic(dest, src, "&"),
+        RvmInstr::Shl(dest, src) => rvm_aot_alu(RvmAluOp::Shl, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW),
+        RvmInstr::Shr(dest, src) => rvm_aot_alu(RvmAluOp::Shr, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW),
+        RvmInstr::Sar(dest, src) => rvm_aot_alu(RvmAluOp::Sar, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW),
+        RvmInstr::Rol(dest, src) => rvm_aot_alu(RvmAluOp::Rol, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW),
+        RvmInstr::Ror(dest, src) => rvm_aot_alu(RvmAluOp::Ror, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW),
+        RvmInstr::Umul(dest, src) => rvm_aot_alu(RvmAluOp::Umul, dest, src, RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW),
+        RvmInstr::Neg(dest) => format!("let v = m.alu({}, 0, {}, {}, {:#x}); {}",
+            RvmAluOp::Sub as u8, rvm_aot_load(dest), dest.rvm_width(RvmMode::Bits32), RVM_FLAG_CARRY | RVM_FLAG_OVERFLOW,
+            rvm_aot_store(dest, "v")),
+        RvmInstr::Udiv(dest, src) => {
+            rvm_aot_divide(dest, src, idx, false, &rvm_aot_store(dest, "(a as u32 / b as u32) as i32"))
+        }
+        RvmInstr::Umod(a, b) => rvm_aot_divide(a, b, idx, false, "m.remainder = (a as u32 % b as u32) as i32;"),
+        RvmInstr::Test(a, b) => format!("let v = {} & {}; m.flags = ((v == 0) as u32) | (((v > 0) as u32) << 1);",
+            rvm_aot_load(a), rvm_aot_load(b)),
+        RvmInstr::Xchg(a, b) => format!("let (x, y) = ({}, {}); {} {}",
+            rvm_aot_load(a), rvm_aot_load(b), rvm_aot_store(a, "y"), rvm_aot_store(b, "x")),
+        RvmInstr::Lea(dest, RvmArg::Mem(addr)) => format!("let v = {}; {}", addr, rvm_aot_store(dest, "v")),
+        RvmInstr::Lea(dest, RvmArg::Ptr(reg, disp)) => {
+            format!("let v = ({}) as i32; {}", rvm_aot_ptr(*reg, *disp), rvm_aot_store(dest, "v"))
+        }
+        RvmInstr::Lea(..) => unreachable!("checked when assembling"),
+        RvmInstr::Cmp(a, b) => format!("let (a, b) = ({}, {}); m.flags = ((a == b) as u32) | (((a > b) as u32) << 1);",
+            rvm_aot_load(a), rvm_aot_load(b)),
+        RvmInstr::Jmp(target) => rvm_aot_jump(target, idx, instrs),
+        RvmInstr::Call(RvmArg::Val(target)) if (*target as usize) < instrs => {
+            format!("m.push({}); pc = {}; continue;", idx + 1, target)
+        }
+        RvmInstr::Call(target) => format!("let t = {}; m.push({}); pc = m.jump({}, t); continue;",
+            rvm_aot_load(target), idx + 1, idx),
+        RvmInstr::Ret => format!("let t = m.pop(); pc = m.jump({}, t); continue;", idx),
+        RvmInstr::RetN(n) => format!("let n = {}; let t = m.pop(); m.regs[6] = m.regs[6].wrapping_add(n); \
+            pc = m.jump({}, t); continue;", rvm_aot_load(n), idx),
+        RvmInstr::Enter(n) => format!("let n = {}; let v = m.regs[7]; m.push(v); m.regs[7] = m.regs[6]; \
+            m.regs[6] = m.regs[6].wrapping_sub(n);", rvm_aot_load(n)),
+        RvmInstr::Leave => "m.regs[6] = m.regs[7]; m.regs[7] = m.pop();".to_string(),
+        RvmInstr::Prn(src) => format!("println!(\"{{}}\", {});", rvm_aot_load(src)),
+        RvmInstr::Movsx(dest, RvmArg::Sub(reg, part)) => {
+            let ty = if *part == RvmRegPart::Word { "i16" } else { "i8" };
+            let val = format!("{} as {} as i32", rvm_aot_load(&RvmArg::Sub(*reg, *part)), ty);
+            format!("let v = {}; {}", val, rvm_aot_store(dest, "v"))
+        }
+        RvmInstr::Movsx(dest, src) => rvm_aot_instr(&RvmInstr::Mov(*dest, *src), idx, instrs),
+        RvmInstr::Loop(target) | RvmInstr::Loopz(target) | RvmInstr::Loopnz(target) => {
+            let cond = match instr {
+                RvmInstr::Loopz(_) => format!(" && m.flags & {:#x} != 0", RVM_FLAG_EQUAL),
+                RvmInstr::Loopnz(_) => format!(" && m.flags & {:#x} == 0", RVM_FLAG_EQUAL),
+                _ => String::new(),
+            };
+            format!("let c = m.regs[2].wrapping_sub(1); m.regs[2] = c; if c != 0{} {{ {} }}",
+                cond, rvm_aot_jump(target, idx, instrs))
+        }
+        RvmInstr::Movs(..) | RvmInstr::Stos(..) | RvmInstr::Cmps(..) | RvmInstr::Scas(..) => rvm_aot_string(instr),
+        RvmInstr::Fld(dest, src) | RvmInstr::Fst(dest, src) => {
+            format!("let v = {}; {}", rvm_aot_fload(src), rvm_aot_fstore(dest, "v"))
+        }
+        RvmInstr::Fadd(dest, src) => rvm_aot_fbinop(dest, src, "+"),
+        RvmInstr::Fsub(dest, src) => rvm_aot_fbinop(dest, src, "-"),
+        RvmInstr::Fmul(dest, src) => rvm_aot_fbinop(dest, src, "*"),
+        RvmInstr::Fdiv(dest, src) => rvm_aot_fbinop(dest, src, "/"),
+        RvmInstr::Fsqrt(dest) => format!("let v = {}.sqrt(); {}", rvm_aot_fload(dest), rvm_aot_fstore(dest, "v")),
+        RvmInstr::Fcmp(a, b) => format!("let (a, b) = ({}, {}); m.flags = if a.is_nan() || b.is_nan() {{ {:#x} }} \
+            else {{ ((a == b) as u32) | (((a > b) as u32) << 1) }};", rvm_aot_fload(a), rvm_aot_fload(b), RVM_FLAG_CARRY),
+        RvmInstr::Itof(dest, src) => format!("let v = {} as f64; {}", rvm_aot_load(src), rvm_aot_fstore(dest, "v")),
+        RvmInstr::Ftoi(dest, src) => format!("let v = {} as i32; {}", rvm_aot_fload(src), rvm_aot_store(dest, "v")),
+        RvmInstr::Fprn(src) => format!("println!(\"{{:?}}\", {});", rvm_aot_fload(src)),
+        RvmInstr::Halt => "break;".to_string(),
+        RvmInstr::CmpJcc(cond, a, b, target) => format!("{} {}",
+            rvm_aot_instr(&RvmInstr::Cmp(*a, *b), idx, instrs),
+            format_args!("if {} {{ {} }}", rvm_aot_cond(*cond), rvm_aot_jump(target, idx, instrs))),
+        RvmInstr::IncCmpJcc(cond, a, b, target) => format!("{} {}",
+            rvm_aot_instr(&RvmInstr::Inc(*a), idx, instrs),
+            rvm_aot_instr(&RvmInstr::CmpJcc(*cond, *a, *b, *target), idx, instrs)),
+        RvmInstr::PushPop(src, dest) => format!("{} {}",
+            rvm_aot_instr(&RvmInstr::Push(*src), idx, instrs), rvm_aot_instr(&RvmInstr::Pop(*dest), idx, instrs)),
+        jump => {
+            let (cond, target) = RvmJumpCond::rvm_cond_of(jump).unwrap();
+            format!("if {} {{ {} }}", rvm_aot_cond(cond), rvm_aot_jump(&target, idx, instrs))
+        }
+    }
+}
+
+// Write `vm`'s program, in its current state, as a Rust program
+pub fn rvm_aot_rust(vm: &RvmCtx, out: &mut dyn Write) -> io::Result<()> {
+    let prog = &vm.prog;
+    if prog.mode == RvmMode::Bits64 {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "64-bit programs cannot be translated"));
+    }
+    let labels = prog.rvm_labels_sorted();
+    let leaders = prog.rvm_block_leaders();
+    let indirect = prog.code.iter().any(|instr| {
+        instr.rvm_is_return() || matches!(instr.rvm_jump_target(), Some(target) if !matches!(target, RvmArg::Val(_)))
+    });
+    let regs: Vec<String> = (0..RvmRegisterMap.len()).map(|reg| vm.mem.rvm_reg_read(reg).to_string()).collect();
+    let fregs: Vec<String> = vm.mem.fregs.iter().map(|val| rvm_aot_fload(&RvmArg::Fval(*val))).collect();
+
+    writeln!(out, "// Translated from {} by rusty-vm aot", prog.files.first().map_or("", String::as_str))?;
+    writeln!(out, "#![allow(unused, unreachable_code)]")?;
+    writeln!(out)?;
+    writeln!(out, "use std::convert::TryInto;")?;
+    writeln!(out)?;
+    writeln!(out, "struct Mem {{")?;
+    writeln!(out, "    flags: u32,")?;
+    writeln!(out, "    remainder: i32,")?;
+    writeln!(out, "    mem_space: Vec<u8>,")?;
+    writeln!(out, "    regs: [i32; {}],", RvmRegisterMap.len())?;
+    writeln!(out, "    fregs: [f64; {}],", RvmFloatRegisterMap.len())?;
+    // The instruction to blame when a memory access faults
+    writeln!(out, "    pc: i32,")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    writeln!(out, "impl Mem {{")?;
+    // Same as rvm_mem_range: the `len` bytes at `addr`, faulting unless all of them are inside memory
+    writeln!(out, "    fn range(&self, addr: usize, len: usize) -> std::ops::Range<usize> {{")?;
+    writeln!(out, "        match addr.checked_add(len) {{")?;
+    writeln!(out, "            Some(end) if end <= self.mem_space.len() => addr..end,")?;
+    writeln!(out, "            _ => self.fault(self.pc, &format!(\"access to invalid address {{}}\", addr)),")?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    fn read(&self, addr: usize) -> i32 {{")?;
+    writeln!(out, "        i32::from_le_bytes(self.mem_space[self.range(addr, 4)].try_into().unwrap())")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    fn write(&mut self, addr: usize, val: i32) {{")?;
+    writeln!(out, "        let range = self.range(addr, 4);")?;
+    writeln!(out, "        self.mem_space[range].copy_from_slice(&val.to_le_bytes());")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    fn read_n(&self, addr: usize, len: usize) -> i32 {{")?;
+    writeln!(out, "        let mut bytes = [0; 4];")?;
+    writeln!(out, "        bytes[..len].copy_from_slice(&self.mem_space[self.range(addr, len)]);")?;
+    writeln!(out, "        i32::from_le_bytes(bytes)")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    fn write_n(&mut self, addr: usize, len: usize, val: i32) {{")?;
+    writeln!(out, "        let range = self.range(addr, len);")?;
+    writeln!(out, "        self.mem_space[range].copy_from_slice(&val.to_le_bytes()[..len]);")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    fn readf(&self, addr: usize) -> f64 {{")?;
+    writeln!(out, "        f64::from_le_bytes(self.mem_space[self.range(addr, 8)].try_into().unwrap())")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    fn writef(&mut self, addr: usize, val: f64) {{")?;
+    writeln!(out, "        let range = self.range(addr, 8);")?;
+    writeln!(out, "        self.mem_space[range].copy_from_slice(&val.to_le_bytes());")?;
+    writeln!(out, "    }}")?;
+    // esp only moves once the slot is known to be inside memory
+    writeln!(out, "    fn push(&mut self, val: i32) {{")?;
+    writeln!(out, "        let sp = self.regs[6].wrapping_sub(4);")?;
+    writeln!(out, "        self.write(sp as u32 as usize, val);")?;
+    writeln!(out, "        self.regs[6] = sp;")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    fn pop(&mut self) -> i32 {{")?;
+    writeln!(out, "        let sp = self.regs[6];")?;
+    writeln!(out, "        let val = self.read(sp as u32 as usize);")?;
+    writeln!(out, "        self.regs[6] = sp.wrapping_add(4);")?;
+    writeln!(out, "        val")?;
+    writeln!(out, "    }}")?;
+    // Same as rvm_alu, with the operation numbered like RvmAluOp
+    writeln!(out, "    fn alu(&mut self, op: u8, a: i32, b: i32, width: u32, affected: u32) -> i32 {{")?;
+    writeln!(out, "        let mask: u64 = (1 << width) - 1;")?;
+    writeln!(out, "        let sign: u64 = 1 << (width - 1);")?;
+    writeln!(out, "        let (ua, ub) = (a as u32 as u64 & mask, b as u32 as u64 & mask);")?;
+    writeln!(out, "        let signed = |val: u64| (val ^ sign).wrapping_sub(sign) as i64;")?;
+    writeln!(out, "        let count = b as u32 & 31;")?;
+    writeln!(out, "        let (res, carry, overflow) = match op {{")?;
+    writeln!(out, "            {} => {{", RvmAluOp::Add as u8)?;
+    writeln!(out, "                let res = (ua + ub) & mask;")?;
+    writeln!(out, "                (res, ua + ub > mask, (ua ^ res) & (ub ^ res) & sign != 0)")?;
+    writeln!(out, "            }}")?;
+    writeln!(out, "            {} => {{", RvmAluOp::Sub as u8)?;
+    writeln!(out, "                let res = ua.wrapping_sub(ub) & mask;")?;
+    writeln!(out, "                (res, ua < ub, (ua ^ ub) & (ua ^ res) & sign != 0)")?;
+    writeln!(out, "            }}")?;
+    writeln!(out, "            {} => {{", RvmAluOp::Mul as u8)?;
+    writeln!(out, "                let product = signed(ua) * signed(ub);")?;
+    writeln!(out, "                let res = product as u64 & mask;")?;
+    writeln!(out, "                (res, product != signed(res), product != signed(res))")?;
+    writeln!(out, "            }}")?;
+    writeln!(out, "            {} => {{", RvmAluOp::Umul as u8)?;
+    writeln!(out, "                let product = ua * ub;")?;
+    writeln!(out, "                (product & mask, product > mask, product > mask)")?;
+    writeln!(out, "            }}")?;
+    writeln!(out, "            _ if count == 0 => return ua as i32,")?;
+    writeln!(out, "            {} => {{", RvmAluOp::Shl as u8)?;
+    writeln!(out, "                let wide = ua << count;")?;
+    writeln!(out, "                (wide & mask, (wide >> width) & 1 != 0, (ua ^ (wide & mask)) & sign != 0)")?;
+    writeln!(out, "            }}")?;
+    writeln!(out, "            {} => {{", RvmAluOp::Shr as u8)?;
+    writeln!(out, "                let res = ua >> count;")?;
+    writeln!(out, "                (res, (ua >> (count - 1)) & 1 != 0, (ua ^ res) & sign != 0)")?;
+    writeln!(out, "            }}")?;
+    writeln!(out, "            {} => {{", RvmAluOp::Sar as u8)?;
+    writeln!(out, "                let val = signed(ua);")?;
+    writeln!(out, "                ((val >> count) as u64 & mask, (val >> (count - 1)) & 1 != 0, false)")?;
+    writeln!(out, "            }}")?;
+    writeln!(out, "            _ => {{")?;
+    writeln!(out, "                let rol = op == {};", RvmAluOp::Rol as u8)?;
+    writeln!(out, "                let by = if rol {{ count % width }} else {{ (width - count % width) % width }};")?;
+    writeln!(out, "                let res = ((ua << by) | (ua >> ((width - by) % width))) & mask;")?;
+    writeln!(out, "                let carry = if rol {{ res & 1 }} else {{ res & sign }};")?;
+    writeln!(out, "                (res, carry != 0, (ua ^ res) & sign != 0)")?;
+    writeln!(out, "            }}")?;
+    writeln!(out, "        }};")?;
+    writeln!(out, "        let flags = (carry as u32) << 2 | (overflow as u32) << 3;")?;
+    writeln!(out, "        self.flags = (self.flags & !affected) | (flags & affected);")?;
+    writeln!(out, "        res as u32 as i32")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    fn fault(&self, pc: i32, what: &str) -> ! {{")?;
+    writeln!(out, "        eprintln!(\"Fault at instruction {{}}: {{}}\", pc, what);")?;
+    writeln!(out, "        std::process::exit(4);")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    fn jump(&self, pc: i32, target: i32) -> i32 {{")?;
+    writeln!(out, "        if target < 0 || target >= {} {{", prog.code.len())?;
+    writeln!(out, "            self.fault(pc, &format!(\"jump to invalid instruction {{}}\", target));")?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "        target")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    fn int(&mut self, pc: i32, n: i32) {{")?;
+    writeln!(out, "        match n {{")?;
+    writeln!(out, "            {} => std::process::exit(self.regs[0]),", RVM_INT_EXIT)?;
+    writeln!(out, "            {} | {} => {{}}", RVM_INT_YIELD, RVM_INT_BREAK)?;
+    writeln!(out, "            {} => {{", RVM_INT_READ)?;
+    writeln!(out, "                let mut line = String::new();")?;
+    writeln!(out, "                if std::io::stdin().read_line(&mut line).unwrap_or(0) == 0 {{")?;
+    writeln!(out, "                    eprintln!(\"out of input at instruction {{}}\", pc);")?;
+    writeln!(out, "                    std::process::exit(1);")?;
+    writeln!(out, "                }}")?;
+    writeln!(out, "                self.regs[0] = line.trim().parse().unwrap_or_else(|_| {{")?;
+    writeln!(out, "                    eprintln!(\"invalid input: {{}}\", line.trim());")?;
+    writeln!(out, "                    std::process::exit(1)")?;
+    writeln!(out, "                }});")?;
+    writeln!(out, "            }}")?;
+    writeln!(out, "            _ => self.fault(pc, &format!(\"unknown interrupt {{}}\", n)),")?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+    writeln!(out, "fn main() {{")?;
+    writeln!(out, "    let mut m = Mem {{")?;
+    writeln!(out, "        flags: {},", vm.mem.flags)?;
+    writeln!(out, "        remainder: {},", vm.mem.remainder)?;
+    writeln!(out, "        mem_space: vec![0; {}],", vm.mem.mem_space.len())?;
+    writeln!(out, "        regs: [{}],", regs.join(", "))?;
+    writeln!(out, "        fregs: [{}],", fregs.join(", "))?;
+    writeln!(out, "        pc: {},", prog.start)?;
+    writeln!(out, "    }};")?;
+    // The data sections, less the zeros memory already starts with
+    let data_len = prog.data.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1);
+    if data_len > 0 {
+        let bytes: Vec<String> = prog.data[..data_len].iter().map(u8::to_string).collect();
+        writeln!(out, "    m.mem_space[{}..{}].copy_from_slice(&[{}]);", RVM_DATA_BASE, RVM_DATA_BASE + data_len, bytes.join(", "))?;
+    }
+    writeln!(out, "    let mut pc: i32 = {};", prog.start)?;
+    writeln!(out, "    loop {{")?;
+    writeln!(out, "        match pc {{")?;
+
+    // Blocks start at leaders; with indirect jumps any other instruction may be jumped to as well
+    let mut starts: Vec<usize> = (0..prog.code.len()).filter(|idx| *idx == 0 || leaders[*idx]).collect();
+    if indirect {
+        starts.extend((1..prog.code.len()).filter(|idx| !leaders[*idx]));
+    }
+    for start in starts {
+        writeln!(out, "            {} => {{", start)?;
+        for (_, name) in labels.iter().filter(|(label_idx, _)| *label_idx == start as i32) {
+            writeln!(out, "                // {}:", name)?;
+        }
+        let mut idx = start;
+        loop {
+            let instr = &prog.code[idx];
+            writeln!(out, "                // {}", instr)?;
+            if rvm_aot_touches_memory(instr) {
+                writeln!(out, "                m.pc = {};", idx)?;
+            }
+            let stmts = rvm_aot_instr(instr, idx as i32, prog.code.len());
+            if !stmts.is_empty() {
+                writeln!(out, "                {}", stmts)?;
+            }
+            idx += 1;
+            if matches!(instr, RvmInstr::Jmp(_) | RvmInstr::Call(_) | RvmInstr::Halt) || instr.rvm_is_return() {
+                break;
+            }
+            if instr.rvm_ends_block() || leaders[idx] {
+                writeln!(out, "                pc = {};", idx)?;
+                break;
+            }
+        }
+        writeln!(out, "            }}")?;
+    }
+
+    writeln!(out, "            _ => unreachable!(),")?;
+    writeln!(out, "        }}")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "}}")?;
+    Ok(())
+}
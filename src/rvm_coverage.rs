@@ -0,0 +1,101 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::rvm::{RvmCtx, RvmHook};
+use crate::rvm_memory::RvmAccess;
+use crate::rvm_prog::RvmArg;
+
+#[derive(Default)]
+struct RvmLineCov {
+    hits: u64,
+    /* (taken, not taken) per conditional jump on the line */
+    branches: Vec<Option<(u64, u64)>>,
+}
+
+pub struct RvmCoverage {
+    hits: Vec<u64>,
+    taken: Vec<u64>,
+    not_taken: Vec<u64>,
+}
+
+impl RvmCoverage {
+    pub fn new(vm: &RvmCtx) -> Self {
+        let len = vm.prog.code.len();
+        RvmCoverage {
+            hits: vec![0; len],
+            taken: vec![0; len],
+            not_taken: vec![0; len]
+        }
+    }
+
+    // Emit the collected counts as an LCOV tracefile, one record per source file
+    pub fn rvm_coverage_lcov(&self, vm: &RvmCtx, out: &mut dyn Write) -> io::Result<()> {
+        let mut files: Vec<BTreeMap<u32, RvmLineCov>> = vm.prog.files.iter().map(|_| BTreeMap::new()).collect();
+
+        // Skip the sentinel, which has no source line of its own
+        for idx in 0..vm.prog.code.len() - 1 {
+            let loc = vm.prog.locs[idx];
+            let line = files[loc.file].entry(loc.line).or_default();
+            line.hits += self.hits[idx];
+            if vm.prog.code[idx].rvm_is_cond_jump() {
+                let counts = (self.hits[idx] > 0).then_some((self.taken[idx], self.not_taken[idx]));
+                line.branches.push(counts);
+            }
+        }
+
+        writeln!(out, "TN:")?;
+        for (file, lines) in vm.prog.files.iter().zip(&files) {
+            if lines.is_empty() {
+                continue;
+            }
+            writeln!(out, "SF:{}", file)?;
+            let (mut found, mut hit) = (0, 0);
+            for (line, cov) in lines {
+                for (block, counts) in cov.branches.iter().enumerate() {
+                    match counts {
+                        Some((taken, not_taken)) => {
+                            writeln!(out, "BRDA:{},{},0,{}", line, block, taken)?;
+                            writeln!(out, "BRDA:{},{},1,{}", line, block, not_taken)?;
+                            hit += (*taken > 0) as u32 + (*not_taken > 0) as u32;
+                        }
+                        None => {
+                            writeln!(out, "BRDA:{},{},0,-", line, block)?;
+                            writeln!(out, "BRDA:{},{},1,-", line, block)?;
+                        }
+                    }
+                    found += 2;
+                }
+            }
+            writeln!(out, "BRF:{}", found)?;
+            writeln!(out, "BRH:{}", hit)?;
+            for (line, cov) in lines {
+                writeln!(out, "DA:{},{}", line, cov.hits)?;
+            }
+            writeln!(out, "LF:{}", lines.len())?;
+            writeln!(out, "LH:{}", lines.values().filter(|cov| cov.hits > 0).count())?;
+            writeln!(out, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+impl RvmHook for RvmCoverage {
+    fn rvm_hook_step(&mut self, vm: &RvmCtx, instr_idx: i32, _accesses: &[RvmAccess]) {
+        let idx = instr_idx as usize;
+        self.hits[idx] += 1;
+        let instr = vm.prog.code[idx];
+        if instr.rvm_is_cond_jump() {
+            // A jump to the very next instruction counts as taken
+            let fallthrough = instr_idx + 1;
+            let target = match instr.rvm_args().first() {
+                Some(RvmArg::Val(target)) => *target,
+                _ => -1,
+            };
+            if vm.rvm_vm_eip() != fallthrough || target == fallthrough as i64 {
+                self.taken[idx] += 1;
+            } else {
+                self.not_taken[idx] += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,535 @@
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+
+use crate::rvm::{RvmCtx, RvmFloatRegisterMap, RvmRegisterMap, RvmRunResult};
+use crate::rvm_gas::{rvm_gas_parts_done, RvmGas};
+use crate::rvm_history::{RvmHistory, RvmUndoRecord, RVM_HISTORY_DEFAULT_CAPACITY};
+use crate::rvm_memory::{rvm_ranges_overlap, RvmAccess};
+use crate::rvm_snapshot::{rvm_snapshot_restore, rvm_snapshot_save};
+use crate::rvm_prog::{RvmArg, RvmMode};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RvmWatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RvmCondOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+const RVM_COND_OPS: [(&str, RvmCondOp); 6] = [
+    ("==", RvmCondOp::Eq), ("!=", RvmCondOp::Ne),
+    ("<", RvmCondOp::Lt), ("<=", RvmCondOp::Le),
+    (">", RvmCondOp::Gt), (">=", RvmCondOp::Ge),
+];
+
+/* A comparison such as `eax > 100`, where either side may be a
+ * register, a memory word or a literal. */
+pub struct RvmCondition {
+    pub lhs: RvmArg,
+    pub op: RvmCondOp,
+    pub rhs: RvmArg,
+}
+
+impl RvmCondition {
+    pub fn rvm_cond_eval(&self, vm: &RvmCtx) -> bool {
+        let lhs = vm.rvm_vm_peek(&self.lhs);
+        let rhs = vm.rvm_vm_peek(&self.rhs);
+        match self.op {
+            RvmCondOp::Eq => lhs == rhs,
+            RvmCondOp::Ne => lhs != rhs,
+            RvmCondOp::Lt => lhs < rhs,
+            RvmCondOp::Le => lhs <= rhs,
+            RvmCondOp::Gt => lhs > rhs,
+            RvmCondOp::Ge => lhs >= rhs,
+        }
+    }
+}
+
+pub enum RvmStopPoint {
+    Break(i32),
+    WatchReg(usize),
+    WatchMem { addr: usize, len: usize, kind: RvmWatchKind },
+    /* Only fires on the transition from false to true */
+    WatchCond { cond: RvmCondition, was_true: bool },
+}
+
+/* A register or memory range whose last write reverse-continue looks for */
+pub enum RvmLocation {
+    Reg(usize),
+    Mem { addr: usize, len: usize },
+}
+
+pub enum RvmDbgStop {
+    Step,
+    Halted,
+    Hit { id: usize, detail: String },
+    /* Reverse execution ran out of recorded history */
+    HistoryStart,
+    /* Reverse execution reached the instruction that last wrote a location */
+    LastWrite(String),
+    /* The gas budget does not cover the next instruction */
+    OutOfGas,
+    /* The program trapped with `int` or faulted */
+    Trap(RvmRunResult),
+}
+
+pub struct RvmDebugger {
+    pub points: Vec<Option<RvmStopPoint>>,
+    pub history: RvmHistory,
+    /* Where execution last stopped, so continuing does not re-report it */
+    stopped_at: Option<i32>,
+}
+
+fn rvm_cond_op_name(op: RvmCondOp) -> &'static str {
+    RVM_COND_OPS.iter().find(|(_, o)| *o == op).unwrap().0
+}
+
+impl RvmDebugger {
+    pub fn new() -> Self {
+        RvmDebugger {
+            points: Vec::new(),
+            history: RvmHistory::new(RVM_HISTORY_DEFAULT_CAPACITY),
+            stopped_at: None
+        }
+    }
+
+    // Stop points are numbered from 1 and keep their number when others are deleted
+    pub fn rvm_dbg_add(&mut self, point: RvmStopPoint) -> usize {
+        self.points.push(Some(point));
+        self.points.len()
+    }
+
+    pub fn rvm_dbg_delete(&mut self, id: usize) -> bool {
+        match self.points.get_mut(id.wrapping_sub(1)) {
+            Some(slot) if slot.is_some() => {
+                *slot = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn rvm_dbg_describe(&self, id: usize, mode: RvmMode) -> String {
+        match &self.points[id - 1] {
+            Some(RvmStopPoint::Break(idx)) => format!("Breakpoint {} at {}", id, idx),
+            Some(RvmStopPoint::WatchReg(reg)) => format!("Watchpoint {}: {}", id, mode.rvm_reg_name(*reg)),
+            Some(RvmStopPoint::WatchMem { addr, len, kind }) => {
+                let kind = match kind {
+                    RvmWatchKind::Read => "read",
+                    RvmWatchKind::Write => "write",
+                    RvmWatchKind::Access => "access",
+                };
+                format!("Watchpoint {}: {} [{}..{}]", id, kind, addr, addr.saturating_add(*len))
+            }
+            Some(RvmStopPoint::WatchCond { cond, .. }) => format!(
+                "Watchpoint {}: {} {} {}",
+                id, cond.lhs.rvm_arg_text(mode), rvm_cond_op_name(cond.op), cond.rhs.rvm_arg_text(mode)
+            ),
+            None => format!("Deleted {}", id),
+        }
+    }
+
+    fn breakpoint_at(&self, instr_idx: i32) -> Option<usize> {
+        self.points.iter().position(|p| matches!(p, Some(RvmStopPoint::Break(idx)) if *idx == instr_idx))
+            .map(|i| i + 1)
+    }
+
+    // Seed the condition watchpoints so one that already holds does not fire immediately
+    pub fn rvm_dbg_arm(&mut self, vm: &RvmCtx) {
+        for point in self.points.iter_mut().flatten() {
+            if let RvmStopPoint::WatchCond { cond, was_true } = point {
+                *was_true = cond.rvm_cond_eval(vm);
+            }
+        }
+    }
+
+    fn check_watchpoints(&mut self, vm: &RvmCtx, accesses: &[RvmAccess]) -> Option<(usize, String)> {
+        let mut hit = None;
+        for (i, point) in self.points.iter_mut().enumerate() {
+            let detail = match point {
+                Some(RvmStopPoint::WatchReg(watched)) => accesses.iter().find_map(|a| match *a {
+                    RvmAccess::RegWrite { reg, old, new } if reg == *watched && old != new => {
+                        Some(format!("{} {} -> {}", vm.prog.mode.rvm_reg_name(reg), old, new))
+                    }
+                    _ => None,
+                }),
+                Some(RvmStopPoint::WatchMem { addr: w_addr, len: w_len, kind }) => accesses.iter().find_map(|a| match *a {
+                    RvmAccess::MemRead { addr, len }
+                        if *kind != RvmWatchKind::Write && rvm_ranges_overlap(addr, len, *w_addr, *w_len) => {
+                        Some(format!("read at {}", addr))
+                    }
+                    RvmAccess::MemWrite { addr, len, .. }
+                        if *kind != RvmWatchKind::Read && rvm_ranges_overlap(addr, len, *w_addr, *w_len) => {
+                        Some(format!("write at {}, value {}", addr, vm.mem.rvm_mem_peek(addr)))
+                    }
+                    _ => None,
+                }),
+                Some(RvmStopPoint::WatchCond { cond, was_true }) => {
+                    let is_true = cond.rvm_cond_eval(vm);
+                    let fired = is_true && !*was_true;
+                    *was_true = is_true;
+                    if fired {
+                        Some(format!("{} = {}", cond.lhs.rvm_arg_text(vm.prog.mode), vm.rvm_vm_peek(&cond.lhs)))
+                    } else {
+                        None
+                    }
+                }
+                _ => None,
+            };
+            // Keep evaluating so every condition tracks its latest state
+            if hit.is_none() {
+                hit = detail.map(|d| (i + 1, d));
+            }
+        }
+        hit
+    }
+
+    // Execute a single instruction, reporting the first watchpoint it triggers
+    pub fn rvm_dbg_step(&mut self, vm: &mut RvmCtx) -> RvmDbgStop {
+        if vm.rvm_vm_halted() {
+            return RvmDbgStop::Halted;
+        }
+        let eip = vm.rvm_vm_eip();
+        // Debugged code is never fused, so an affordable instruction runs whole
+        let instr = vm.prog.code[eip as usize];
+        if vm.gas.as_ref().is_some_and(|budget| budget.rvm_gas_affordable(&instr).is_none()) {
+            return RvmDbgStop::OutOfGas;
+        }
+        let (exit_code, next_input, input_len) = (vm.exit_code, vm.input.front().copied(), vm.input.len());
+        vm.mem.journal = Some(Vec::new());
+
+        let trap = vm.rvm_vm_step();
+        let next = vm.rvm_vm_eip();
+        // An instruction that stopped without completing is paid for when it runs again
+        let done = if trap.is_some() { rvm_gas_parts_done(eip, next) } else { usize::MAX };
+        let gas = vm.gas.as_mut().filter(|_| done > 0).map(|budget| budget.rvm_gas_pay(&instr, done));
+
+        let accesses = vm.mem.journal.take().unwrap_or_default();
+        let hit = self.check_watchpoints(vm, &accesses);
+        let input = next_input.filter(|_| vm.input.len() < input_len);
+        self.history.rvm_history_push(RvmUndoRecord { eip, accesses, input, exit_code, gas });
+        self.stopped_at = Some(next);
+
+        match (trap, hit) {
+            (Some(RvmRunResult::Halted(_)), _) => RvmDbgStop::Halted,
+            (Some(result), _) => RvmDbgStop::Trap(result),
+            (None, Some((id, detail))) => RvmDbgStop::Hit { id, detail },
+            (None, None) => RvmDbgStop::Step,
+        }
+    }
+
+    pub fn rvm_dbg_continue(&mut self, vm: &mut RvmCtx) -> RvmDbgStop {
+        self.rvm_dbg_run(vm, usize::MAX)
+    }
+
+    // Continue for at most `max_steps` instructions, returning Step if nothing stopped us
+    pub fn rvm_dbg_run(&mut self, vm: &mut RvmCtx, max_steps: usize) -> RvmDbgStop {
+        for _ in 0..max_steps {
+            if vm.rvm_vm_halted() {
+                return RvmDbgStop::Halted;
+            }
+            // A breakpoint we already stopped at is stepped over
+            let eip = vm.rvm_vm_eip();
+            if self.stopped_at != Some(eip)
+                && let Some(id) = self.breakpoint_at(eip) {
+                self.stopped_at = Some(eip);
+                return RvmDbgStop::Hit { id, detail: String::new() };
+            }
+            match self.rvm_dbg_step(vm) {
+                RvmDbgStop::Step => self.stopped_at = None,
+                stop => return stop,
+            }
+        }
+        RvmDbgStop::Step
+    }
+}
+
+impl RvmDebugger {
+    fn after_reverse(&mut self, vm: &RvmCtx) {
+        self.stopped_at = Some(vm.rvm_vm_eip());
+        self.rvm_dbg_arm(vm);
+    }
+
+    // Undo the last executed instruction. Output already printed stays printed.
+    pub fn rvm_dbg_reverse_step(&mut self, vm: &mut RvmCtx) -> RvmDbgStop {
+        let stop = match self.history.rvm_history_undo(vm) {
+            Some(_) => RvmDbgStop::Step,
+            None => RvmDbgStop::HistoryStart,
+        };
+        self.after_reverse(vm);
+        stop
+    }
+
+    /* Run backwards until the instruction that last wrote `target`, or
+     * until a breakpoint when no target is given. */
+    pub fn rvm_dbg_reverse_continue(&mut self, vm: &mut RvmCtx, target: Option<&RvmLocation>) -> RvmDbgStop {
+        let stop = loop {
+            let Some(record) = self.history.rvm_history_undo(vm) else {
+                break RvmDbgStop::HistoryStart;
+            };
+            match target {
+                Some(RvmLocation::Reg(reg)) if record.rvm_writes_reg(*reg) => {
+                    break RvmDbgStop::LastWrite(vm.prog.mode.rvm_reg_name(*reg).to_string());
+                }
+                Some(RvmLocation::Mem { addr, len }) if record.rvm_writes_mem(*addr, *len) => {
+                    break RvmDbgStop::LastWrite(format!("[{}..{}]", addr, addr + len));
+                }
+                _ => {}
+            }
+            if let Some(id) = self.breakpoint_at(record.eip) {
+                break RvmDbgStop::Hit { id, detail: String::new() };
+            }
+        };
+        self.after_reverse(vm);
+        stop
+    }
+}
+
+fn rvm_dbg_parse_location(vm: &mut RvmCtx, toks: &[&str]) -> Result<Option<RvmLocation>, String> {
+    match toks {
+        [] => Ok(None),
+        [reg] if RvmCtx::token_to_register(reg, vm.prog.mode).is_some() => {
+            Ok(Some(RvmLocation::Reg(RvmCtx::token_to_register(reg, vm.prog.mode).unwrap())))
+        }
+        [addr] | [addr, _] => match rvm_dbg_parse_operand(vm, addr)? {
+            RvmArg::Mem(addr) => {
+                let len = rvm_dbg_parse_len(vm, addr, toks.get(1))?;
+                Ok(Some(RvmLocation::Mem { addr, len }))
+            }
+            _ => Err(format!("Invalid location: {}", addr)),
+        },
+        _ => Err("Usage: reverse-continue [reg | [addr] [len]]".to_string()),
+    }
+}
+
+// The length of a memory range, a word by default, which has to fit in memory
+fn rvm_dbg_parse_len(vm: &mut RvmCtx, addr: usize, tok: Option<&&str>) -> Result<usize, String> {
+    let len = match tok {
+        Some(len) => vm.rvm_parse_value(len)?,
+        None => 4,
+    };
+    if len <= 0 {
+        return Err(format!("Invalid length: {}", len));
+    }
+    match addr.checked_add(len as usize) {
+        Some(end) if end <= vm.mem.mem_space.len() => Ok(len as usize),
+        _ => Err(format!("Range [{}] {} is outside memory", addr, len)),
+    }
+}
+
+fn rvm_dbg_parse_operand(vm: &mut RvmCtx, tok: &str) -> Result<RvmArg, String> {
+    if let Some(reg) = RvmCtx::token_to_register_arg(tok, vm.prog.mode) {
+        return Ok(reg);
+    }
+    if let Some(inner) = tok.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+        return vm.rvm_parse_address(inner);
+    }
+    vm.rvm_parse_immediate(tok).map(RvmArg::Val)
+}
+
+fn rvm_dbg_parse_watch(vm: &mut RvmCtx, kind: RvmWatchKind, toks: &[&str]) -> Result<RvmStopPoint, String> {
+    match toks {
+        [reg] if kind == RvmWatchKind::Write && RvmCtx::token_to_register(reg, vm.prog.mode).is_some() => {
+            Ok(RvmStopPoint::WatchReg(RvmCtx::token_to_register(reg, vm.prog.mode).unwrap()))
+        }
+        [lhs, op, rhs] if kind == RvmWatchKind::Write => {
+            let op = RVM_COND_OPS.iter().find(|(name, _)| name == op)
+                .ok_or(format!("Unknown comparison: {}", op))?.1;
+            let lhs = rvm_dbg_parse_operand(vm, lhs)?;
+            let rhs = rvm_dbg_parse_operand(vm, rhs)?;
+            Ok(RvmStopPoint::WatchCond { cond: RvmCondition { lhs, op, rhs }, was_true: false })
+        }
+        [addr] | [addr, _] if addr.starts_with('[') => {
+            let addr = match rvm_dbg_parse_operand(vm, addr)? {
+                RvmArg::Mem(addr) => addr,
+                _ => return Err(format!("Invalid address: {}", addr)),
+            };
+            let len = rvm_dbg_parse_len(vm, addr, toks.get(1))?;
+            Ok(RvmStopPoint::WatchMem { addr, len, kind })
+        }
+        _ => Err("Usage: watch <reg> | watch <lhs> <op> <rhs> | [r|a]watch [addr] [len]".to_string()),
+    }
+}
+
+fn rvm_dbg_print_regs(vm: &RvmCtx) {
+    for reg in 0..RvmRegisterMap.len() {
+        let name = vm.prog.mode.rvm_reg_name(reg);
+        match vm.prog.mode {
+            RvmMode::Bits32 => {
+                let val = vm.mem.rvm_reg_read(reg);
+                println!("{:<4} 0x{:08x} {}", name, val, val);
+            }
+            RvmMode::Bits64 => {
+                let val = vm.mem.registers[reg].rvm_reg_value();
+                println!("{:<4} 0x{:016x} {}", name, val, val);
+            }
+        }
+    }
+    for (regs, names) in vm.mem.fregs.chunks(4).zip(RvmFloatRegisterMap.chunks(4)) {
+        let row: Vec<String> = regs.iter().zip(names).map(|(val, name)| format!("{:<4} {:<12?}", name, val)).collect();
+        println!("{}", row.join(" ").trim_end());
+    }
+    println!("flags 0x{:x} remainder {}", vm.mem.flags, vm.mem.remainder);
+}
+
+fn rvm_dbg_print_stop(dbg: &RvmDebugger, vm: &RvmCtx, stop: &RvmDbgStop) {
+    match stop {
+        RvmDbgStop::Halted if vm.exit_code != 0 => println!("Program halted with exit code {}", vm.exit_code),
+        RvmDbgStop::Halted => println!("Program halted"),
+        RvmDbgStop::Step => {}
+        RvmDbgStop::HistoryStart => println!("No more reverse-execution history"),
+        RvmDbgStop::LastWrite(loc) => println!("Last write to {}", loc),
+        RvmDbgStop::OutOfGas => println!("Out of gas"),
+        RvmDbgStop::Trap(RvmRunResult::Fault(fault)) => println!("Fault: {}", fault),
+        RvmDbgStop::Trap(RvmRunResult::WaitingForInput) => println!("Waiting for input"),
+        RvmDbgStop::Trap(RvmRunResult::Yielded) => println!("Program yielded"),
+        RvmDbgStop::Trap(_) => println!("Breakpoint interrupt"),
+        RvmDbgStop::Hit { id, detail } => {
+            if detail.is_empty() {
+                println!("{}", dbg.rvm_dbg_describe(*id, vm.prog.mode));
+            } else {
+                println!("{}: {}", dbg.rvm_dbg_describe(*id, vm.prog.mode), detail);
+            }
+        }
+    }
+    if !vm.rvm_vm_halted() {
+        let eip = vm.rvm_vm_eip() as usize;
+        println!("eip = {}: {}", eip, vm.prog.code[eip].rvm_instr_text(vm.prog.mode));
+    }
+}
+
+// Interactive command loop driving the debugger from stdin
+pub fn rvm_dbg_repl(vm: &mut RvmCtx) {
+    let mut dbg = RvmDebugger::new();
+    let stdin = io::stdin();
+    let mut line = String::new();
+
+    loop {
+        print!("(rvm) ");
+        io::stdout().flush().ok();
+        line.clear();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        let toks: Vec<&str> = line.split_whitespace().collect();
+        let Some((cmd, rest)) = toks.split_first() else {
+            continue;
+        };
+
+        match *cmd {
+            "b" | "break" => {
+                let Some(loc) = rest.first() else {
+                    println!("Usage: break <label|index>");
+                    continue;
+                };
+                let idx = match vm.rvm_resolve_location(loc) {
+                    Ok(idx) => idx,
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                };
+                let id = dbg.rvm_dbg_add(RvmStopPoint::Break(idx));
+                println!("{}", dbg.rvm_dbg_describe(id, vm.prog.mode));
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let kind = match *cmd {
+                    "rwatch" => RvmWatchKind::Read,
+                    "awatch" => RvmWatchKind::Access,
+                    _ => RvmWatchKind::Write,
+                };
+                match rvm_dbg_parse_watch(vm, kind, rest) {
+                    Ok(point) => {
+                        let id = dbg.rvm_dbg_add(point);
+                        dbg.rvm_dbg_arm(vm);
+                        println!("{}", dbg.rvm_dbg_describe(id, vm.prog.mode));
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+            "d" | "delete" => {
+                let id = rest.first().and_then(|t| t.parse::<usize>().ok()).unwrap_or(0);
+                if !dbg.rvm_dbg_delete(id) {
+                    println!("No stop point number {}", id);
+                }
+            }
+            "info" => {
+                for id in 1..=dbg.points.len() {
+                    if dbg.points[id - 1].is_some() {
+                        println!("{}", dbg.rvm_dbg_describe(id, vm.prog.mode));
+                    }
+                }
+                println!("{} instructions of reverse-execution history", dbg.history.rvm_history_len());
+            }
+            "s" | "step" => {
+                let stop = dbg.rvm_dbg_step(vm);
+                rvm_dbg_print_stop(&dbg, vm, &stop);
+            }
+            "c" | "continue" => {
+                let stop = dbg.rvm_dbg_continue(vm);
+                rvm_dbg_print_stop(&dbg, vm, &stop);
+            }
+            "rs" | "reverse-step" => {
+                let stop = dbg.rvm_dbg_reverse_step(vm);
+                rvm_dbg_print_stop(&dbg, vm, &stop);
+            }
+            "rc" | "reverse-continue" => match rvm_dbg_parse_location(vm, rest) {
+                Ok(target) => {
+                    let stop = dbg.rvm_dbg_reverse_continue(vm, target.as_ref());
+                    rvm_dbg_print_stop(&dbg, vm, &stop);
+                }
+                Err(e) => println!("{}", e),
+            },
+            "r" | "regs" => rvm_dbg_print_regs(vm),
+            "gas" => match rest.first().map(|t| t.parse::<u64>()) {
+                // Add to the budget, metering from here on if there was none
+                Some(Ok(amount)) => match vm.gas.as_mut() {
+                    Some(gas) => gas.rvm_gas_refill(amount),
+                    None => vm.gas = Some(RvmGas::new(amount)),
+                },
+                Some(Err(_)) => println!("Usage: gas [amount]"),
+                None => match &vm.gas {
+                    Some(gas) => println!("{} gas left, {} used by {} instructions",
+                        gas.remaining, gas.used, gas.instructions),
+                    None => println!("No gas budget"),
+                },
+            },
+            "input" => match rest.iter().map(|t| t.parse::<i32>()).collect::<Result<Vec<_>, _>>() {
+                Ok(vals) if !vals.is_empty() => vals.into_iter().for_each(|val| vm.rvm_vm_feed_input(val)),
+                _ => println!("Usage: input <value>..."),
+            },
+            "save" | "restore" => {
+                let Some(path) = rest.first() else {
+                    println!("Usage: {} <file>", cmd);
+                    continue;
+                };
+                let res = match *cmd {
+                    "save" => File::create(path).and_then(|mut file| rvm_snapshot_save(vm, &mut file)),
+                    _ => File::open(path).and_then(|mut file| rvm_snapshot_restore(vm, &mut file)),
+                };
+                match res {
+                    Ok(()) if *cmd == "restore" => {
+                        // State changed behind the history's back, so it can no longer be replayed
+                        dbg.history.rvm_history_clear();
+                        dbg.rvm_dbg_arm(vm);
+                        rvm_dbg_print_stop(&dbg, vm, &RvmDbgStop::Step);
+                    }
+                    Ok(()) => {}
+                    Err(e) => println!("{}: {}", path, e),
+                }
+            }
+            "q" | "quit" => break,
+            _ => println!("Commands: break, watch, rwatch, awatch, delete, info, step, continue, \
+                           reverse-step, reverse-continue, regs, gas, input, save, restore, quit"),
+        }
+    }
+}
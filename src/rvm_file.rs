@@ -1,6 +1,5 @@
 use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Write, Seek, SeekFrom};
-use std::path::Path;
+use std::io::{self, Read, Seek, SeekFrom};
 
 pub fn rvm_fopen(filename: &str, _extension: &str, mode: &str) -> io::Result<File> {
     match mode {
@@ -17,8 +16,9 @@ pub fn rvm_fcopy(src: &mut File) -> io::Result<String> {
     Ok(content)
 }
 
+#[allow(dead_code)]
 pub fn rvm_flength(file: &mut File) -> io::Result<u64> {
-    let current_pos = file.seek(SeekFrom::Current(0))?;
+    let current_pos = file.stream_position()?;
     let length = file.seek(SeekFrom::End(0))?;
     file.seek(SeekFrom::Start(current_pos))?;
     Ok(length)
@@ -0,0 +1,54 @@
+/* Superinstruction fusion.
+ *
+ * Common instruction sequences are replaced by a single instruction the
+ * interpreter runs in one dispatch:
+ *
+ *   cmp a, b; jcc t          ->  cmp+jcc a, b, t
+ *   inc a; cmp a, b; jcc t   ->  inc+cmp+jcc a, b, t
+ *   push a; pop b            ->  push+pop a, b
+ *
+ * The fused instruction takes the place of the first one in the sequence
+ * and the others are left where they are, so instruction indices keep
+ * their meaning. A sequence is only fused if no label points into its
+ * middle, since a jump there would otherwise skip half of it. A fault in
+ * a later part is reported at that part, after the earlier parts ran.
+ */
+use std::collections::HashSet;
+
+use crate::rvm_prog::{RvmInstr, RvmJumpCond, RvmProg};
+
+// The superinstruction starting at the front of `code` and how many instructions it covers
+fn rvm_fuse_at(code: &[RvmInstr]) -> Option<(RvmInstr, usize)> {
+    match code {
+        [RvmInstr::Inc(a), RvmInstr::Cmp(x, b), jump, ..] if a == x => {
+            let (cond, target) = RvmJumpCond::rvm_cond_of(jump)?;
+            Some((RvmInstr::IncCmpJcc(cond, *a, *b, target), 3))
+        }
+        [RvmInstr::Cmp(a, b), jump, ..] => {
+            let (cond, target) = RvmJumpCond::rvm_cond_of(jump)?;
+            Some((RvmInstr::CmpJcc(cond, *a, *b, target), 2))
+        }
+        [RvmInstr::Push(src), RvmInstr::Pop(dest), ..] => Some((RvmInstr::PushPop(*src, *dest), 2)),
+        _ => None,
+    }
+}
+
+// Fuse every eligible sequence in `prog`, returning how many were fused
+pub fn rvm_fuse(prog: &mut RvmProg) -> usize {
+    let mut targets: HashSet<i32> = prog.labels.rvm_htab_entries().into_iter().map(|(_, idx)| idx).collect();
+    targets.insert(prog.start);
+
+    let mut fused = 0;
+    let mut idx = 0;
+    while idx < prog.code.len() {
+        match rvm_fuse_at(&prog.code[idx..]) {
+            Some((instr, len)) if !(1..len).any(|k| targets.contains(&((idx + k) as i32))) => {
+                prog.code[idx] = instr;
+                fused += 1;
+                idx += len;
+            }
+            _ => idx += 1,
+        }
+    }
+    fused
+}
@@ -0,0 +1,98 @@
+/* Instruction budget ("gas") for running untrusted programs.
+ *
+ * Every instruction costs gas; by default one unit, or whatever a cost
+ * table assigns its opcode. When the next instruction costs more than
+ * what is left, rvm_vm_run stops in front of it with
+ * RvmRunResult::OutOfGas and eip pointing at it, so the program can be
+ * resumed after rvm_gas_refill. Gas is only paid once an instruction
+ * has run, so one that stops without completing, such as `int 2`
+ * waiting for input or a faulting instruction, costs nothing and is
+ * paid for when it runs again. A superinstruction costs as much as the
+ * instructions it replaced; if only its first part is affordable, that
+ * part runs on its own, and if a later part faults only the parts
+ * before it are paid for.
+ *
+ * Cost tables are text files of `mnemonic cost` lines, '#' starting a
+ * comment; opcodes that are not listed cost 1.
+ */
+use std::fs;
+
+use crate::rvm::{RvmCtx, RvmOpcodeMap};
+use crate::rvm_prog::RvmInstr;
+
+pub struct RvmGas {
+    pub remaining: u64,
+    /* Gas spent and instructions executed since the budget was set */
+    pub used: u64,
+    pub instructions: u64,
+    /* Cost of each opcode */
+    pub costs: Vec<u64>,
+}
+
+impl RvmGas {
+    pub fn new(budget: u64) -> Self {
+        RvmGas {
+            remaining: budget,
+            used: 0,
+            instructions: 0,
+            costs: vec![1; RvmOpcodeMap.len()]
+        }
+    }
+
+    pub fn rvm_gas_load_costs(&mut self, path: &str) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        for (num, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let (Some(mnemonic), Some(cost), None) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(format!("{}:{}: expected `mnemonic cost`", path, num + 1));
+            };
+            let opcode = RvmCtx::instr_to_opcode(mnemonic);
+            if opcode < 0 {
+                return Err(format!("{}:{}: unknown instruction {}", path, num + 1, mnemonic));
+            }
+            self.costs[opcode as usize] = cost.parse()
+                .map_err(|_| format!("{}:{}: invalid cost {}", path, num + 1, cost))?;
+        }
+        Ok(())
+    }
+
+    pub fn rvm_gas_refill(&mut self, gas: u64) {
+        self.remaining = self.remaining.saturating_add(gas);
+    }
+
+    fn cost_of(&self, parts: &[RvmInstr]) -> u64 {
+        parts.iter().map(|part| self.costs[part.rvm_opcode() as usize]).sum()
+    }
+
+    // What of `instr` the budget covers: all of it, its first part, or nothing when out of gas
+    pub fn rvm_gas_affordable(&self, instr: &RvmInstr) -> Option<RvmInstr> {
+        let (parts, len) = instr.rvm_unfused();
+        match self.cost_of(&parts[..len]) {
+            cost if cost <= self.remaining => Some(*instr),
+            _ if len > 1 && self.cost_of(&parts[..1]) <= self.remaining => Some(parts[0]),
+            _ => None,
+        }
+    }
+
+    // Pay for the first `done` parts of `instr`, returning the cost
+    pub fn rvm_gas_pay(&mut self, instr: &RvmInstr, done: usize) -> u64 {
+        let (parts, len) = instr.rvm_unfused();
+        let parts = &parts[..len.min(done)];
+        let cost = self.cost_of(parts);
+        self.remaining -= cost;
+        self.used += cost;
+        self.instructions += parts.len() as u64;
+        cost
+    }
+}
+
+/* How many parts of the instruction at `instr_idx` ran when it stopped
+ * the program and left eip at `resume`: those in front of `resume`, or
+ * all of them when it resumes elsewhere. */
+pub fn rvm_gas_parts_done(instr_idx: i32, resume: i32) -> usize {
+    if resume >= instr_idx { (resume - instr_idx) as usize } else { usize::MAX }
+}
@@ -0,0 +1,429 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use crate::rvm::{RvmCtx, RvmFault, RvmRegisterMap, RvmRunResult};
+use crate::rvm_debug::{RvmDbgStop, RvmDebugger, RvmStopPoint, RvmWatchKind};
+use crate::rvm_prog::RvmMode;
+
+/* Instructions executed between checks for a ^C from the client */
+const RVM_GDB_POLL_INTERVAL: usize = 4096;
+const RVM_GDB_PACKET_SIZE: usize = 0x4000;
+
+/* Registers as seen by the debugger: the register file followed by
+ * the flags and the remainder. eip doubles as the program counter and
+ * counts instructions, so breakpoint addresses are instruction indices. */
+const RVM_GDB_NUM_REGS: usize = RvmRegisterMap.len() + 2;
+const RVM_GDB_REG_FLAGS: usize = RvmRegisterMap.len();
+const RVM_GDB_REG_REMAINDER: usize = RvmRegisterMap.len() + 1;
+
+pub trait RvmGdbStream: Read + Write {
+    fn rvm_set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl RvmGdbStream for TcpStream {
+    fn rvm_set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.set_nonblocking(nonblocking)
+    }
+}
+
+impl RvmGdbStream for UnixStream {
+    fn rvm_set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.set_nonblocking(nonblocking)
+    }
+}
+
+// gdb is told the registers are 32 bits wide, which they are not in a 64-bit program
+pub fn rvm_gdb_check(vm: &RvmCtx) -> io::Result<()> {
+    match vm.prog.mode {
+        RvmMode::Bits32 => Ok(()),
+        RvmMode::Bits64 => Err(io::Error::new(io::ErrorKind::Unsupported, "64-bit programs are not supported")),
+    }
+}
+
+// Wait for a single client on `addr`, either `host:port` or `unix:/path/to/socket`
+pub fn rvm_gdb_accept(addr: &str) -> io::Result<Box<dyn RvmGdbStream>> {
+    if let Some(path) = addr.strip_prefix("unix:") {
+        // Clear away a socket left by an earlier run, but never anything else
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is not a socket", path))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let listener = UnixListener::bind(path)?;
+        eprintln!("Listening on {}", addr);
+        let (stream, _) = listener.accept()?;
+        Ok(Box::new(stream))
+    } else {
+        let listener = TcpListener::bind(addr)?;
+        eprintln!("Listening on {}", listener.local_addr()?);
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        Ok(Box::new(stream))
+    }
+}
+
+fn rvm_gdb_target_xml() -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+        <target version=\"1.0\">\n<feature name=\"org.rusty-vm.core\">\n");
+    for (i, name) in RvmRegisterMap.iter().enumerate() {
+        let reg_type = match *name {
+            "eip" => "code_ptr",
+            "esp" | "ebp" => "data_ptr",
+            _ => "int32",
+        };
+        xml += &format!("<reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>\n", name, reg_type, i);
+    }
+    xml += &format!("<reg name=\"flags\" bitsize=\"32\" type=\"int32\" regnum=\"{}\"/>\n", RVM_GDB_REG_FLAGS);
+    xml += &format!("<reg name=\"remainder\" bitsize=\"32\" type=\"int32\" regnum=\"{}\"/>\n", RVM_GDB_REG_REMAINDER);
+    xml += "</feature>\n</target>\n";
+    xml
+}
+
+fn rvm_gdb_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn rvm_gdb_unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn rvm_gdb_parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+pub struct RvmGdbServer {
+    stream: Box<dyn RvmGdbStream>,
+    dbg: RvmDebugger,
+    /* Bytes read while polling for a ^C that belong to the next packet */
+    pending: VecDeque<u8>,
+}
+
+impl RvmGdbServer {
+    pub fn new(stream: Box<dyn RvmGdbStream>) -> Self {
+        RvmGdbServer {
+            stream,
+            dbg: RvmDebugger::new(),
+            pending: VecDeque::new()
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut byte = [0u8; 1];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    // Read the next packet, acknowledging it. Returns None once the client hangs up.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => break,
+                // Acks and stray interrupts while stopped are ignored
+                Some(_) => continue,
+            }
+        }
+        let mut data = Vec::new();
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(b) => data.push(b),
+            }
+        }
+        let mut checksum = [0u8; 2];
+        for digit in &mut checksum {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b) => *digit = b,
+            }
+        }
+        let expected = std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+        let actual = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if expected != Some(actual) {
+            self.stream.write_all(b"-")?;
+            return self.read_packet();
+        }
+        self.stream.write_all(b"+")?;
+        Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        self.stream.write_all(format!("${}#{:02x}", data, checksum).as_bytes())?;
+        self.stream.flush()
+    }
+
+    /* Check, without blocking, whether the client sent a ^C. Anything else
+     * it sent is kept for read_packet. */
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.rvm_set_nonblocking(true)?;
+        let mut byte = [0u8; 1];
+        let res = self.stream.read(&mut byte);
+        self.stream.rvm_set_nonblocking(false)?;
+        match res {
+            Ok(1) if byte[0] == 0x03 => Ok(true),
+            Ok(1) => {
+                self.pending.push_back(byte[0]);
+                Ok(false)
+            }
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn reg_read(vm: &RvmCtx, reg: usize) -> i32 {
+        match reg {
+            RVM_GDB_REG_FLAGS => vm.mem.flags as i32,
+            RVM_GDB_REG_REMAINDER => vm.mem.remainder as i32,
+            _ => vm.mem.rvm_reg_read(reg),
+        }
+    }
+
+    // eip has to stay an instruction index; the last one is the end of the program
+    fn reg_valid(vm: &RvmCtx, reg: usize, val: i32) -> bool {
+        reg != 0x8 || (0..vm.prog.code.len() as i64).contains(&(val as i64))
+    }
+
+    fn reg_write(&mut self, vm: &mut RvmCtx, reg: usize, val: i32) {
+        match reg {
+            RVM_GDB_REG_FLAGS => vm.mem.flags = val as u32,
+            RVM_GDB_REG_REMAINDER => vm.mem.remainder = val as i64,
+            0x8 => vm.rvm_vm_set_eip(val),
+            _ => vm.mem.rvm_reg_write(reg, val),
+        }
+        // State changed behind the history's back, so it can no longer be replayed
+        self.dbg.history.rvm_history_clear();
+        self.dbg.rvm_dbg_arm(vm);
+    }
+
+    fn stop_reply(&self, vm: &RvmCtx, stop: &RvmDbgStop) -> String {
+        match stop {
+            RvmDbgStop::Halted => format!("W{:02x}", vm.exit_code as u8),
+            RvmDbgStop::HistoryStart => "T05replaylog:begin;".to_string(),
+            RvmDbgStop::OutOfGas => "S05".to_string(),
+            // Faults are reported as the signal a native program would get
+            RvmDbgStop::Trap(RvmRunResult::Fault(fault)) => match fault {
+                RvmFault::DivideByZero | RvmFault::DivideOverflow => "S08".to_string(),
+                RvmFault::BadJump(_) | RvmFault::BadAddress(_) => "S0b".to_string(),
+                RvmFault::BadInterrupt(_) => "S04".to_string(),
+            },
+            RvmDbgStop::Hit { id, .. } => match &self.dbg.points[id - 1] {
+                Some(RvmStopPoint::WatchMem { addr, kind, .. }) => {
+                    let kind = match kind {
+                        RvmWatchKind::Write => "watch",
+                        RvmWatchKind::Read => "rwatch",
+                        RvmWatchKind::Access => "awatch",
+                    };
+                    format!("T05{}:{:x};", kind, addr)
+                }
+                _ => "T05swbreak:;".to_string(),
+            },
+            _ if vm.rvm_vm_halted() => format!("W{:02x}", vm.exit_code as u8),
+            _ => "S05".to_string(),
+        }
+    }
+
+    fn find_point(&self, kind: &str, addr: usize) -> Option<usize> {
+        self.dbg.points.iter().position(|p| match (kind, p) {
+            ("0" | "1", Some(RvmStopPoint::Break(idx))) => *idx as usize == addr,
+            ("2", Some(RvmStopPoint::WatchMem { addr: a, kind: RvmWatchKind::Write, .. }))
+            | ("3", Some(RvmStopPoint::WatchMem { addr: a, kind: RvmWatchKind::Read, .. }))
+            | ("4", Some(RvmStopPoint::WatchMem { addr: a, kind: RvmWatchKind::Access, .. })) => *a == addr,
+            _ => false,
+        }).map(|i| i + 1)
+    }
+
+    fn handle_point(&mut self, vm: &RvmCtx, insert: bool, args: &str) -> String {
+        let mut parts = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (parts.next(), parts.next(), parts.next()) else {
+            return "E01".to_string();
+        };
+        let (Some(addr), Some(len)) = (rvm_gdb_parse_hex(addr), rvm_gdb_parse_hex(len)) else {
+            return "E01".to_string();
+        };
+        if !insert {
+            return match self.find_point(kind, addr) {
+                Some(id) => {
+                    self.dbg.rvm_dbg_delete(id);
+                    "OK".to_string()
+                }
+                None => "E01".to_string(),
+            };
+        }
+        // Breakpoints go on instructions of the program, watchpoints on non-empty ranges of memory
+        let in_code = addr < vm.prog.code.len();
+        let in_mem = len > 0 && addr.checked_add(len).is_some_and(|end| end <= vm.mem.mem_space.len());
+        let point = match kind {
+            "0" | "1" if in_code => RvmStopPoint::Break(addr as i32),
+            "2" if in_mem => RvmStopPoint::WatchMem { addr, len, kind: RvmWatchKind::Write },
+            "3" if in_mem => RvmStopPoint::WatchMem { addr, len, kind: RvmWatchKind::Read },
+            "4" if in_mem => RvmStopPoint::WatchMem { addr, len, kind: RvmWatchKind::Access },
+            "0" | "1" | "2" | "3" | "4" => return "E01".to_string(),
+            _ => return String::new(),
+        };
+        if self.find_point(kind, addr).is_none() {
+            self.dbg.rvm_dbg_add(point);
+            self.dbg.rvm_dbg_arm(vm);
+        }
+        "OK".to_string()
+    }
+
+    fn handle_query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!("PacketSize={:x};qXfer:features:read+;swbreak+;ReverseStep+;ReverseContinue+",
+                RVM_GDB_PACKET_SIZE);
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = rvm_gdb_target_xml();
+            let mut parts = args.split(',');
+            let (Some(off), Some(len)) = (
+                parts.next().and_then(rvm_gdb_parse_hex),
+                parts.next().and_then(rvm_gdb_parse_hex),
+            ) else {
+                return "E01".to_string();
+            };
+            let Some(end) = off.checked_add(len) else {
+                return "E01".to_string();
+            };
+            let off = off.min(xml.len());
+            let end = end.min(xml.len());
+            let prefix = if end == xml.len() { "l" } else { "m" };
+            return format!("{}{}", prefix, &xml[off..end]);
+        }
+        match packet {
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn handle_continue(&mut self, vm: &mut RvmCtx) -> io::Result<String> {
+        loop {
+            let stop = self.dbg.rvm_dbg_run(vm, RVM_GDB_POLL_INTERVAL);
+            if !matches!(stop, RvmDbgStop::Step) || vm.rvm_vm_halted() {
+                return Ok(self.stop_reply(vm, &stop));
+            }
+            if self.interrupted()? {
+                return Ok("S02".to_string());
+            }
+        }
+    }
+
+    // Serve requests until the client detaches, kills the program or disconnects
+    pub fn rvm_gdb_serve(&mut self, vm: &mut RvmCtx) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match packet.chars().next() {
+                Some('?') => "S05".to_string(),
+                Some('g') => {
+                    let mut regs = Vec::with_capacity(RVM_GDB_NUM_REGS * 4);
+                    for reg in 0..RVM_GDB_NUM_REGS {
+                        regs.extend_from_slice(&Self::reg_read(vm, reg).to_le_bytes());
+                    }
+                    rvm_gdb_hex(&regs)
+                }
+                Some('G') => {
+                    let vals: Option<Vec<i32>> = rvm_gdb_unhex(&packet[1..])
+                        .filter(|bytes| bytes.len() == RVM_GDB_NUM_REGS * 4)
+                        .map(|bytes| bytes.chunks(4).map(|val| i32::from_le_bytes(val.try_into().unwrap())).collect());
+                    match vals {
+                        Some(vals) if vals.iter().enumerate().all(|(reg, val)| Self::reg_valid(vm, reg, *val)) => {
+                            for (reg, val) in vals.into_iter().enumerate() {
+                                self.reg_write(vm, reg, val);
+                            }
+                            "OK".to_string()
+                        }
+                        _ => "E01".to_string(),
+                    }
+                }
+                Some('p') => match rvm_gdb_parse_hex(&packet[1..]) {
+                    Some(reg) if reg < RVM_GDB_NUM_REGS => rvm_gdb_hex(&Self::reg_read(vm, reg).to_le_bytes()),
+                    _ => "E01".to_string(),
+                },
+                Some('P') => {
+                    let parsed = packet[1..].split_once('=').and_then(|(reg, val)| {
+                        Some((rvm_gdb_parse_hex(reg)?, rvm_gdb_unhex(val)?))
+                    });
+                    match parsed {
+                        Some((reg, val)) if reg < RVM_GDB_NUM_REGS && val.len() == 4
+                            && Self::reg_valid(vm, reg, i32::from_le_bytes(val[..].try_into().unwrap())) => {
+                            self.reg_write(vm, reg, i32::from_le_bytes(val.try_into().unwrap()));
+                            "OK".to_string()
+                        }
+                        _ => "E01".to_string(),
+                    }
+                }
+                Some('m') => {
+                    let range = packet[1..].split_once(',').and_then(|(addr, len)| {
+                        Some((rvm_gdb_parse_hex(addr)?, rvm_gdb_parse_hex(len)?))
+                    });
+                    match range {
+                        Some((addr, len)) if addr.checked_add(len).is_some_and(|end| end <= vm.mem.mem_space.len()) => {
+                            rvm_gdb_hex(&vm.mem.mem_space[addr..addr + len])
+                        }
+                        _ => "E01".to_string(),
+                    }
+                }
+                Some('M') => {
+                    let parsed = packet[1..].split_once(':').and_then(|(range, data)| {
+                        let (addr, len) = range.split_once(',')?;
+                        Some((rvm_gdb_parse_hex(addr)?, rvm_gdb_parse_hex(len)?, rvm_gdb_unhex(data)?))
+                    });
+                    match parsed {
+                        Some((addr, len, data)) if data.len() == len
+                            && addr.checked_add(len).is_some_and(|end| end <= vm.mem.mem_space.len()) => {
+                            vm.mem.mem_space[addr..addr + len].copy_from_slice(&data);
+                            self.dbg.history.rvm_history_clear();
+                            "OK".to_string()
+                        }
+                        _ => "E01".to_string(),
+                    }
+                }
+                Some('Z') => self.handle_point(vm, true, &packet[1..]),
+                Some('z') => self.handle_point(vm, false, &packet[1..]),
+                Some('s') => {
+                    let stop = self.dbg.rvm_dbg_step(vm);
+                    self.stop_reply(vm, &stop)
+                }
+                Some('c') => self.handle_continue(vm)?,
+                Some('b') if packet == "bs" => {
+                    let stop = self.dbg.rvm_dbg_reverse_step(vm);
+                    self.stop_reply(vm, &stop)
+                }
+                Some('b') if packet == "bc" => {
+                    let stop = self.dbg.rvm_dbg_reverse_continue(vm, None);
+                    self.stop_reply(vm, &stop)
+                }
+                Some('H') => "OK".to_string(),
+                Some('T') => "OK".to_string(),
+                Some('q') => self.handle_query(&packet),
+                Some('D') => {
+                    // Let the program run to completion once the debugger is gone
+                    self.write_packet("OK")?;
+                    self.dbg.points.clear();
+                    self.dbg.rvm_dbg_continue(vm);
+                    return Ok(());
+                }
+                Some('k') => return Ok(()),
+                _ => String::new(),
+            };
+            self.write_packet(&reply)?;
+        }
+        Ok(())
+    }
+}
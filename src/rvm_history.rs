@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+
+use crate::rvm::RvmCtx;
+use crate::rvm_memory::{rvm_ranges_overlap, RvmAccess};
+
+pub const RVM_HISTORY_DEFAULT_CAPACITY: usize = 100_000;
+
+/* Everything needed to take back one executed instruction: where it
+ * was, the previous contents of every location it wrote, the input
+ * value it read with `int 2`, the exit code before it and the gas it
+ * was charged. */
+pub struct RvmUndoRecord {
+    pub eip: i32,
+    pub accesses: Vec<RvmAccess>,
+    pub input: Option<i32>,
+    pub exit_code: i32,
+    pub gas: Option<u64>,
+}
+
+impl RvmUndoRecord {
+    pub fn rvm_writes_reg(&self, reg: usize) -> bool {
+        self.accesses.iter().any(|a| matches!(*a, RvmAccess::RegWrite { reg: r, .. } if r == reg))
+    }
+
+    pub fn rvm_writes_mem(&self, addr: usize, len: usize) -> bool {
+        self.accesses.iter().any(|a| match *a {
+            RvmAccess::MemWrite { addr: w_addr, len: w_len, .. } => rvm_ranges_overlap(w_addr, w_len, addr, len),
+            _ => false,
+        })
+    }
+}
+
+/* Bounded ring buffer of undo records; the oldest records are dropped
+ * once the capacity is reached. */
+pub struct RvmHistory {
+    records: VecDeque<RvmUndoRecord>,
+    capacity: usize,
+}
+
+impl RvmHistory {
+    pub fn new(capacity: usize) -> Self {
+        RvmHistory {
+            records: VecDeque::with_capacity(capacity.min(4096)),
+            capacity
+        }
+    }
+
+    pub fn rvm_history_len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn rvm_history_push(&mut self, record: RvmUndoRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn rvm_history_clear(&mut self) {
+        self.records.clear();
+    }
+
+    // Undo the most recent instruction, returning its record
+    pub fn rvm_history_undo(&mut self, vm: &mut RvmCtx) -> Option<RvmUndoRecord> {
+        let record = self.records.pop_back()?;
+        for access in record.accesses.iter().rev() {
+            vm.mem.rvm_undo(access);
+        }
+        if let Some(val) = record.input {
+            vm.input.push_front(val);
+        }
+        vm.exit_code = record.exit_code;
+        if let (Some(gas), Some(cost)) = (vm.gas.as_mut(), record.gas) {
+            gas.remaining += cost;
+            gas.used -= cost;
+            gas.instructions -= 1;
+        }
+        vm.rvm_vm_set_eip(record.eip);
+        Some(record)
+    }
+}
@@ -1,4 +1,5 @@
 const HTAB_SIZE : usize = 4096;
+#[allow(dead_code)]
 const KEY_LENGTH : usize = 64;
 const HTAB_LOAD_FACTOR : f64 = 0.7;
 
@@ -14,7 +15,7 @@ impl RvmHtabNode {
     fn new(key: String, value: i32, value_str: String) -> Self {
         RvmHtabNode {
             key : key.clone(),
-            value : value,
+            value,
             value_str,
             next: None
         }
@@ -39,7 +40,7 @@ impl RvmHtabCtx {
     fn htab_hash(key: &str, size: usize) -> usize {
         let mut hash: usize = 0;
         for c in key.chars() {
-            hash = hash.wrapping_add((hash << c as usize));
+            hash = hash.wrapping_add(hash.wrapping_shl(c as u32));
             hash = hash.wrapping_sub(c as usize);
             
         }
@@ -56,15 +57,13 @@ impl RvmHtabCtx {
         /* Traverse the original hash table, rehashing
         * every entry into the new table        */
 
-        for i in 0..old_size {
-            if let Some(mut node) = old_nodes[i].take() {
+        for slot in old_nodes.iter_mut().take(old_size) {
+            if let Some(mut node) = slot.take() {
                 loop {
                     self.rvm_htab_add(&node.key,node.value, &node.value_str);
-                    let next_node = node.next.take();
-                    if next_node.is_none() {
-                        break;
-                    } else {
-                        node = next_node.unwrap();
+                    match node.next.take() {
+                        Some(next_node) => node = next_node,
+                        None => break,
                     }
                 }
             }
@@ -115,4 +114,16 @@ impl RvmHtabCtx {
         }
         None
     }
-}
\ No newline at end of file
+
+    pub fn rvm_htab_entries(&self) -> Vec<(String, i32)> {
+        let mut entries = Vec::with_capacity(self.num_nodes as usize);
+        for slot in &self.nodes {
+            let mut current_node = slot.as_ref();
+            while let Some(node) = current_node {
+                entries.push((node.key.clone(), node.value));
+                current_node = node.next.as_ref();
+            }
+        }
+        entries
+    }
+}
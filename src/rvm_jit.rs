@@ -0,0 +1,636 @@
+/* x86-64 JIT for Linux, built with the `jit` feature.
+ *
+ * The program is split into basic blocks at labels, jump targets and
+ * after every jump, and each block is translated to native code up
+ * front. VM registers, flags and the remainder live in an RvmJitState
+ * that the generated code addresses through rdi; rsi holds the base of
+ * the VM's memory space. Memory operands are bounds checked when the
+ * block is compiled, stack accesses when they happen.
+ *
+ * A block ends by jumping straight into the block at its successor, or
+ * by returning the next instruction index to rvm_jit_run. Instructions
+ * with host side effects (`int`, `prn`) are never compiled and run in
+ * the interpreter, and neither are the shifts and rotations, whose
+ * carry and overflow flags differ from the host's, nor the string and
+ * float instructions, nor instructions with sub-register or [reg+disp]
+ * operands. An instruction that would fault
+ * (division by zero, a stack access outside memory) sets `fallback` and
+ * returns its own index, so the interpreter runs it and reports the
+ * fault as usual; so does a jump out of the program.
+ *
+ * Generated code only uses rax, rcx, rdx, rsi and rdi and never touches
+ * the host stack, so a block returns directly to the caller.
+ */
+use std::io;
+use std::mem::offset_of;
+use std::ptr;
+
+use crate::rvm::{RvmCtx, RvmRegisterMap, RvmRunResult};
+use crate::rvm_memory::RvmMem;
+use crate::rvm_prog::{RvmArg, RvmInstr, RvmJumpCond, RvmMode};
+
+unsafe extern "C" {
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+}
+
+const PROT_READ: i32 = 0x1;
+const PROT_WRITE: i32 = 0x2;
+const PROT_EXEC: i32 = 0x4;
+const MAP_PRIVATE: i32 = 0x2;
+const MAP_ANONYMOUS: i32 = 0x20;
+const PAGE_SIZE: usize = 4096;
+
+/* Host registers, by their x86 encoding */
+const EAX: u8 = 0;
+const ECX: u8 = 1;
+const EDX: u8 = 2;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+
+const ESP: usize = 0x6;
+const EBP: usize = 0x7;
+// The VM's ecx, which loop counts down
+const COUNTER: usize = 0x2;
+
+#[repr(C)]
+struct RvmJitState {
+    regs: [i32; RvmRegisterMap.len()],
+    flags: u32,
+    remainder: i32,
+    /* Set by generated code that stopped at an instruction it cannot finish */
+    fallback: u32,
+    mem: *mut u8,
+}
+
+impl RvmJitState {
+    fn new(mem: &RvmMem) -> Self {
+        let mut state = RvmJitState {
+            regs: [0; RvmRegisterMap.len()],
+            flags: 0,
+            remainder: 0,
+            fallback: 0,
+            mem: ptr::null_mut()
+        };
+        state.rvm_jit_sync_in(mem);
+        state
+    }
+
+    fn rvm_jit_sync_in(&mut self, mem: &RvmMem) {
+        for (reg, val) in self.regs.iter_mut().enumerate() {
+            *val = mem.rvm_reg_read(reg);
+        }
+        self.flags = mem.flags;
+        self.remainder = mem.remainder as i32;
+    }
+
+    fn rvm_jit_sync_out(&self, mem: &mut RvmMem) {
+        for (reg, val) in self.regs.iter().enumerate() {
+            mem.rvm_reg_write(reg, *val);
+        }
+        mem.flags = self.flags;
+        mem.remainder = self.remainder as i64;
+    }
+}
+
+fn reg_offset(reg: usize) -> i32 {
+    (offset_of!(RvmJitState, regs) + reg * 4) as i32
+}
+
+/* Where a rel32 placeholder should end up pointing */
+enum RvmJitFixup {
+    Pc(i32),
+    Fault(i32),
+}
+
+struct RvmJitAsm {
+    buf: Vec<u8>,
+    fixups: Vec<(usize, RvmJitFixup)>,
+    mem_len: usize,
+    /* Number of instructions in the program */
+    instrs: usize,
+}
+
+impl RvmJitAsm {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn emit_i32(&mut self, val: i32) {
+        self.buf.extend_from_slice(&val.to_le_bytes());
+    }
+
+    // `opcode modrm disp32` with base register `base`
+    fn emit_disp(&mut self, opcode: u8, reg: u8, base: u8, disp: i32) {
+        self.emit(&[opcode, 0x80 | (reg << 3) | base]);
+        self.emit_i32(disp);
+    }
+
+    fn emit_rel32(&mut self, fixup: RvmJitFixup) {
+        self.fixups.push((self.buf.len(), fixup));
+        self.emit_i32(0);
+    }
+
+    fn state_load(&mut self, reg: u8, offset: usize) {
+        self.emit_disp(0x8B, reg, RDI, offset as i32);
+    }
+
+    fn state_store(&mut self, reg: u8, offset: usize) {
+        self.emit_disp(0x89, reg, RDI, offset as i32);
+    }
+
+    fn load(&mut self, reg: u8, arg: &RvmArg) {
+        match *arg {
+            RvmArg::Reg(r) => self.emit_disp(0x8B, reg, RDI, reg_offset(r)),
+            RvmArg::Mem(addr) => self.emit_disp(0x8B, reg, RSI, addr as i32),
+            RvmArg::Val(val) => {
+                // 32-bit programs only hold immediates that fit
+                self.emit(&[0xB8 + reg]);
+                self.emit_i32(val as i32);
+            }
+            RvmArg::Sub(..) | RvmArg::Ptr(..) => unreachable!("sub-registers and [reg+disp] are interpreted"),
+            RvmArg::Freg(_) | RvmArg::Fval(_) => unreachable!("float instructions are interpreted"),
+        }
+    }
+
+    fn store(&mut self, reg: u8, arg: &RvmArg) {
+        match *arg {
+            RvmArg::Reg(r) => self.emit_disp(0x89, reg, RDI, reg_offset(r)),
+            RvmArg::Mem(addr) => self.emit_disp(0x89, reg, RSI, addr as i32),
+            RvmArg::Val(_) => {}
+            RvmArg::Sub(..) | RvmArg::Ptr(..) => unreachable!("sub-registers and [reg+disp] are interpreted"),
+            RvmArg::Freg(_) | RvmArg::Fval(_) => unreachable!("float instructions are interpreted"),
+        }
+    }
+
+    // Leave for instruction `pc`, through its block if it has one
+    fn exit_to(&mut self, pc: i32) {
+        self.emit(&[0xE9]);
+        self.emit_rel32(RvmJitFixup::Pc(pc));
+    }
+
+    // Load the stack pointer into ecx, bailing out to `idx` unless [ecx + adjust] is in memory
+    fn stack_check(&mut self, idx: i32, adjust: i8) {
+        self.emit_disp(0x8B, ECX, RDI, reg_offset(ESP));
+        if adjust != 0 {
+            self.emit(&[0x83, 0xC1, adjust as u8]);                 // add ecx, adjust
+        }
+        self.emit(&[0x81, 0xF9]);                                   // cmp ecx, mem_len - 4
+        self.emit_i32((self.mem_len - 4) as i32);
+        self.emit(&[0x0F, 0x87]);                                   // ja fault
+        self.emit_rel32(RvmJitFixup::Fault(idx));
+    }
+
+    // Bail out to `idx` unless eax is an instruction index
+    fn jump_check(&mut self, idx: i32) {
+        self.emit(&[0x3D]);                                         // cmp eax, instrs
+        self.emit_i32(self.instrs as i32);
+        self.emit(&[0x0F, 0x83]);                                   // jae fault
+        self.emit_rel32(RvmJitFixup::Fault(idx));
+    }
+
+    fn push_eax(&mut self, idx: i32) {
+        self.stack_check(idx, -4);
+        self.emit(&[0x89, 0x04, 0x0E]);                             // mov [rsi + rcx], eax
+        self.emit_disp(0x89, ECX, RDI, reg_offset(ESP));
+    }
+
+    fn pop_eax(&mut self, idx: i32) {
+        self.stack_check(idx, 0);
+        self.emit(&[0x8B, 0x04, 0x0E]);                             // mov eax, [rsi + rcx]
+        self.emit(&[0x83, 0xC1, 0x04]);                             // add ecx, 4
+        self.emit_disp(0x89, ECX, RDI, reg_offset(ESP));
+    }
+
+    // eax = dest op src for the two-operand ALU instructions, setting carry and overflow
+    fn alu(&mut self, dest: &RvmArg, src: &RvmArg, op: &[u8]) {
+        self.load(EAX, dest);
+        self.load(ECX, src);
+        self.emit(op);
+        self.merge_flags(true);
+        self.store(EAX, dest);
+    }
+
+    // eax = dest op src for and, or and xor
+    fn logic(&mut self, dest: &RvmArg, src: &RvmArg, op: &[u8]) {
+        self.load(EAX, dest);
+        self.load(ECX, src);
+        self.emit(op);
+        self.clear_flags();
+        self.store(EAX, dest);
+    }
+
+    /* Copy the host's overflow flag, and its carry flag if `carry`, into
+     * the VM flags. Must directly follow the instruction setting them. */
+    fn merge_flags(&mut self, carry: bool) {
+        let flags = offset_of!(RvmJitState, flags) as i32;
+        self.emit(&[0x0F, 0x90, 0xC1]);                             // seto cl
+        if carry {
+            self.emit(&[0x0F, 0x92, 0xC2]);                         // setc dl
+        }
+        self.emit(&[0x0F, 0xB6, 0xC9, 0xC1, 0xE1, 0x03]);           // movzx ecx, cl; shl ecx, 3
+        if carry {
+            self.emit(&[0x0F, 0xB6, 0xD2, 0xC1, 0xE2, 0x02]);       // movzx edx, dl; shl edx, 2
+            self.emit(&[0x09, 0xD1]);                               // or ecx, edx
+        }
+        self.emit_disp(0x8B, EDX, RDI, flags);
+        self.emit(&[0x83, 0xE2, if carry { 0x3 } else { 0x7 }]);    // and edx, kept flags
+        self.emit(&[0x09, 0xCA]);                                   // or edx, ecx
+        self.emit_disp(0x89, EDX, RDI, flags);
+    }
+
+    // Logic instructions clear carry and overflow
+    fn clear_flags(&mut self) {
+        self.emit_disp(0x83, 4, RDI, offset_of!(RvmJitState, flags) as i32);
+        self.emit(&[0x03]);                                         // and dword [rdi + flags], 3
+    }
+
+    // Set the flags as cmp does from `op`, which compares eax with something
+    fn cmp_flags(&mut self, op: &[u8]) {
+        self.emit(&[0x31, 0xD2]);                                   // xor edx, edx
+        self.emit(op);
+        self.emit(&[0x0F, 0x94, 0xC2]);                             // sete dl
+        self.emit(&[0x0F, 0x9F, 0xC0]);                             // setg al
+        self.emit(&[0x0F, 0xB6, 0xC0]);                             // movzx eax, al
+        self.emit(&[0x01, 0xC0, 0x09, 0xD0]);                       // add eax, eax; or eax, edx
+        self.state_store(EAX, offset_of!(RvmJitState, flags));
+    }
+
+    // Unsigned eax / ecx, leaving quotient in eax and remainder in edx
+    fn divide_unsigned(&mut self, a: &RvmArg, b: &RvmArg, idx: i32) {
+        self.load(EAX, a);
+        self.load(ECX, b);
+        self.emit(&[0x85, 0xC9]);                                   // test ecx, ecx
+        self.emit(&[0x0F, 0x84]);                                   // jz fault
+        self.emit_rel32(RvmJitFixup::Fault(idx));
+        self.emit(&[0x31, 0xD2, 0xF7, 0xF1]);                       // xor edx, edx; div ecx
+    }
+
+    // Signed eax / ecx, leaving quotient in eax and remainder in edx
+    fn divide(&mut self, a: &RvmArg, b: &RvmArg, idx: i32) {
+        self.load(EAX, a);
+        self.load(ECX, b);
+        self.emit(&[0x85, 0xC9]);                                   // test ecx, ecx
+        self.emit(&[0x0F, 0x84]);                                   // jz fault
+        self.emit_rel32(RvmJitFixup::Fault(idx));
+        self.emit(&[0x83, 0xF9, 0xFF]);                             // cmp ecx, -1
+        self.emit(&[0x75, 0x0B]);                                   // jne +11
+        self.emit(&[0x3D]);                                         // cmp eax, i32::MIN
+        self.emit_i32(i32::MIN);
+        self.emit(&[0x0F, 0x84]);                                   // je fault
+        self.emit_rel32(RvmJitFixup::Fault(idx));
+        self.emit(&[0x99, 0xF7, 0xF9]);                             // cdq; idiv ecx
+    }
+
+    fn rvm_jit_instr(&mut self, instr: &RvmInstr, idx: i32) {
+        let flags = offset_of!(RvmJitState, flags);
+        let remainder = offset_of!(RvmJitState, remainder);
+        match instr {
+            RvmInstr::Nop => {}
+            RvmInstr::Mov(dest, src) => {
+                self.load(EAX, src);
+                self.store(EAX, dest);
+            }
+            RvmInstr::Push(src) => {
+                self.load(EAX, src);
+                self.push_eax(idx);
+            }
+            RvmInstr::Pop(dest) => {
+                self.pop_eax(idx);
+                self.store(EAX, dest);
+            }
+            RvmInstr::Pushf => {
+                self.state_load(EAX, flags);
+                self.push_eax(idx);
+            }
+            RvmInstr::Popf => {
+                self.pop_eax(idx);
+                self.state_store(EAX, flags);
+            }
+            RvmInstr::Inc(dest) => {
+                self.load(EAX, dest);
+                self.emit(&[0xFF, 0xC0]);                           // inc eax
+                self.merge_flags(false);
+                self.store(EAX, dest);
+            }
+            RvmInstr::Dec(dest) => {
+                self.load(EAX, dest);
+                self.emit(&[0xFF, 0xC8]);                           // dec eax
+                self.merge_flags(false);
+                self.store(EAX, dest);
+            }
+            RvmInstr::Add(dest, src) => self.alu(dest, src, &[0x01, 0xC8]),
+            RvmInstr::Sub(dest, src) => self.alu(dest, src, &[0x29, 0xC8]),
+            RvmInstr::Mul(dest, src) => self.alu(dest, src, &[0x0F, 0xAF, 0xC1]),
+            RvmInstr::Xor(dest, src) => self.logic(dest, src, &[0x31, 0xC8]),
+            RvmInstr::Or(dest, src) => self.logic(dest, src, &[0x09, 0xC8]),
+            RvmInstr::And(dest, src) => self.logic(dest, src, &[0x21, 0xC8]),
+            RvmInstr::Div(dest, src) => {
+                self.divide(dest, src, idx);
+                self.store(EAX, dest);
+            }
+            RvmInstr::Mod(a, b) => {
+                self.divide(a, b, idx);
+                self.state_store(EDX, remainder);
+            }
+            RvmInstr::Rem(dest) => {
+                self.state_load(EAX, remainder);
+                self.store(EAX, dest);
+            }
+            RvmInstr::Not(dest) => {
+                self.load(EAX, dest);
+                self.emit(&[0xF7, 0xD0]);                           // not eax
+                self.store(EAX, dest);
+            }
+            RvmInstr::Cmp(a, b) => {
+                self.load(EAX, a);
+                self.load(ECX, b);
+                self.cmp_flags(&[0x39, 0xC8]);                      // cmp eax, ecx
+            }
+            RvmInstr::Test(a, b) => {
+                self.load(EAX, a);
+                self.load(ECX, b);
+                self.emit(&[0x21, 0xC8]);                           // and eax, ecx
+                self.cmp_flags(&[0x85, 0xC0]);                      // test eax, eax
+            }
+            RvmInstr::Neg(dest) => {
+                self.load(EAX, dest);
+                self.emit(&[0xF7, 0xD8]);                           // neg eax
+                self.merge_flags(true);
+                self.store(EAX, dest);
+            }
+            RvmInstr::Umul(dest, src) => {
+                self.load(EAX, dest);
+                self.load(ECX, src);
+                self.emit(&[0xF7, 0xE1]);                           // mul ecx
+                self.merge_flags(true);
+                self.store(EAX, dest);
+            }
+            RvmInstr::Udiv(dest, src) => {
+                self.divide_unsigned(dest, src, idx);
+                self.store(EAX, dest);
+            }
+            RvmInstr::Umod(a, b) => {
+                self.divide_unsigned(a, b, idx);
+                self.state_store(EDX, remainder);
+            }
+            RvmInstr::Xchg(a, b) => {
+                self.load(EAX, a);
+                self.load(ECX, b);
+                self.store(ECX, a);
+                self.store(EAX, b);
+            }
+            RvmInstr::Lea(dest, RvmArg::Mem(addr)) => {
+                self.emit(&[0xB8]);                                 // mov eax, addr
+                self.emit_i32(*addr as i32);
+                self.store(EAX, dest);
+            }
+            RvmInstr::Jmp(RvmArg::Val(target)) => self.exit_to(*target as i32),
+            RvmInstr::Jmp(target) => {
+                self.load(EAX, target);
+                self.jump_check(idx);
+                self.emit(&[0xC3]);                                 // ret
+            }
+            RvmInstr::Call(RvmArg::Val(target)) => {
+                // Like the interpreter, push the index of the next instruction
+                self.emit(&[0xB8]);
+                self.emit_i32(idx + 1);
+                self.push_eax(idx);
+                self.exit_to(*target as i32);
+            }
+            RvmInstr::Ret | RvmInstr::RetN(RvmArg::Val(_)) => {
+                let drop = match instr {
+                    RvmInstr::RetN(RvmArg::Val(n)) => *n as i32,
+                    _ => 0,
+                };
+                self.stack_check(idx, 0);
+                self.emit(&[0x8B, 0x04, 0x0E]);                     // mov eax, [rsi + rcx]
+                self.jump_check(idx);
+                self.emit(&[0x81, 0xC1]);                           // add ecx, 4 + drop
+                self.emit_i32(drop.wrapping_add(4));
+                self.emit_disp(0x89, ECX, RDI, reg_offset(ESP));
+                self.emit(&[0xC3]);
+            }
+            RvmInstr::Enter(RvmArg::Val(n)) => {
+                self.emit_disp(0x8B, EAX, RDI, reg_offset(EBP));
+                self.push_eax(idx);
+                self.emit_disp(0x89, ECX, RDI, reg_offset(EBP));    // ebp = esp
+                self.emit(&[0x81, 0xE9]);                           // sub ecx, n
+                self.emit_i32(*n as i32);
+                self.emit_disp(0x89, ECX, RDI, reg_offset(ESP));
+            }
+            RvmInstr::Leave => {
+                // Setting esp first is harmless if the pop bails out
+                self.emit_disp(0x8B, EAX, RDI, reg_offset(EBP));
+                self.emit_disp(0x89, EAX, RDI, reg_offset(ESP));
+                self.pop_eax(idx);
+                self.emit_disp(0x89, EAX, RDI, reg_offset(EBP));
+            }
+            RvmInstr::Loop(RvmArg::Val(target)) | RvmInstr::Loopz(RvmArg::Val(target))
+            | RvmInstr::Loopnz(RvmArg::Val(target)) => {
+                self.emit_disp(0x8B, EAX, RDI, reg_offset(COUNTER));
+                self.emit(&[0xFF, 0xC8]);                           // dec eax
+                self.emit_disp(0x89, EAX, RDI, reg_offset(COUNTER));
+                self.emit(&[0x0F, 0x84]);                           // jz next
+                self.emit_rel32(RvmJitFixup::Pc(idx + 1));
+                if !matches!(instr, RvmInstr::Loop(_)) {
+                    self.state_load(EAX, flags);
+                    self.emit(&[0xA9]);                             // test eax, equal
+                    self.emit_i32(0x1);
+                    self.emit(&[0x0F, if matches!(instr, RvmInstr::Loopz(_)) { 0x84 } else { 0x85 }]);
+                    self.emit_rel32(RvmJitFixup::Pc(idx + 1));
+                }
+                self.exit_to(*target as i32);
+            }
+            jump => {
+                let (cond, target) = RvmJumpCond::rvm_cond_of(jump).unwrap();
+                let (mask, taken_if_set) = match cond {
+                    RvmJumpCond::E => (0x1, true),
+                    RvmJumpCond::Ne => (0x1, false),
+                    RvmJumpCond::G => (0x2, true),
+                    RvmJumpCond::Ge => (0x3, true),
+                    RvmJumpCond::L => (0x3, false),
+                    RvmJumpCond::Le => (0x2, false),
+                };
+                let RvmArg::Val(target) = target else { unreachable!() };
+                self.state_load(EAX, flags);
+                self.emit(&[0xA9]);                                 // test eax, mask
+                self.emit_i32(mask);
+                self.emit(&[0x0F, if taken_if_set { 0x85 } else { 0x84 }]);
+                self.emit_rel32(RvmJitFixup::Pc(target as i32));
+                self.exit_to(idx + 1);
+            }
+        }
+    }
+}
+
+// Whether `instr` can be compiled, given a memory space of `mem_len` bytes and `instrs` instructions
+fn rvm_jit_supported(instr: &RvmInstr, mem_len: usize, instrs: usize) -> bool {
+    let in_bounds = instr.rvm_args().iter().all(|arg| match *arg {
+        RvmArg::Mem(addr) => addr + 4 <= mem_len && addr <= i32::MAX as usize,
+        RvmArg::Sub(..) | RvmArg::Ptr(..) => false,
+        _ => true,
+    });
+    // The interpreter reports jumps out of the program
+    let in_program = match instr.rvm_jump_target() {
+        Some(RvmArg::Val(target)) => (target as usize) < instrs,
+        _ => true,
+    };
+    let compiled = match instr {
+        RvmInstr::Int(_) | RvmInstr::Prn(_) | RvmInstr::Movsx(..) | RvmInstr::Halt => false,
+        RvmInstr::Shl(..) | RvmInstr::Shr(..) | RvmInstr::Sar(..) | RvmInstr::Rol(..) | RvmInstr::Ror(..) => false,
+        RvmInstr::CmpJcc(..) | RvmInstr::IncCmpJcc(..) | RvmInstr::PushPop(..) => false,
+        RvmInstr::Movs(..) | RvmInstr::Stos(..) | RvmInstr::Cmps(..) | RvmInstr::Scas(..) => false,
+        RvmInstr::Fld(..) | RvmInstr::Fst(..) | RvmInstr::Fadd(..) | RvmInstr::Fsub(..) | RvmInstr::Fmul(..)
+        | RvmInstr::Fdiv(..) | RvmInstr::Fsqrt(_) | RvmInstr::Fcmp(..) | RvmInstr::Itof(..)
+        | RvmInstr::Ftoi(..) | RvmInstr::Fprn(_) => false,
+        RvmInstr::Call(target) | RvmInstr::RetN(target) | RvmInstr::Enter(target) => matches!(target, RvmArg::Val(_)),
+        _ if instr.rvm_is_cond_jump() => matches!(instr.rvm_jump_target(), Some(RvmArg::Val(_))),
+        _ => true,
+    };
+    compiled && in_bounds && in_program
+}
+
+type RvmJitEntry = unsafe extern "sysv64" fn(*mut RvmJitState, *const u8) -> i32;
+
+pub struct RvmJit {
+    code: *mut u8,
+    code_len: usize,
+    /* Offset of the compiled block starting at each instruction, if any */
+    blocks: Vec<Option<usize>>,
+}
+
+impl RvmJit {
+    pub fn new(vm: &RvmCtx) -> io::Result<Self> {
+        // The generated code works on 32-bit registers only
+        if vm.prog.mode == RvmMode::Bits64 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "64-bit programs are not supported"));
+        }
+        let code = &vm.prog.code;
+        let mem_len = vm.mem.mem_space.len().min(i32::MAX as usize);
+        let mut asm = RvmJitAsm { buf: Vec::new(), fixups: Vec::new(), mem_len, instrs: code.len() };
+
+        // Entry: jump to the block in rsi with the memory base in rsi
+        asm.emit(&[0x48, 0x89, 0xF0]);                              // mov rax, rsi
+        asm.emit(&[0x48, 0x8B, 0x80 | (RSI << 3) | RDI]);            // mov rsi, [rdi + mem]
+        asm.emit_i32(offset_of!(RvmJitState, mem) as i32);
+        asm.emit(&[0xFF, 0xE0]);                                    // jmp rax
+
+        // Interpreted instructions split blocks as well
+        let mut leaders = vm.prog.rvm_block_leaders();
+        for (idx, instr) in code.iter().enumerate() {
+            if !rvm_jit_supported(instr, mem_len, code.len()) && idx + 1 < code.len() {
+                leaders[idx + 1] = true;
+            }
+        }
+
+        let mut blocks = vec![None; code.len()];
+        for start in 0..code.len() {
+            if !leaders[start] || !rvm_jit_supported(&code[start], mem_len, code.len()) {
+                continue;
+            }
+            blocks[start] = Some(asm.buf.len());
+            let mut idx = start;
+            loop {
+                asm.rvm_jit_instr(&code[idx], idx as i32);
+                if code[idx].rvm_ends_block() {
+                    break;
+                }
+                idx += 1;
+                if leaders[idx] || !rvm_jit_supported(&code[idx], mem_len, code.len()) {
+                    asm.exit_to(idx as i32);
+                    break;
+                }
+            }
+        }
+
+        // Resolve jumps, adding a return stub for every target without a block
+        let fixups = std::mem::take(&mut asm.fixups);
+        let mut stubs: Vec<(bool, i32, usize)> = Vec::new();
+        for (pos, fixup) in fixups {
+            let dest = match fixup {
+                RvmJitFixup::Pc(pc) if pc >= 0 && (pc as usize) < code.len() && blocks[pc as usize].is_some() => {
+                    blocks[pc as usize].unwrap()
+                }
+                RvmJitFixup::Pc(pc) | RvmJitFixup::Fault(pc) => {
+                    let fault = matches!(fixup, RvmJitFixup::Fault(_));
+                    match stubs.iter().find(|(f, p, _)| *f == fault && *p == pc) {
+                        Some((_, _, stub)) => *stub,
+                        None => {
+                            let stub = asm.buf.len();
+                            if fault {
+                                asm.emit_disp(0xC7, 0, RDI, offset_of!(RvmJitState, fallback) as i32);
+                                asm.emit_i32(1);                    // mov dword [rdi + fallback], 1
+                            }
+                            asm.emit(&[0xB8]);                      // mov eax, pc; ret
+                            asm.emit_i32(pc);
+                            asm.emit(&[0xC3]);
+                            stubs.push((fault, pc, stub));
+                            stub
+                        }
+                    }
+                }
+            };
+            let rel = dest as i32 - (pos as i32 + 4);
+            asm.buf[pos..pos + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+
+        let code_len = asm.buf.len().div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let mem = unsafe {
+            mmap(ptr::null_mut(), code_len, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+        };
+        if mem as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(asm.buf.as_ptr(), mem, asm.buf.len());
+            if mprotect(mem, code_len, PROT_READ | PROT_EXEC) != 0 {
+                let err = io::Error::last_os_error();
+                munmap(mem, code_len);
+                return Err(err);
+            }
+        }
+        Ok(RvmJit { code: mem, code_len, blocks })
+    }
+
+    // Run the program from eip until it stops, in compiled code wherever possible
+    pub fn rvm_jit_run(&self, vm: &mut RvmCtx) -> RvmRunResult {
+        let entry: RvmJitEntry = unsafe { std::mem::transmute(self.code) };
+        let mut state = RvmJitState::new(&vm.mem);
+        let mut pc = vm.rvm_vm_eip();
+        let result = loop {
+            if vm.prog.code[pc as usize] == RvmInstr::Halt {
+                break RvmRunResult::Halted(vm.exit_code);
+            }
+            match self.blocks[pc as usize] {
+                Some(offset) if state.fallback == 0 => {
+                    state.mem = vm.mem.mem_space.as_mut_ptr();
+                    pc = unsafe { entry(&mut state, self.code.add(offset)) };
+                }
+                _ => {
+                    state.fallback = 0;
+                    state.rvm_jit_sync_out(&mut vm.mem);
+                    vm.rvm_vm_set_eip(pc);
+                    let stop = vm.rvm_vm_step();
+                    pc = vm.rvm_vm_eip();
+                    state.rvm_jit_sync_in(&vm.mem);
+                    if let Some(result) = stop {
+                        break result;
+                    }
+                }
+            }
+        };
+        state.rvm_jit_sync_out(&mut vm.mem);
+        vm.rvm_vm_set_eip(pc);
+        result
+    }
+}
+
+impl Drop for RvmJit {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.code, self.code_len);
+        }
+    }
+}
@@ -1,36 +1,85 @@
-use crate::rvm_htab::{self, RvmHtabCtx};
+use crate::rvm_htab::RvmHtabCtx;
+use crate::rvm_preprocessor::TOK_LINE;
+use crate::rvm_prog::RvmSrcLoc;
 
 const TVM_LEX_MAX_TOKENS: usize = 1024;
 
 pub struct RvmLexerCtx {
-    pub tokens: Vec<Vec<String>>
+    pub tokens: Vec<Vec<String>>,
+    /* Source position of each line of tokens */
+    pub locs: Vec<RvmSrcLoc>,
+    pub files: Vec<String>
 }
 
 impl RvmLexerCtx {
     pub fn new() -> Self {
         RvmLexerCtx {
-            tokens: vec![]
+            tokens: vec![],
+            locs: vec![],
+            files: vec![]
         }
     }
 
-    pub fn rvm_lex(&mut self, source: &str, defines: &RvmHtabCtx) {
-        // Split the source into individual lines 
-        let lines: Vec<String> = source.lines()
-                            .map(|line| {
-                                /* Ignore comments delimited by '#' */                                
-                                if let Some(comment_index) = line.find('#') {
-                                    line[..comment_index].trim().to_string()
-                                } else {
-                                    line.trim().to_string()
-                                }
-                            })
-                            .filter(|line| !line.is_empty()) // Ignore empty lines
-                            .collect();
-
-        for line in lines {
+    fn file_index(&mut self, file: &str) -> usize {
+        match self.files.iter().position(|f| f == file) {
+            Some(idx) => idx,
+            None => {
+                self.files.push(file.to_string());
+                self.files.len() - 1
+            }
+        }
+    }
+
+    pub fn rvm_lex(&mut self, source: &str, defines: &RvmHtabCtx) -> i32 {
+        let mut loc = RvmSrcLoc { file: 0, line: 0 };
+
+        for raw_line in source.lines() {
+            loc.line += 1;
+
+            // Position markers left behind by the preprocessor
+            if let Some(marker) = raw_line.trim_start().strip_prefix(TOK_LINE) {
+                let mut parts = marker.trim().splitn(2, ' ');
+                // Lines count from 1, so the marked line can be stepped back to
+                let Some(line) = parts.next().and_then(|n| n.parse::<u32>().ok()).filter(|n| *n > 0) else {
+                    println!("Error: line {}: invalid {} marker", loc.line, TOK_LINE);
+                    return 1;
+                };
+                loc.file = self.file_index(parts.next().unwrap_or(""));
+                loc.line = line - 1;
+                continue;
+            }
+
+            /* Ignore comments delimited by '#', unless it is quoted */
+            let (unquoted, open_quote) = rvm_unquoted(raw_line);
+            let code_end = unquoted.iter().find(|(_, c)| *c == '#').map_or(raw_line.len(), |(i, _)| *i);
+            if open_quote.is_some_and(|start| start < code_end) {
+                println!("Error: line {}: unterminated literal", loc.line);
+                return 1;
+            }
+            let line = raw_line[..code_end].trim();
+            // Ignore empty lines
+            if line.is_empty() {
+                continue;
+            }
+
+            // Split at spaces, tabs and commas that are not quoted
+            let mut raw_toks = Vec::new();
+            let mut tok_start = 0;
+            for (i, c) in rvm_unquoted(line).0 {
+                if matches!(c, ' ' | '\t' | ',') {
+                    raw_toks.push(&line[tok_start..i]);
+                    tok_start = i + 1;
+                }
+            }
+            raw_toks.push(&line[tok_start..]);
+
             let mut line_toks: Vec<String> = Vec::with_capacity(TVM_LEX_MAX_TOKENS);
-            for tok in line.split([' ', '\t', ',']) {
-                // Check if token exists in defines map 
+            for tok in raw_toks {
+                if let Some(Err(e)) = rvm_unquote(tok) {
+                    println!("Error: line {}: {}", loc.line, e);
+                    return 1;
+                }
+                // Check if token exists in defines map
                 let resolved_tok = defines.rvm_htab_find_ref(tok);
                 match resolved_tok {
                     Some(resolved) => {
@@ -44,6 +93,82 @@ impl RvmLexerCtx {
                     break;
                 }
             }
+            self.tokens.push(line_toks);
+            self.locs.push(loc);
         }
+        0
     }
-}
\ No newline at end of file
+}
+
+/* The characters of a line that are not inside a string or character
+ * literal, with their byte offsets, and where a literal that never ends
+ * starts. The quotes themselves count as unquoted. */
+fn rvm_unquoted(line: &str) -> (Vec<(usize, char)>, Option<usize>) {
+    let mut unquoted = Vec::new();
+    let mut open: Option<(usize, char)> = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match open {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some((_, quote)) if c == quote => {
+                open = None;
+                unquoted.push((i, c));
+            }
+            Some(_) => {}
+            None => {
+                if c == '"' || c == '\'' {
+                    open = Some((i, c));
+                }
+                unquoted.push((i, c));
+            }
+        }
+    }
+    (unquoted, open.map(|(start, _)| start))
+}
+
+// Whether a token is a string ("...") or character ('.') literal
+pub fn rvm_is_literal(tok: &str) -> bool {
+    tok.starts_with(['"', '\''])
+}
+
+/* The bytes of a string or character literal, with the escapes \n, \t,
+ * \r, \0, \\, \', \" and \xHH replaced; None for any other token. A
+ * character literal holds exactly one byte. */
+pub fn rvm_unquote(tok: &str) -> Option<Result<Vec<u8>, String>> {
+    let quote = tok.chars().next().filter(|_| rvm_is_literal(tok))?;
+    let Some(body) = tok[1..].strip_suffix(quote).filter(|_| tok.len() >= 2) else {
+        return Some(Err(format!("malformed literal {}", tok)));
+    };
+
+    let mut bytes = Vec::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut utf8 = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            continue;
+        }
+        let byte = match chars.next() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('0') => 0,
+            Some(c @ ('\\' | '\'' | '"')) => c as u8,
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) if hex.len() == 2 && hex.chars().all(|c| c.is_ascii_hexdigit()) => byte,
+                    _ => return Some(Err(format!("invalid escape \\x{} in {}", hex, tok))),
+                }
+            }
+            Some(c) => return Some(Err(format!("invalid escape \\{} in {}", c, tok))),
+            None => return Some(Err(format!("malformed literal {}", tok))),
+        };
+        bytes.push(byte);
+    }
+    if quote == '\'' && bytes.len() != 1 {
+        return Some(Err(format!("character literal {} must be one byte", tok)));
+    }
+    Some(Ok(bytes))
+}
@@ -1,21 +1,63 @@
 use core::panic;
+use std::ops::Range;
+
+use crate::rvm::RvmFault;
+use crate::rvm_prog::RvmArg;
+use crate::rvm_word::RvmWord;
 
 const MIN_MEMORY_SIZE: usize = 64 * 1024 * 1024; // 64 MB
 const NUM_REGISTERS: usize = 17;
+const NUM_FLOAT_REGISTERS: usize = 16;
 const MIN_STACK_SIZE: usize = 2 * 1024 * 1024; // 2 MB
 
+/* The data sections start where the stack starts, growing up while the
+ * stack grows down */
+pub const RVM_DATA_BASE: usize = MIN_STACK_SIZE;
+
+/* A register's contents; parts of registers are RvmArg::Sub operands.
+ * In 32-bit mode the value is kept sign-extended from its low half. */
 #[derive(Clone)]
 pub enum RvmRegU {
-    I32(i32),
-    I32ADDR(i32),
-    I16 {h : i16, l : i16},
+    I64(i64),
+    I64ADDR(i64),
+}
+
+impl RvmRegU {
+    pub fn rvm_reg_value(&self) -> i64 {
+        match *self {
+            RvmRegU::I64(val) | RvmRegU::I64ADDR(val) => val,
+        }
+    }
+}
+
+/* A single register or memory access made while executing an
+ * instruction. Only recorded while the journal is enabled; writes
+ * carry the previous contents so they can be undone. */
+#[derive(Clone, Debug, PartialEq)]
+pub enum RvmAccess {
+    RegWrite { reg: usize, old: i64, new: i64 },
+    FregWrite { reg: usize, old: f64 },
+    MemRead { addr: usize, len: usize },
+    MemWrite { addr: usize, len: usize, old: Vec<u8> },
+    FlagsWrite { old: u32 },
+    RemainderWrite { old: i64 },
+}
+
+/* Whether two byte ranges share an address; a range reaching past the
+ * end of the address space is cut off there. */
+pub fn rvm_ranges_overlap(a: usize, a_len: usize, b: usize, b_len: usize) -> bool {
+    a < b.saturating_add(b_len) && b < a.saturating_add(a_len)
 }
 
 pub struct RvmMem {
-    pub flags: u32, 
-    pub remainder: i32,
+    pub flags: u32,
+    /* Kept sign-extended in 32-bit mode, like the registers */
+    pub remainder: i64,
     pub mem_space: Vec<u8>,
-    pub registers: Vec<RvmRegU>
+    pub registers: Vec<RvmRegU>,
+    /* f0..f15 */
+    pub fregs: Vec<f64>,
+    pub journal: Option<Vec<RvmAccess>>
 }
 
 impl RvmMem {
@@ -24,33 +66,261 @@ impl RvmMem {
             flags: 0,
             remainder: 0,
             mem_space: vec![0; MIN_MEMORY_SIZE],
-            registers: vec![RvmRegU::I32(0); NUM_REGISTERS]
+            registers: vec![RvmRegU::I64(0); NUM_REGISTERS],
+            fregs: vec![0.0; NUM_FLOAT_REGISTERS],
+            journal: None
         }
     }
 
     pub fn rvm_stack_create(&mut self) {
         // 0x7 will have the base of the stack
         // 0x6 will have the current top of the stack
-        // 
-        self.registers[0x7] = RvmRegU::I32ADDR(MIN_STACK_SIZE as i32);
-        self.registers[0x6] = RvmRegU::I32ADDR(MIN_STACK_SIZE as i32);
+        //
+        self.registers[0x7] = RvmRegU::I64ADDR(MIN_STACK_SIZE as i64);
+        self.registers[0x6] = RvmRegU::I64ADDR(MIN_STACK_SIZE as i64);
     }
 
-    pub fn rvm_stack_push(&mut self, item : i32) {
-        if let RvmRegU::I32ADDR(mut sp) = self.registers[0x6] {
-            let new_sp = sp - 4;
-            self.mem_space[new_sp as usize..sp as usize].copy_from_slice(&item.to_le_bytes());
+    // Push a stack slot, as wide as W; esp is left alone if the slot is outside memory
+    pub fn rvm_stack_push<W: RvmWord>(&mut self, item : W) -> Result<(), RvmFault> {
+        if let RvmRegU::I64ADDR(sp) = self.registers[0x6] {
+            let new_sp = W::rvm_from_i64(sp).rvm_wrapping_sub(W::rvm_from_i64(W::BYTES as i64));
+            self.rvm_mem_set(new_sp.rvm_addr(), item)?;
+            self.rvm_reg_set(0x6, new_sp);
+            Ok(())
+        } else {
+            panic!("Invalid stack pointer");
         }
     }
 
-    pub fn rvm_stack_pop(&mut self) -> i32 {
-        if let RvmRegU::I32ADDR(mut sp) = self.registers[0x6] {
-            let new_sp = sp + 4;
-            let ret = i32::from_le_bytes(self.mem_space[sp as usize..new_sp as usize].try_into().unwrap());
-            self.registers[0x6] = RvmRegU::I32ADDR(new_sp);
-            ret
+    pub fn rvm_stack_pop<W: RvmWord>(&mut self) -> Result<W, RvmFault> {
+        if let RvmRegU::I64ADDR(sp) = self.registers[0x6] {
+            let sp = W::rvm_from_i64(sp);
+            let ret = self.rvm_mem_get(sp.rvm_addr())?;
+            self.rvm_reg_set(0x6, sp.rvm_wrapping_add(W::rvm_from_i64(W::BYTES as i64)));
+            Ok(ret)
         } else {
             panic!("Invalid stack pointer");
         }
     }
+
+    pub fn rvm_reg_read(&self, reg: usize) -> i32 {
+        self.rvm_reg_get(reg)
+    }
+
+    pub fn rvm_reg_write(&mut self, reg: usize, val: i32) {
+        self.rvm_reg_set(reg, val);
+    }
+
+    // The register as a W: the low half of it in 32-bit mode
+    pub fn rvm_reg_get<W: RvmWord>(&self, reg: usize) -> W {
+        W::rvm_from_i64(self.registers[reg].rvm_reg_value())
+    }
+
+    pub fn rvm_reg_set<W: RvmWord>(&mut self, reg: usize, val: W) {
+        let old = self.registers[reg].rvm_reg_value();
+        let val = val.rvm_to_i64();
+        // Keep address registers (esp, ebp) tagged as addresses
+        self.registers[reg] = match self.registers[reg] {
+            RvmRegU::I64ADDR(_) => RvmRegU::I64ADDR(val),
+            _ => RvmRegU::I64(val),
+        };
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(RvmAccess::RegWrite { reg, old, new: val });
+        }
+    }
+
+    pub fn rvm_freg_write(&mut self, reg: usize, val: f64) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(RvmAccess::FregWrite { reg, old: self.fregs[reg] });
+        }
+        self.fregs[reg] = val;
+    }
+
+    // The `len` bytes at `addr`, faulting unless all of them are inside memory
+    fn rvm_mem_range(&self, addr: usize, len: usize) -> Result<Range<usize>, RvmFault> {
+        match addr.checked_add(len) {
+            Some(end) if end <= self.mem_space.len() => Ok(addr..end),
+            _ => Err(RvmFault::BadAddress(addr)),
+        }
+    }
+
+    // Read the `len` bytes at `addr`, recording the access
+    fn rvm_mem_bytes(&mut self, addr: usize, len: usize) -> Result<&[u8], RvmFault> {
+        let range = self.rvm_mem_range(addr, len)?;
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(RvmAccess::MemRead { addr, len });
+        }
+        Ok(&self.mem_space[range])
+    }
+
+    // The `len` bytes at `addr` to write to, recording what they held
+    fn rvm_mem_bytes_mut(&mut self, addr: usize, len: usize) -> Result<&mut [u8], RvmFault> {
+        let range = self.rvm_mem_range(addr, len)?;
+        if let Some(journal) = self.journal.as_mut() {
+            let old = self.mem_space[range.clone()].to_vec();
+            journal.push(RvmAccess::MemWrite { addr, len, old });
+        }
+        Ok(&mut self.mem_space[range])
+    }
+
+    // Read the W::BYTES bytes at `addr`
+    pub fn rvm_mem_get<W: RvmWord>(&mut self, addr: usize) -> Result<W, RvmFault> {
+        Ok(W::rvm_from_le(self.rvm_mem_bytes(addr, W::BYTES)?))
+    }
+
+    pub fn rvm_mem_set<W: RvmWord>(&mut self, addr: usize, val: W) -> Result<(), RvmFault> {
+        val.rvm_write_le(self.rvm_mem_bytes_mut(addr, W::BYTES)?);
+        Ok(())
+    }
+
+    // Read the `len` bytes at `addr`, zero-extended
+    pub fn rvm_mem_read_n(&mut self, addr: usize, len: usize) -> Result<i32, RvmFault> {
+        let mut bytes = [0; 4];
+        bytes[..len].copy_from_slice(self.rvm_mem_bytes(addr, len)?);
+        Ok(i32::from_le_bytes(bytes))
+    }
+
+    // Write the low `len` bytes of `val` at `addr`
+    pub fn rvm_mem_write_n(&mut self, addr: usize, len: usize, val: i32) -> Result<(), RvmFault> {
+        self.rvm_mem_bytes_mut(addr, len)?.copy_from_slice(&val.to_le_bytes()[..len]);
+        Ok(())
+    }
+
+    pub fn rvm_mem_read_f64(&mut self, addr: usize) -> Result<f64, RvmFault> {
+        Ok(f64::from_le_bytes(self.rvm_mem_bytes(addr, 8)?.try_into().unwrap()))
+    }
+
+    pub fn rvm_mem_write_f64(&mut self, addr: usize, val: f64) -> Result<(), RvmFault> {
+        self.rvm_mem_bytes_mut(addr, 8)?.copy_from_slice(&val.to_le_bytes());
+        Ok(())
+    }
+
+    pub fn rvm_flags_write(&mut self, flags: u32) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(RvmAccess::FlagsWrite { old: self.flags });
+        }
+        self.flags = flags;
+    }
+
+    pub fn rvm_remainder_write(&mut self, remainder: i64) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(RvmAccess::RemainderWrite { old: self.remainder });
+        }
+        self.remainder = remainder;
+    }
+
+    // Revert a recorded write, bypassing the journal
+    pub fn rvm_undo(&mut self, access: &RvmAccess) {
+        match access {
+            RvmAccess::RegWrite { reg, old, .. } => {
+                self.registers[*reg] = match self.registers[*reg] {
+                    RvmRegU::I64ADDR(_) => RvmRegU::I64ADDR(*old),
+                    _ => RvmRegU::I64(*old),
+                };
+            }
+            RvmAccess::MemWrite { addr, len, old } => {
+                self.mem_space[*addr..*addr + *len].copy_from_slice(old);
+            }
+            RvmAccess::FregWrite { reg, old } => self.fregs[*reg] = *old,
+            RvmAccess::FlagsWrite { old } => self.flags = *old,
+            RvmAccess::RemainderWrite { old } => self.remainder = *old,
+            RvmAccess::MemRead { .. } => {}
+        }
+    }
+
+    // Read memory without recording the access in the journal
+    pub fn rvm_mem_peek(&self, addr: usize) -> i32 {
+        self.rvm_mem_peek_word(addr)
+    }
+
+    // Memory outside the space peeks as 0, so a debugger condition on a wild pointer is just false
+    pub fn rvm_mem_peek_word<W: RvmWord>(&self, addr: usize) -> W {
+        match self.rvm_mem_range(addr, W::BYTES) {
+            Ok(range) => W::rvm_from_le(&self.mem_space[range]),
+            Err(_) => W::ZERO,
+        }
+    }
+
+    // Resolve an operand without recording the access in the journal
+    pub fn rvm_peek<W: RvmWord>(&self, arg: &RvmArg) -> W {
+        match *arg {
+            RvmArg::Reg(reg) => self.rvm_reg_get(reg),
+            RvmArg::Mem(addr) => self.rvm_mem_peek_word(addr),
+            RvmArg::Val(val) => W::rvm_from_i64(val),
+            RvmArg::Sub(reg, part) => W::rvm_from_i64(part.rvm_part_get(self.registers[reg].rvm_reg_value())),
+            RvmArg::Ptr(reg, disp) => self.rvm_mem_peek_word(self.rvm_ptr_addr::<W>(reg, disp)),
+            RvmArg::Freg(_) | RvmArg::Fval(_) => unreachable!("not an integer operand"),
+        }
+    }
+
+    // The address [reg+disp] refers to
+    pub fn rvm_ptr_addr<W: RvmWord>(&self, reg: usize, disp: i32) -> usize {
+        self.rvm_reg_get::<W>(reg).rvm_wrapping_add(W::rvm_from_i64(disp as i64)).rvm_addr()
+    }
+
+    /* Resolve an operand to the value it currently holds. This and
+     * rvm_store are inlined into the dispatch loop, where most operands are
+     * registers or immediates and should not pay for a call. */
+    #[inline(always)]
+    pub fn rvm_load<W: RvmWord>(&mut self, arg: &RvmArg) -> Result<W, RvmFault> {
+        Ok(match *arg {
+            RvmArg::Reg(reg) => self.rvm_reg_get(reg),
+            RvmArg::Mem(addr) => self.rvm_mem_get(addr)?,
+            RvmArg::Val(val) => W::rvm_from_i64(val),
+            RvmArg::Sub(reg, part) => W::rvm_from_i64(part.rvm_part_get(self.registers[reg].rvm_reg_value())),
+            RvmArg::Ptr(reg, disp) => {
+                let addr = self.rvm_ptr_addr::<W>(reg, disp);
+                self.rvm_mem_get(addr)?
+            }
+            RvmArg::Freg(_) | RvmArg::Fval(_) => unreachable!("checked when assembling"),
+        })
+    }
+
+    // Store a value through an operand; writes to immediates are dropped
+    #[inline(always)]
+    pub fn rvm_store<W: RvmWord>(&mut self, arg: &RvmArg, val: W) -> Result<(), RvmFault> {
+        match *arg {
+            RvmArg::Reg(reg) => self.rvm_reg_set(reg, val),
+            RvmArg::Mem(addr) => self.rvm_mem_set(addr, val)?,
+            RvmArg::Val(_) => {}
+            RvmArg::Sub(reg, part) => {
+                let full = part.rvm_part_set(self.registers[reg].rvm_reg_value(), val.rvm_to_i64());
+                self.rvm_reg_set(reg, W::rvm_from_i64(full));
+            }
+            RvmArg::Ptr(reg, disp) => {
+                let addr = self.rvm_ptr_addr::<W>(reg, disp);
+                self.rvm_mem_set(addr, val)?;
+            }
+            RvmArg::Freg(_) | RvmArg::Fval(_) => unreachable!("checked when assembling"),
+        }
+        Ok(())
+    }
+
+    // Resolve a float operand to the value it currently holds
+    pub fn rvm_fload<W: RvmWord>(&mut self, arg: &RvmArg) -> Result<f64, RvmFault> {
+        Ok(match *arg {
+            RvmArg::Freg(reg) => self.fregs[reg],
+            RvmArg::Fval(val) => val,
+            RvmArg::Mem(addr) => self.rvm_mem_read_f64(addr)?,
+            RvmArg::Ptr(reg, disp) => {
+                let addr = self.rvm_ptr_addr::<W>(reg, disp);
+                self.rvm_mem_read_f64(addr)?
+            }
+            _ => unreachable!("checked when assembling"),
+        })
+    }
+
+    // Store a value through a float operand
+    pub fn rvm_fstore<W: RvmWord>(&mut self, arg: &RvmArg, val: f64) -> Result<(), RvmFault> {
+        match *arg {
+            RvmArg::Freg(reg) => self.rvm_freg_write(reg, val),
+            RvmArg::Mem(addr) => self.rvm_mem_write_f64(addr, val)?,
+            RvmArg::Ptr(reg, disp) => {
+                let addr = self.rvm_ptr_addr::<W>(reg, disp);
+                self.rvm_mem_write_f64(addr, val)?;
+            }
+            _ => unreachable!("checked when assembling"),
+        }
+        Ok(())
+    }
 }
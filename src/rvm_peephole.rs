@@ -0,0 +1,265 @@
+/* Peephole optimizer.
+ *
+ * Each rule looks at one instruction, or two neighbouring ones, and
+ * removes or rewrites what does nothing:
+ *
+ *   redundant-mov    mov x, x, and a mov repeating or undoing the one before it
+ *   identity-op      add/sub/or/xor x, 0, shifts and rotations by 0, and
+ *                    multiplications and divisions by 1
+ *   jump-to-next     a jump whose target is the next instruction, except
+ *                    calls and loops, which do more than jump
+ *   jump-threading   a jump to a jmp is pointed at that jmp's target instead
+ *
+ * Rules are applied until none fires. Removed instructions are then
+ * dropped and every label, the entry point and every jump target is
+ * moved to the instruction that now holds its place. A program that
+ * jumps through an operand other than a label, returns, or uses a code
+ * label as a value may compute code addresses we cannot follow, so there
+ * removed instructions become nops instead and pairs of instructions are
+ * left alone. Arithmetic sets the carry and
+ * overflow flags, so in a program that reads them back with pushf only
+ * the shifts and rotations by 0 and divisions by 1 count as identity ops.
+ */
+use std::collections::HashSet;
+use std::io::{self, Write};
+
+use crate::rvm_htab::RvmHtabCtx;
+use crate::rvm_prog::{RvmArg, RvmInstr, RvmProg};
+
+const RVM_PEEPHOLE_RULES: [&str; 4] = ["redundant-mov", "identity-op", "jump-to-next", "jump-threading"];
+const RVM_REDUNDANT_MOV: usize = 0;
+const RVM_IDENTITY_OP: usize = 1;
+const RVM_JUMP_TO_NEXT: usize = 2;
+const RVM_JUMP_THREADING: usize = 3;
+
+pub struct RvmPeepholeReport {
+    pub before: usize,
+    pub after: usize,
+    /* Times each rule fired, indexed like RVM_PEEPHOLE_RULES */
+    pub counts: [usize; 4],
+    /* Set when instructions were turned into nops rather than removed */
+    pub in_place: bool,
+}
+
+impl RvmPeepholeReport {
+    pub fn rvm_peephole_report(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "peephole: {} -> {} instructions", self.before, self.after)?;
+        for (rule, count) in RVM_PEEPHOLE_RULES.iter().zip(self.counts) {
+            writeln!(out, "  {:<16} {}", rule, count)?;
+        }
+        if self.in_place {
+            writeln!(out, "  (computed code addresses present: removed instructions were replaced by nop)")?;
+        }
+        Ok(())
+    }
+}
+
+/* Two operands that may name different but overlapping memory, or where
+ * writing one moves the other */
+fn rvm_args_alias(a: &RvmArg, b: &RvmArg) -> bool {
+    match (a, b) {
+        (RvmArg::Mem(x), RvmArg::Mem(y)) => x != y && x.abs_diff(*y) < 4,
+        (RvmArg::Ptr(..), RvmArg::Mem(_) | RvmArg::Ptr(..)) | (RvmArg::Mem(_), RvmArg::Ptr(..)) => a != b,
+        (RvmArg::Ptr(base, _), RvmArg::Reg(reg) | RvmArg::Sub(reg, _))
+        | (RvmArg::Reg(reg) | RvmArg::Sub(reg, _), RvmArg::Ptr(base, _)) => base == reg,
+        _ => false,
+    }
+}
+
+/* Shifts and rotations by 0 and divisions by 1 leave the flags alone
+ * too; the others clear carry and overflow, which only pushf can tell. */
+fn rvm_is_identity(instr: &RvmInstr, reads_flags: bool) -> bool {
+    use RvmInstr::*;
+    match instr {
+        Shl(_, RvmArg::Val(0)) | Shr(_, RvmArg::Val(0)) | Sar(_, RvmArg::Val(0))
+        | Rol(_, RvmArg::Val(0)) | Ror(_, RvmArg::Val(0))
+        | Div(_, RvmArg::Val(1)) | Udiv(_, RvmArg::Val(1)) => true,
+        Add(_, RvmArg::Val(0)) | Sub(_, RvmArg::Val(0)) | Or(_, RvmArg::Val(0))
+        | Xor(_, RvmArg::Val(0)) | Mul(_, RvmArg::Val(1)) | Umul(_, RvmArg::Val(1)) => !reads_flags,
+        _ => false,
+    }
+}
+
+struct RvmPeephole<'a> {
+    prog: &'a mut RvmProg,
+    removed: Vec<bool>,
+    counts: [usize; 4],
+    in_place: bool,
+    reads_flags: bool,
+}
+
+impl RvmPeephole<'_> {
+    // The first instruction at or after `idx` that is still there
+    fn next_kept(&self, mut idx: usize) -> usize {
+        while idx < self.removed.len() && self.removed[idx] {
+            idx += 1;
+        }
+        idx
+    }
+
+    // Instructions control can arrive at other than by falling through
+    fn targets(&self) -> HashSet<usize> {
+        let mut targets: Vec<usize> = self.prog.labels.rvm_htab_entries()
+            .into_iter()
+            .map(|(_, idx)| idx as usize)
+            .collect();
+        targets.push(self.prog.start as usize);
+        for instr in &self.prog.code {
+            if let Some(RvmArg::Val(target)) = instr.rvm_jump_target() {
+                targets.push(target as usize);
+            }
+        }
+        // Arriving at a removed instruction means arriving at the one after it
+        targets.into_iter().map(|idx| self.next_kept(idx)).collect()
+    }
+
+    fn remove(&mut self, idx: usize, rule: usize) {
+        self.removed[idx] = true;
+        self.counts[rule] += 1;
+    }
+
+    fn thread_jumps(&mut self) -> bool {
+        let mut changed = false;
+        for idx in 0..self.prog.code.len() {
+            if self.removed[idx] {
+                continue;
+            }
+            let Some(RvmArg::Val(target)) = self.prog.code[idx].rvm_jump_target() else {
+                continue;
+            };
+            let mut dest = target;
+            let mut seen = HashSet::from([idx]);
+            loop {
+                let at = self.next_kept(dest as usize);
+                match self.prog.code.get(at) {
+                    Some(RvmInstr::Jmp(RvmArg::Val(next))) if seen.insert(at) => dest = *next,
+                    _ => break,
+                }
+            }
+            if dest != target {
+                *self.prog.code[idx].rvm_jump_target_mut().unwrap() = RvmArg::Val(dest);
+                self.counts[RVM_JUMP_THREADING] += 1;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn remove_dead(&mut self) -> bool {
+        let targets = self.targets();
+        let mut changed = false;
+        for idx in 0..self.prog.code.len() {
+            if self.removed[idx] {
+                continue;
+            }
+            let instr = self.prog.code[idx];
+            let next = self.next_kept(idx + 1);
+
+            let rule = match instr {
+                RvmInstr::Mov(a, b) if a == b => Some(RVM_REDUNDANT_MOV),
+                _ if rvm_is_identity(&instr, self.reads_flags) => Some(RVM_IDENTITY_OP),
+                _ => match instr.rvm_jump_target() {
+                    Some(RvmArg::Val(target)) if !matches!(instr, RvmInstr::Call(_))
+                        && !instr.rvm_is_loop() && self.next_kept(target as usize) == next => Some(RVM_JUMP_TO_NEXT),
+                    _ => None,
+                },
+            };
+            if let Some(rule) = rule {
+                self.remove(idx, rule);
+                changed = true;
+                continue;
+            }
+
+            // mov a, b followed by mov a, b or mov b, a: the second changes nothing
+            if !self.in_place
+                && !targets.contains(&next)
+                && let RvmInstr::Mov(a, b) = instr
+                && let Some(RvmInstr::Mov(c, d)) = self.prog.code.get(next)
+                && ((a, b) == (*c, *d) || (a, b) == (*d, *c))
+                && !rvm_args_alias(&a, &b)
+                && a.rvm_width(self.prog.mode) == b.rvm_width(self.prog.mode) {
+                self.remove(next, RVM_REDUNDANT_MOV);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    // Drop removed instructions and move everything that pointed at them along
+    fn compact(&mut self) {
+        let mut new_idx = Vec::with_capacity(self.removed.len());
+        let mut kept: i64 = 0;
+        for removed in &self.removed {
+            new_idx.push(kept);
+            if !removed {
+                kept += 1;
+            }
+        }
+        let remap = |idx: i64| match new_idx.get(idx as usize) {
+            Some(new) if idx >= 0 => *new,
+            _ => idx,
+        };
+
+        let code = std::mem::take(&mut self.prog.code);
+        let locs = std::mem::take(&mut self.prog.locs);
+        for ((mut instr, loc), removed) in code.into_iter().zip(locs).zip(&self.removed) {
+            if *removed {
+                continue;
+            }
+            if let Some(RvmArg::Val(target)) = instr.rvm_jump_target_mut() {
+                *target = remap(*target);
+            }
+            self.prog.code.push(instr);
+            self.prog.locs.push(loc);
+        }
+
+        let mut labels = RvmHtabCtx::new();
+        for (name, idx) in self.prog.labels.rvm_htab_entries() {
+            labels.rvm_htab_add(&name, remap(idx as i64) as i32, "");
+        }
+        self.prog.labels = labels;
+        self.prog.start = remap(self.prog.start as i64) as i32;
+    }
+}
+
+// Optimize `prog` in place and report what each rule did
+pub fn rvm_peephole(prog: &mut RvmProg) -> RvmPeepholeReport {
+    let before = prog.code.len() - 1;
+    let in_place = prog.label_values || prog.code.iter().any(|instr| {
+        instr.rvm_is_return() || matches!(instr.rvm_jump_target(), Some(target) if !matches!(target, RvmArg::Val(_)))
+    });
+
+    let reads_flags = prog.code.iter().any(|instr| matches!(instr, RvmInstr::Pushf));
+
+    let mut pass = RvmPeephole {
+        removed: vec![false; prog.code.len()],
+        prog,
+        counts: [0; 4],
+        in_place,
+        reads_flags,
+    };
+    loop {
+        let threaded = pass.thread_jumps();
+        let removed = pass.remove_dead();
+        if !threaded && !removed {
+            break;
+        }
+    }
+
+    if in_place {
+        for (instr, removed) in pass.prog.code.iter_mut().zip(&pass.removed) {
+            if *removed {
+                *instr = RvmInstr::Nop;
+            }
+        }
+    } else {
+        pass.compact();
+    }
+
+    RvmPeepholeReport {
+        before,
+        after: pass.prog.code.len() - 1,
+        counts: pass.counts,
+        in_place,
+    }
+}
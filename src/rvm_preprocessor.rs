@@ -1,18 +1,27 @@
 use std::{fs, path::Path};
 
 use crate::rvm_htab::RvmHtabCtx;
+use crate::rvm_prog::RvmMode;
 
 const TOK_INCLUDE : &str = "%include";
 const TOK_DEFINE : &str = "%define";
+/* `%bits 32` or `%bits 64` picks the program's mode */
+const TOK_BITS : &str = "%bits";
+/* `%line <n> <file>` markers tell the lexer where the following source
+ * line came from once includes have been spliced in */
+pub const TOK_LINE : &str = "%line";
 
 pub struct RvmPreprocessor {
-    pub defines : RvmHtabCtx
+    pub defines : RvmHtabCtx,
+    /* Set by a %bits directive */
+    pub mode : Option<RvmMode>
 }
 
 impl RvmPreprocessor {
     pub fn new() -> Self {
         RvmPreprocessor {
-            defines : RvmHtabCtx::new()
+            defines : RvmHtabCtx::new(),
+            mode : None
         }
     }
 
@@ -39,6 +48,15 @@ impl RvmPreprocessor {
                     return -1;
                 }
             }
+            match self.process_bits(src) {
+                Ok(res) => {
+                    ret += res as i32;
+                }
+                Err(e) => {
+                    eprintln!("Error processing %bits: {}", e);
+                    return -1;
+                }
+            }
             // Keep going till no includes or defines need to be replaced 
             if ret == 0 {
                 break;
@@ -47,6 +65,18 @@ impl RvmPreprocessor {
         0
     }
 
+    // The file and line number of the source at byte offset `pos`, from the closest marker before it
+    fn location_of(src: &str, pos: usize) -> (String, usize) {
+        let Some(marker) = src[..pos].rfind(TOK_LINE) else {
+            return (String::new(), src[..pos].matches('\n').count() + 1);
+        };
+        let marker_end = src[marker..].find('\n').map(|e| marker + e).unwrap_or(src.len());
+        let mut parts = src[marker + TOK_LINE.len()..marker_end].trim().splitn(2, ' ');
+        let line: usize = parts.next().and_then(|n| n.parse().ok()).unwrap_or(1);
+        let file = parts.next().unwrap_or("").to_string();
+        (file, (line + src[marker_end..pos].matches('\n').count()).saturating_sub(1))
+    }
+
     fn process_includes(&mut self, src: &mut String) -> Result<bool, String> {
         if let Some(start) = src.find(TOK_INCLUDE) {
             // Find the end of the line
@@ -63,14 +93,18 @@ impl RvmPreprocessor {
             }
     
             // Read file content
-            let file_contents: String = fs::read_to_string(filepath)
+            let file_contents: String = fs::read_to_string(&filepath)
                 .map_err(|_| format!("Failed to read file: {}", filename))?;
     
-            src.replace_range(start..end, &file_contents);
+            // Bracket the contents with markers so source positions survive the splice
+            let (parent, line) = RvmPreprocessor::location_of(src, start);
+            let spliced = format!("{} 1 {}\n{}\n{} {} {}",
+                TOK_LINE, filepath.display(), file_contents, TOK_LINE, line + 1, parent);
+            src.replace_range(start..end, &spliced);
             
             return Ok(true);    
         }
-        return Ok(false);
+        Ok(false)
     }
 
     fn process_defines(&mut self, src: &mut String) -> Result<bool, String> {
@@ -100,8 +134,30 @@ impl RvmPreprocessor {
             // Insert into hash table
             self.defines.rvm_htab_add(&key, 0, &value);
 
-            // Remove the `#define` line from source
-            src.replace_range(start..end + 1, ""); 
+            // Remove the `#define` line from source, keeping its newline so line numbers hold
+            src.replace_range(start..end, ""); 
+
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    fn process_bits(&mut self, src: &mut String) -> Result<bool, String> {
+        if let Some(start) = src.find(TOK_BITS) {
+            let end = src[start..].find('\n').map(|e| start + e).unwrap_or(src.len());
+
+            let mode = match src[start + TOK_BITS.len()..end].trim() {
+                "32" => RvmMode::Bits32,
+                "64" => RvmMode::Bits64,
+                bits => return Err(format!("Unsupported width: {}", bits)),
+            };
+            if self.mode.is_some_and(|m| m != mode) {
+                return Err("Conflicting widths".to_string());
+            }
+            self.mode = Some(mode);
+
+            // Like a define, the line goes but its newline stays
+            src.replace_range(start..end, "");
 
             return Ok(true);
         }
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::rvm::{RvmCtx, RvmHook};
+use crate::rvm_memory::RvmAccess;
+use crate::rvm_prog::RvmInstr;
+
+const RVM_PROFILE_TOP_INSTRUCTIONS: usize = 20;
+
+/* Estimated cost of each instruction, roughly in line with what the
+ * host spends executing it. Only relative values matter. */
+pub fn rvm_instr_cycles(instr: &RvmInstr) -> u64 {
+    use RvmInstr::*;
+    match instr {
+        Push(_) | Pop(_) | Pushf | Popf => 2,
+        Mul(..) | Umul(..) => 3,
+        Div(..) | Mod(..) | Udiv(..) | Umod(..) => 20,
+        Call(_) | Ret | RetN(_) => 3,
+        Prn(_) | Fprn(_) => 50,
+        Fdiv(..) | Fsqrt(_) => 20,
+        _ => 1,
+    }
+}
+
+/* A node in the call tree: the function it stands for and its caller */
+struct RvmCallNode {
+    parent: usize,
+    func: i32,
+    cycles: u64,
+}
+
+pub struct RvmProfiler {
+    counts: Vec<u64>,
+    cycles: Vec<u64>,
+    /* Call tree rooted at the entry point; node 0 is the root */
+    nodes: Vec<RvmCallNode>,
+    children: HashMap<(usize, i32), usize>,
+    stack: Vec<usize>,
+    calls: HashMap<i32, u64>,
+}
+
+impl RvmProfiler {
+    pub fn new(vm: &RvmCtx) -> Self {
+        let len = vm.prog.code.len();
+        RvmProfiler {
+            counts: vec![0; len],
+            cycles: vec![0; len],
+            nodes: vec![RvmCallNode { parent: 0, func: vm.prog.start, cycles: 0 }],
+            children: HashMap::new(),
+            stack: vec![0],
+            calls: HashMap::new()
+        }
+    }
+
+    fn label_at(labels: &[(i32, String)], idx: i32) -> Option<&(i32, String)> {
+        let pos = labels.partition_point(|(label_idx, _)| *label_idx <= idx);
+        if pos == 0 { None } else { labels.get(pos - 1) }
+    }
+
+    // Name of the code at `idx`, as `label` or `label+offset`
+    fn location_name(labels: &[(i32, String)], idx: i32) -> String {
+        match Self::label_at(labels, idx) {
+            Some((label_idx, name)) if *label_idx == idx => name.clone(),
+            Some((label_idx, name)) => format!("{}+{}", name, idx - label_idx),
+            None => format!("@{}", idx),
+        }
+    }
+
+    fn function_name(labels: &[(i32, String)], func: i32) -> String {
+        match Self::label_at(labels, func) {
+            Some((label_idx, name)) if *label_idx == func => name.clone(),
+            _ => format!("fn@{}", func),
+        }
+    }
+
+    fn path(&self, mut node: usize) -> Vec<i32> {
+        let mut path = vec![self.nodes[node].func];
+        while node != 0 {
+            node = self.nodes[node].parent;
+            path.push(self.nodes[node].func);
+        }
+        path.reverse();
+        path
+    }
+
+    fn percent(part: u64, total: u64) -> f64 {
+        if total == 0 { 0.0 } else { part as f64 * 100.0 / total as f64 }
+    }
+
+    pub fn rvm_profile_report(&self, vm: &RvmCtx, out: &mut dyn Write) -> io::Result<()> {
+        let labels = vm.prog.rvm_labels_sorted();
+        let total_instrs: u64 = self.counts.iter().sum();
+        let total_cycles: u64 = self.cycles.iter().sum();
+        writeln!(out, "Executed {} instructions, {} cycles", total_instrs, total_cycles)?;
+
+        let mut by_instr: Vec<usize> = (0..self.counts.len()).filter(|i| self.counts[*i] > 0).collect();
+        by_instr.sort_by_key(|i| (std::cmp::Reverse(self.cycles[*i]), *i));
+        writeln!(out, "\nHottest instructions:")?;
+        writeln!(out, "{:>6}  {:<20} {:<6} {:>12} {:>12} {:>7}", "index", "location", "op", "count", "cycles", "%")?;
+        for i in by_instr.iter().take(RVM_PROFILE_TOP_INSTRUCTIONS) {
+            writeln!(out, "{:>6}  {:<20} {:<6} {:>12} {:>12} {:>6.2}%",
+                i, Self::location_name(&labels, *i as i32), vm.prog.code[*i].rvm_mnemonic(),
+                self.counts[*i], self.cycles[*i], Self::percent(self.cycles[*i], total_cycles))?;
+        }
+
+        let mut by_label: HashMap<String, (u64, u64)> = HashMap::new();
+        for i in by_instr.iter() {
+            let name = match Self::label_at(&labels, *i as i32) {
+                Some((_, name)) => name.clone(),
+                None => "(none)".to_string(),
+            };
+            let entry = by_label.entry(name).or_default();
+            entry.0 += self.counts[*i];
+            entry.1 += self.cycles[*i];
+        }
+        let mut by_label: Vec<(String, (u64, u64))> = by_label.into_iter().collect();
+        by_label.sort_by(|a, b| b.1.1.cmp(&a.1.1).then(a.0.cmp(&b.0)));
+        writeln!(out, "\nBy label:")?;
+        writeln!(out, "{:<28} {:>12} {:>12} {:>7}", "label", "count", "cycles", "%")?;
+        for (name, (count, cycles)) in &by_label {
+            writeln!(out, "{:<28} {:>12} {:>12} {:>6.2}%", name, count, cycles, Self::percent(*cycles, total_cycles))?;
+        }
+
+        // Self cost is charged to the innermost function, inclusive cost to every function on the stack once
+        let mut by_func: HashMap<i32, (u64, u64)> = HashMap::new();
+        for (node, call_node) in self.nodes.iter().enumerate() {
+            by_func.entry(call_node.func).or_default().0 += call_node.cycles;
+            let mut path = self.path(node);
+            path.sort();
+            path.dedup();
+            for func in path {
+                by_func.entry(func).or_default().1 += call_node.cycles;
+            }
+        }
+        let mut by_func: Vec<(i32, (u64, u64))> = by_func.into_iter().collect();
+        by_func.sort_by(|a, b| b.1.1.cmp(&a.1.1).then(b.1.0.cmp(&a.1.0)).then(a.0.cmp(&b.0)));
+        writeln!(out, "\nBy function:")?;
+        writeln!(out, "{:<28} {:>10} {:>12} {:>12} {:>7}", "function", "calls", "self", "inclusive", "%")?;
+        for (func, (self_cycles, incl_cycles)) in &by_func {
+            writeln!(out, "{:<28} {:>10} {:>12} {:>12} {:>6.2}%",
+                Self::function_name(&labels, *func), self.calls.get(func).copied().unwrap_or(0),
+                self_cycles, incl_cycles, Self::percent(*incl_cycles, total_cycles))?;
+        }
+        Ok(())
+    }
+
+    // One `caller;callee cycles` line per call stack, as consumed by flamegraph tools
+    pub fn rvm_profile_folded(&self, vm: &RvmCtx, out: &mut dyn Write) -> io::Result<()> {
+        let labels = vm.prog.rvm_labels_sorted();
+        let mut lines: Vec<(String, u64)> = self.nodes.iter().enumerate()
+            .filter(|(_, call_node)| call_node.cycles > 0)
+            .map(|(node, call_node)| {
+                let names: Vec<String> = self.path(node).iter().map(|f| Self::function_name(&labels, *f)).collect();
+                (names.join(";"), call_node.cycles)
+            })
+            .collect();
+        lines.sort();
+        for (stack, cycles) in lines {
+            writeln!(out, "{} {}", stack, cycles)?;
+        }
+        Ok(())
+    }
+}
+
+impl RvmHook for RvmProfiler {
+    fn rvm_hook_step(&mut self, vm: &RvmCtx, instr_idx: i32, _accesses: &[RvmAccess]) {
+        let instr = vm.prog.code[instr_idx as usize];
+        let cycles = rvm_instr_cycles(&instr);
+        self.counts[instr_idx as usize] += 1;
+        self.cycles[instr_idx as usize] += cycles;
+
+        let node = *self.stack.last().unwrap();
+        self.nodes[node].cycles += cycles;
+
+        match instr {
+            RvmInstr::Call(_) => {
+                // CALL: the callee is wherever execution continues
+                let func = vm.rvm_vm_eip();
+                *self.calls.entry(func).or_default() += 1;
+                let next_node = self.nodes.len();
+                let child = *self.children.entry((node, func)).or_insert(next_node);
+                if child == next_node {
+                    self.nodes.push(RvmCallNode { parent: node, func, cycles: 0 });
+                }
+                self.stack.push(child);
+            }
+            RvmInstr::Ret | RvmInstr::RetN(_) if self.stack.len() > 1 => {
+                // RET: never pop the entry point, so a stray ret is charged to it
+                self.stack.pop();
+            }
+            _ => {}
+        }
+    }
+}
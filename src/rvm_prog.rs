@@ -1,25 +1,773 @@
-use crate::rvm_htab;
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::rvm::{RVM_FLAG_EQUAL, RVM_FLAG_GREATER, RvmFloatRegisterMap, RvmOpcodeMap, RvmRegister64Map, RvmRegisterMap, RvmSubRegisterMap};
 use crate::rvm_htab::RvmHtabCtx;
-use crate::rvm_memory;
+use crate::rvm_memory::RVM_DATA_BASE;
+
+/* An instruction operand. TinyVM resolves every operand to a pointer
+ * into the register file, the value table or the memory space; we keep
+ * the same three cases but store them by index instead. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RvmArg {
+    Reg(usize),
+    Mem(usize),
+    /* Range-checked to the program's mode when assembling */
+    Val(i64),
+    /* Part of a register, named in RvmSubRegisterMap */
+    Sub(usize, RvmRegPart),
+    /* The memory at a register plus a displacement, like [ebp-4] */
+    Ptr(usize, i32),
+    /* A float register, f0..f15, and a float literal; only the float
+     * instructions take these */
+    Freg(usize),
+    Fval(f64),
+}
+
+/* The slice of a register a sub-register names. Reading one
+ * zero-extends it; writing one replaces only its bits, truncating the
+ * value and leaving the rest of the register alone. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RvmRegPart {
+    /* bits 0-15, like ax */
+    Word,
+    /* bits 0-7, like al */
+    Low,
+    /* bits 8-15, like ah */
+    High,
+    /* bits 0-31, like eax in 64-bit mode */
+    Dword,
+}
+
+impl RvmRegPart {
+    fn rvm_part_shift_mask(&self) -> (u32, u64) {
+        match self {
+            RvmRegPart::Dword => (0, 0xffffffff),
+            RvmRegPart::Word => (0, 0xffff),
+            RvmRegPart::Low => (0, 0xff),
+            RvmRegPart::High => (8, 0xff),
+        }
+    }
+
+    // The part of `full` this names, zero-extended
+    pub fn rvm_part_get(&self, full: i64) -> i64 {
+        let (shift, mask) = self.rvm_part_shift_mask();
+        ((full as u64 >> shift) & mask) as i64
+    }
+
+    /* `full` with this part replaced by the low bits of `val`; like on
+     * x86-64, writing a Dword clears the high half instead of keeping it */
+    pub fn rvm_part_set(&self, full: i64, val: i64) -> i64 {
+        let (shift, mask) = self.rvm_part_shift_mask();
+        match self {
+            RvmRegPart::Dword => (val as u64 & mask) as i64,
+            _ => ((full as u64 & !(mask << shift)) | ((val as u64 & mask) << shift)) as i64,
+        }
+    }
+
+    // A value read from this part, sign-extended from its width instead
+    pub fn rvm_part_sign_extend(&self, val: i64) -> i64 {
+        match self {
+            RvmRegPart::Dword => val as i32 as i64,
+            RvmRegPart::Word => val as i16 as i64,
+            RvmRegPart::Low | RvmRegPart::High => val as i8 as i64,
+        }
+    }
+}
+
+/* How wide a program's registers, memory operands and stack slots are.
+ * Chosen with `%bits 64` in the source or RvmCtx::new_with_mode. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RvmMode {
+    Bits32,
+    Bits64,
+}
+
+impl RvmMode {
+    // What a whole register is called in this mode
+    pub fn rvm_reg_name(&self, reg: usize) -> &'static str {
+        match self {
+            RvmMode::Bits32 => RvmRegisterMap[reg],
+            RvmMode::Bits64 => RvmRegister64Map[reg],
+        }
+    }
+}
+
+/* Where the lines of a program go: instructions, initialized data, or
+ * data that only reserves zeroed space. Chosen with `section .text`,
+ * `.data` or `.bss`; programs start in .text. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RvmSection {
+    Text,
+    Data,
+    Bss,
+}
+
+impl RvmSection {
+    pub fn rvm_section_of(name: &str) -> Option<RvmSection> {
+        match name {
+            ".text" => Some(RvmSection::Text),
+            ".data" => Some(RvmSection::Data),
+            ".bss" => Some(RvmSection::Bss),
+            _ => None,
+        }
+    }
+}
+
+impl RvmArg {
+    // Bits the operand holds; arithmetic on it wraps around at this width
+    pub fn rvm_width(&self, mode: RvmMode) -> u32 {
+        match (self, mode) {
+            (RvmArg::Sub(_, RvmRegPart::Word), _) => 16,
+            (RvmArg::Sub(_, RvmRegPart::Low | RvmRegPart::High), _) => 8,
+            (RvmArg::Sub(_, RvmRegPart::Dword), _) | (_, RvmMode::Bits32) => 32,
+            (_, RvmMode::Bits64) => 64,
+        }
+    }
+
+    // The operand as written in a program of the given mode
+    pub fn rvm_arg_text(&self, mode: RvmMode) -> String {
+        match *self {
+            RvmArg::Reg(reg) => mode.rvm_reg_name(reg).to_string(),
+            RvmArg::Ptr(reg, 0) => format!("[{}]", mode.rvm_reg_name(reg)),
+            RvmArg::Ptr(reg, disp) if disp < 0 => format!("[{}-{}]", mode.rvm_reg_name(reg), disp.unsigned_abs()),
+            RvmArg::Ptr(reg, disp) => format!("[{}+{}]", mode.rvm_reg_name(reg), disp),
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for RvmArg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RvmArg::Reg(_) | RvmArg::Ptr(..) => write!(f, "{}", self.rvm_arg_text(RvmMode::Bits32)),
+            RvmArg::Mem(addr) => write!(f, "[{}]", addr),
+            RvmArg::Val(val) => write!(f, "{}", val),
+            RvmArg::Sub(reg, part) => {
+                let (name, _, _) = RvmSubRegisterMap.iter().find(|(_, r, p)| *r == reg && *p == part).unwrap();
+                write!(f, "{}", name)
+            }
+            RvmArg::Freg(reg) => write!(f, "{}", RvmFloatRegisterMap[reg]),
+            // Debug formatting keeps the decimal point, so it reads back as a float
+            RvmArg::Fval(val) => write!(f, "{:?}", val),
+        }
+    }
+}
+
+/* Condition of a conditional jump, as tested against the flags left by
+ * `cmp`. Fused compare-and-branch instructions carry one of these. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RvmJumpCond {
+    E,
+    Ne,
+    G,
+    Ge,
+    L,
+    Le,
+}
+
+impl RvmJumpCond {
+    // The condition and target of a conditional jump
+    pub fn rvm_cond_of(instr: &RvmInstr) -> Option<(RvmJumpCond, RvmArg)> {
+        match *instr {
+            RvmInstr::Je(target) => Some((RvmJumpCond::E, target)),
+            RvmInstr::Jne(target) => Some((RvmJumpCond::Ne, target)),
+            RvmInstr::Jg(target) => Some((RvmJumpCond::G, target)),
+            RvmInstr::Jge(target) => Some((RvmJumpCond::Ge, target)),
+            RvmInstr::Jl(target) => Some((RvmJumpCond::L, target)),
+            RvmInstr::Jle(target) => Some((RvmJumpCond::Le, target)),
+            _ => None,
+        }
+    }
+
+    pub fn rvm_cond_holds(&self, flags: u32) -> bool {
+        match self {
+            RvmJumpCond::E => flags & RVM_FLAG_EQUAL != 0,
+            RvmJumpCond::Ne => flags & RVM_FLAG_EQUAL == 0,
+            RvmJumpCond::G => flags & RVM_FLAG_GREATER != 0,
+            RvmJumpCond::Ge => flags & (RVM_FLAG_EQUAL | RVM_FLAG_GREATER) != 0,
+            RvmJumpCond::L => flags & (RVM_FLAG_EQUAL | RVM_FLAG_GREATER) == 0,
+            RvmJumpCond::Le => flags & RVM_FLAG_GREATER == 0,
+        }
+    }
+
+    pub fn rvm_cond_mnemonic(&self) -> &'static str {
+        match self {
+            RvmJumpCond::E => "je",
+            RvmJumpCond::Ne => "jne",
+            RvmJumpCond::G => "jg",
+            RvmJumpCond::Ge => "jge",
+            RvmJumpCond::L => "jl",
+            RvmJumpCond::Le => "jle",
+        }
+    }
+}
+
+/* The prefix repeating a string instruction ecx times. `repe` stops
+ * early once a comparison comes out unequal and `repne` once one comes
+ * out equal; on cmps and scas a plain `rep` means `repe`. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RvmRep {
+    Once,
+    Rep,
+    Repe,
+    Repne,
+}
+
+impl RvmRep {
+    pub fn rvm_rep_of(tok: &str) -> Option<RvmRep> {
+        match tok {
+            "rep" => Some(RvmRep::Rep),
+            "repe" | "repz" => Some(RvmRep::Repe),
+            "repne" | "repnz" => Some(RvmRep::Repne),
+            _ => None,
+        }
+    }
+
+    pub fn rvm_rep_mnemonic(&self) -> &'static str {
+        match self {
+            RvmRep::Once => "",
+            RvmRep::Rep => "rep",
+            RvmRep::Repe => "repe",
+            RvmRep::Repne => "repne",
+        }
+    }
+}
+
+/* A decoded instruction. Operands are stored inline and their number
+ * is checked once at assembly time, so the interpreter never has to.
+ * Variants follow the TinyVM opcode order; `Halt` is the sentinel
+ * placed after the last instruction. The variants after it are
+ * superinstructions, only ever produced by the fusion pass. */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RvmInstr {
+    Nop,
+    Int(RvmArg),
+    Mov(RvmArg, RvmArg),
+    Push(RvmArg),
+    Pop(RvmArg),
+    Pushf,
+    Popf,
+    Inc(RvmArg),
+    Dec(RvmArg),
+    Add(RvmArg, RvmArg),
+    Sub(RvmArg, RvmArg),
+    Mul(RvmArg, RvmArg),
+    Div(RvmArg, RvmArg),
+    Mod(RvmArg, RvmArg),
+    Rem(RvmArg),
+    Not(RvmArg),
+    Xor(RvmArg, RvmArg),
+    Or(RvmArg, RvmArg),
+    And(RvmArg, RvmArg),
+    Shl(RvmArg, RvmArg),
+    Shr(RvmArg, RvmArg),
+    Cmp(RvmArg, RvmArg),
+    Jmp(RvmArg),
+    Call(RvmArg),
+    Ret,
+    /* ret n: return, then drop n bytes of arguments */
+    RetN(RvmArg),
+    Je(RvmArg),
+    Jne(RvmArg),
+    Jg(RvmArg),
+    Jge(RvmArg),
+    Jl(RvmArg),
+    Jle(RvmArg),
+    Prn(RvmArg),
+    Movsx(RvmArg, RvmArg),
+    Neg(RvmArg),
+    Sar(RvmArg, RvmArg),
+    Rol(RvmArg, RvmArg),
+    Ror(RvmArg, RvmArg),
+    Test(RvmArg, RvmArg),
+    Xchg(RvmArg, RvmArg),
+    Lea(RvmArg, RvmArg),
+    Umul(RvmArg, RvmArg),
+    Udiv(RvmArg, RvmArg),
+    Umod(RvmArg, RvmArg),
+    Enter(RvmArg),
+    Leave,
+    /* Decrement ecx and jump while it is not zero (and, for loopz and
+     * loopnz, while the last comparison was equal or not equal) */
+    Loop(RvmArg),
+    Loopz(RvmArg),
+    Loopnz(RvmArg),
+    /* String instructions on 1 or 2 bytes at a time, stepping esi and
+     * edi forward by that many:
+     *   movs  copy [esi] to [edi]
+     *   stos  store al or ax at [edi]
+     *   cmps  compare [esi] with [edi]
+     *   scas  compare al or ax with [edi] */
+    Movs(usize, RvmRep),
+    Stos(usize, RvmRep),
+    Cmps(usize, RvmRep),
+    Scas(usize, RvmRep),
+    /* Float instructions. Their memory operands hold 8-byte IEEE-754
+     * doubles. fcmp sets the flags like cmp, or only carry when either
+     * side is NaN; itof converts an integer, and ftoi truncates towards
+     * zero, saturating at the ends of the register width (the i32 range,
+     * or the i64 range in 64-bit mode) and taking NaN to 0. */
+    Fld(RvmArg, RvmArg),
+    Fst(RvmArg, RvmArg),
+    Fadd(RvmArg, RvmArg),
+    Fsub(RvmArg, RvmArg),
+    Fmul(RvmArg, RvmArg),
+    Fdiv(RvmArg, RvmArg),
+    Fsqrt(RvmArg),
+    Fcmp(RvmArg, RvmArg),
+    Itof(RvmArg, RvmArg),
+    Ftoi(RvmArg, RvmArg),
+    Fprn(RvmArg),
+    Halt,
+    /* cmp a, b; jcc target */
+    CmpJcc(RvmJumpCond, RvmArg, RvmArg, RvmArg),
+    /* inc a; cmp a, b; jcc target */
+    IncCmpJcc(RvmJumpCond, RvmArg, RvmArg, RvmArg),
+    /* push src; pop dest */
+    PushPop(RvmArg, RvmArg),
+}
+
+impl RvmInstr {
+    // Build an instruction from an opcode and its operands, if the operand count fits
+    pub fn rvm_decode(opcode: i32, args: &[RvmArg]) -> Option<RvmInstr> {
+        use RvmInstr::*;
+        let instr = match (opcode, args) {
+            (0x0, []) => Nop,
+            (0x1, [a]) => Int(*a),
+            (0x2, [a, b]) => Mov(*a, *b),
+            (0x3, [a]) => Push(*a),
+            (0x4, [a]) => Pop(*a),
+            (0x5, []) => Pushf,
+            (0x6, []) => Popf,
+            (0x7, [a]) => Inc(*a),
+            (0x8, [a]) => Dec(*a),
+            (0x9, [a, b]) => Add(*a, *b),
+            (0xA, [a, b]) => Sub(*a, *b),
+            (0xB, [a, b]) => Mul(*a, *b),
+            (0xC, [a, b]) => Div(*a, *b),
+            (0xD, [a, b]) => Mod(*a, *b),
+            (0xE, [a]) => Rem(*a),
+            (0xF, [a]) => Not(*a),
+            (0x10, [a, b]) => Xor(*a, *b),
+            (0x11, [a, b]) => Or(*a, *b),
+            (0x12, [a, b]) => And(*a, *b),
+            (0x13, [a, b]) => Shl(*a, *b),
+            (0x14, [a, b]) => Shr(*a, *b),
+            (0x15, [a, b]) => Cmp(*a, *b),
+            (0x16, [a]) => Jmp(*a),
+            (0x17, [a]) => Call(*a),
+            (0x18, []) => Ret,
+            (0x18, [a]) => RetN(*a),
+            (0x19, [a]) => Je(*a),
+            (0x1A, [a]) => Jne(*a),
+            (0x1B, [a]) => Jg(*a),
+            (0x1C, [a]) => Jge(*a),
+            (0x1D, [a]) => Jl(*a),
+            (0x1E, [a]) => Jle(*a),
+            (0x1F, [a]) => Prn(*a),
+            (0x20, [a, b]) => Movsx(*a, *b),
+            (0x21, [a]) => Neg(*a),
+            (0x22, [a, b]) => Sar(*a, *b),
+            (0x23, [a, b]) => Rol(*a, *b),
+            (0x24, [a, b]) => Ror(*a, *b),
+            (0x25, [a, b]) => Test(*a, *b),
+            (0x26, [a, b]) => Xchg(*a, *b),
+            (0x27, [a, b]) => Lea(*a, *b),
+            (0x28, [a, b]) => Umul(*a, *b),
+            (0x29, [a, b]) => Udiv(*a, *b),
+            (0x2A, [a, b]) => Umod(*a, *b),
+            (0x2B, [a]) => Enter(*a),
+            (0x2C, []) => Leave,
+            (0x2D, [a]) => Loop(*a),
+            (0x2E, [a]) => Loopz(*a),
+            (0x2F, [a]) => Loopnz(*a),
+            (0x30, []) => Movs(1, RvmRep::Once),
+            (0x31, []) => Movs(2, RvmRep::Once),
+            (0x32, []) => Stos(1, RvmRep::Once),
+            (0x33, []) => Stos(2, RvmRep::Once),
+            (0x34, []) => Cmps(1, RvmRep::Once),
+            (0x35, []) => Cmps(2, RvmRep::Once),
+            (0x36, []) => Scas(1, RvmRep::Once),
+            (0x37, []) => Scas(2, RvmRep::Once),
+            (0x38, [a, b]) => Fld(*a, *b),
+            (0x39, [a, b]) => Fst(*a, *b),
+            (0x3A, [a, b]) => Fadd(*a, *b),
+            (0x3B, [a, b]) => Fsub(*a, *b),
+            (0x3C, [a, b]) => Fmul(*a, *b),
+            (0x3D, [a, b]) => Fdiv(*a, *b),
+            (0x3E, [a]) => Fsqrt(*a),
+            (0x3F, [a, b]) => Fcmp(*a, *b),
+            (0x40, [a, b]) => Itof(*a, *b),
+            (0x41, [a, b]) => Ftoi(*a, *b),
+            (0x42, [a]) => Fprn(*a),
+            _ => return None,
+        };
+        Some(instr)
+    }
+
+    // The string instruction repeated by `rep`, or None for anything else
+    pub fn rvm_with_rep(&self, rep: RvmRep) -> Option<RvmInstr> {
+        use RvmInstr::*;
+        let compares = rep == RvmRep::Rep && matches!(self, Cmps(..) | Scas(..));
+        let rep = if compares { RvmRep::Repe } else { rep };
+        match *self {
+            Movs(size, _) if rep == RvmRep::Rep => Some(Movs(size, rep)),
+            Stos(size, _) if rep == RvmRep::Rep => Some(Stos(size, rep)),
+            Cmps(size, _) => Some(Cmps(size, rep)),
+            Scas(size, _) => Some(Scas(size, rep)),
+            _ => None,
+        }
+    }
+
+    // The TinyVM opcode number, -1 for the sentinel. A superinstruction
+    // reports the opcode of the first instruction it replaced.
+    pub fn rvm_opcode(&self) -> i32 {
+        use RvmInstr::*;
+        match self {
+            Nop => 0x0, Int(_) => 0x1, Mov(..) => 0x2,
+            Push(_) => 0x3, Pop(_) => 0x4, Pushf => 0x5, Popf => 0x6,
+            Inc(_) => 0x7, Dec(_) => 0x8, Add(..) => 0x9, Sub(..) => 0xA,
+            Mul(..) => 0xB, Div(..) => 0xC, Mod(..) => 0xD, Rem(_) => 0xE,
+            Not(_) => 0xF, Xor(..) => 0x10, Or(..) => 0x11, And(..) => 0x12,
+            Shl(..) => 0x13, Shr(..) => 0x14,
+            Cmp(..) => 0x15, Jmp(_) => 0x16, Call(_) => 0x17, Ret | RetN(_) => 0x18,
+            Je(_) => 0x19, Jne(_) => 0x1A, Jg(_) => 0x1B,
+            Jge(_) => 0x1C, Jl(_) => 0x1D, Jle(_) => 0x1E,
+            Prn(_) => 0x1F, Movsx(..) => 0x20,
+            Neg(_) => 0x21, Sar(..) => 0x22, Rol(..) => 0x23, Ror(..) => 0x24,
+            Test(..) => 0x25, Xchg(..) => 0x26, Lea(..) => 0x27,
+            Umul(..) => 0x28, Udiv(..) => 0x29, Umod(..) => 0x2A,
+            Enter(_) => 0x2B, Leave => 0x2C,
+            Loop(_) => 0x2D, Loopz(_) => 0x2E, Loopnz(_) => 0x2F,
+            Movs(size, _) => 0x2F + *size as i32, Stos(size, _) => 0x31 + *size as i32,
+            Cmps(size, _) => 0x33 + *size as i32, Scas(size, _) => 0x35 + *size as i32,
+            Fld(..) => 0x38, Fst(..) => 0x39, Fadd(..) => 0x3A, Fsub(..) => 0x3B,
+            Fmul(..) => 0x3C, Fdiv(..) => 0x3D, Fsqrt(_) => 0x3E, Fcmp(..) => 0x3F,
+            Itof(..) => 0x40, Ftoi(..) => 0x41, Fprn(_) => 0x42,
+            Halt => -0x1,
+            CmpJcc(..) => 0x15, IncCmpJcc(..) => 0x7, PushPop(..) => 0x3,
+        }
+    }
+
+    pub fn rvm_mnemonic(&self) -> String {
+        match *self {
+            RvmInstr::Halt => "halt".to_string(),
+            RvmInstr::CmpJcc(cond, ..) => format!("cmp+{}", cond.rvm_cond_mnemonic()),
+            RvmInstr::IncCmpJcc(cond, ..) => format!("inc+cmp+{}", cond.rvm_cond_mnemonic()),
+            RvmInstr::PushPop(..) => "push+pop".to_string(),
+            RvmInstr::Movs(_, rep) | RvmInstr::Stos(_, rep) | RvmInstr::Cmps(_, rep) | RvmInstr::Scas(_, rep)
+                if rep != RvmRep::Once => {
+                format!("{} {}", rep.rvm_rep_mnemonic(), RvmOpcodeMap[self.rvm_opcode() as usize])
+            }
+            _ => RvmOpcodeMap[self.rvm_opcode() as usize].to_string(),
+        }
+    }
+
+    pub fn rvm_args(&self) -> Vec<RvmArg> {
+        use RvmInstr::*;
+        match *self {
+            Mov(a, b) | Movsx(a, b) | Add(a, b) | Sub(a, b) | Mul(a, b) | Div(a, b) | Mod(a, b)
+            | Xor(a, b) | Or(a, b) | And(a, b) | Shl(a, b) | Shr(a, b) | Cmp(a, b)
+            | Sar(a, b) | Rol(a, b) | Ror(a, b) | Test(a, b) | Xchg(a, b) | Lea(a, b)
+            | Umul(a, b) | Udiv(a, b) | Umod(a, b)
+            | Fld(a, b) | Fst(a, b) | Fadd(a, b) | Fsub(a, b) | Fmul(a, b) | Fdiv(a, b)
+            | Fcmp(a, b) | Itof(a, b) | Ftoi(a, b) => vec![a, b],
+            Int(a) | Push(a) | Pop(a) | Inc(a) | Dec(a) | Rem(a) | Not(a) | Neg(a) | RetN(a) | Enter(a)
+            | Jmp(a) | Call(a) | Je(a) | Jne(a) | Jg(a) | Jge(a) | Jl(a) | Jle(a) | Prn(a)
+            | Loop(a) | Loopz(a) | Loopnz(a) | Fsqrt(a) | Fprn(a) => vec![a],
+            Nop | Pushf | Popf | Ret | Leave | Halt
+            | Movs(..) | Stos(..) | Cmps(..) | Scas(..) => vec![],
+            CmpJcc(_, a, b, t) | IncCmpJcc(_, a, b, t) => vec![a, b, t],
+            PushPop(a, b) => vec![a, b],
+        }
+    }
+
+    // The operand this instruction stores its result through, if any; xchg stores through both
+    pub fn rvm_dest(&self) -> Option<RvmArg> {
+        use RvmInstr::*;
+        match *self {
+            Mov(a, _) | Movsx(a, _) | Pop(a) | Inc(a) | Dec(a) | Add(a, _) | Sub(a, _) | Mul(a, _) | Div(a, _)
+            | Rem(a) | Not(a) | Xor(a, _) | Or(a, _) | And(a, _) | Shl(a, _) | Shr(a, _)
+            | Neg(a) | Sar(a, _) | Rol(a, _) | Ror(a, _) | Xchg(a, _) | Lea(a, _) | Umul(a, _) | Udiv(a, _)
+            | Fld(a, _) | Fst(a, _) | Fadd(a, _) | Fsub(a, _) | Fmul(a, _) | Fdiv(a, _) | Fsqrt(a)
+            | Itof(a, _) | Ftoi(a, _)
+            | IncCmpJcc(_, a, _, _) | PushPop(_, a) => Some(a),
+            _ => None,
+        }
+    }
+
+    /* Whether each operand is of the kind the instruction works on: float
+     * instructions take float registers, float literals and memory, the
+     * others integer operands */
+    pub fn rvm_operands_typed(&self) -> bool {
+        use RvmInstr::*;
+        let freg = |arg: RvmArg| matches!(arg, RvmArg::Freg(_));
+        let float = |arg: RvmArg| matches!(arg, RvmArg::Freg(_) | RvmArg::Fval(_) | RvmArg::Mem(_) | RvmArg::Ptr(..));
+        let int = |arg: RvmArg| !matches!(arg, RvmArg::Freg(_) | RvmArg::Fval(_));
+        match *self {
+            Fld(a, b) | Fadd(a, b) | Fsub(a, b) | Fmul(a, b) | Fdiv(a, b) | Fcmp(a, b) => freg(a) && float(b),
+            Fst(a, b) => matches!(a, RvmArg::Mem(_) | RvmArg::Ptr(..)) && freg(b),
+            Fsqrt(a) => freg(a),
+            Fprn(a) => float(a),
+            Itof(a, b) => freg(a) && int(b),
+            Ftoi(a, b) => int(a) && freg(b),
+            _ => self.rvm_args().into_iter().all(int),
+        }
+    }
+
+    // The code address a jump or call transfers control to
+    pub fn rvm_jump_target_mut(&mut self) -> Option<&mut RvmArg> {
+        use RvmInstr::*;
+        match self {
+            Jmp(t) | Call(t) | Je(t) | Jne(t) | Jg(t) | Jge(t) | Jl(t) | Jle(t)
+            | Loop(t) | Loopz(t) | Loopnz(t) | CmpJcc(_, _, _, t) | IncCmpJcc(_, _, _, t) => Some(t),
+            _ => None,
+        }
+    }
+
+    pub fn rvm_jump_target(&self) -> Option<RvmArg> {
+        let mut instr = *self;
+        instr.rvm_jump_target_mut().copied()
+    }
+
+    // The instructions a superinstruction replaced, padded with nops; just itself otherwise
+    pub fn rvm_unfused(&self) -> ([RvmInstr; 3], usize) {
+        use RvmInstr::*;
+        let jump = |cond: RvmJumpCond, t| match cond {
+            RvmJumpCond::E => Je(t),
+            RvmJumpCond::Ne => Jne(t),
+            RvmJumpCond::G => Jg(t),
+            RvmJumpCond::Ge => Jge(t),
+            RvmJumpCond::L => Jl(t),
+            RvmJumpCond::Le => Jle(t),
+        };
+        match *self {
+            CmpJcc(cond, a, b, t) => ([Cmp(a, b), jump(cond, t), Nop], 2),
+            IncCmpJcc(cond, a, b, t) => ([Inc(a), Cmp(a, b), jump(cond, t)], 3),
+            PushPop(src, dest) => ([Push(src), Pop(dest), Nop], 2),
+            instr => ([instr, Nop, Nop], 1),
+        }
+    }
+
+    // Whether control can leave this instruction other than by falling through
+    pub fn rvm_ends_block(&self) -> bool {
+        self.rvm_jump_target().is_some() || self.rvm_is_return()
+    }
+
+    pub fn rvm_is_return(&self) -> bool {
+        matches!(self, RvmInstr::Ret | RvmInstr::RetN(_))
+    }
+
+    pub fn rvm_is_cond_jump(&self) -> bool {
+        use RvmInstr::*;
+        matches!(self, Je(_) | Jne(_) | Jg(_) | Jge(_) | Jl(_) | Jle(_) | Loop(_) | Loopz(_) | Loopnz(_))
+    }
+
+    pub fn rvm_is_loop(&self) -> bool {
+        matches!(self, RvmInstr::Loop(_) | RvmInstr::Loopz(_) | RvmInstr::Loopnz(_))
+    }
+}
+
+impl RvmInstr {
+    // The instruction as written in a program of the given mode
+    pub fn rvm_instr_text(&self, mode: RvmMode) -> String {
+        let args: Vec<String> = self.rvm_args().iter().map(|a| a.rvm_arg_text(mode)).collect();
+        if args.is_empty() {
+            self.rvm_mnemonic()
+        } else {
+            format!("{} {}", self.rvm_mnemonic(), args.join(", "))
+        }
+    }
+}
+
+impl fmt::Display for RvmInstr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.rvm_instr_text(RvmMode::Bits32))
+    }
+}
+
+/* Where an instruction came from: an index into `RvmProg::files` and a
+ * 1-based line number */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RvmSrcLoc {
+    pub file: usize,
+    pub line: u32,
+}
 
 pub struct RvmProg {
+    pub mode: RvmMode,
     pub start: i32,
-    pub instructions: Vec<i32>,
-    pub args: Vec<Vec<i32>>,
-    pub values: Vec<i32>,
+    pub code: Vec<RvmInstr>,
+    pub locs: Vec<RvmSrcLoc>,
+    pub files: Vec<String>,
     pub defines: RvmHtabCtx,
-    pub labels: RvmHtabCtx
+    pub labels: RvmHtabCtx,
+    /* Set when a code label is an operand other than a jump target, so
+     * the program computes with instruction indices */
+    pub label_values: bool,
+    /* What the data sections hold, laid out from RVM_DATA_BASE, and how
+     * many zeroed bytes of .bss follow it */
+    pub data: Vec<u8>,
+    pub bss_size: usize,
+    /* Labels in the data sections, by memory address rather than
+     * instruction index */
+    pub data_labels: RvmHtabCtx
 }
 
 impl RvmProg {
     pub fn new() -> Self {
         Self {
+            mode: RvmMode::Bits32,
             start: 0,
-            instructions: Vec::new(),
-            args: Vec::new(),
-            values: Vec::new(),
+            code: Vec::new(),
+            locs: Vec::new(),
+            files: Vec::new(),
             defines: RvmHtabCtx::new(),
-            labels: RvmHtabCtx::new()
+            labels: RvmHtabCtx::new(),
+            label_values: false,
+            data: Vec::new(),
+            bss_size: 0,
+            data_labels: RvmHtabCtx::new()
+        }
+    }
+
+    /* FNV-1a hash of the mode, the instructions, the entry point and the
+     * data sections, the same on every machine and unchanged by
+     * superinstruction fusion */
+    pub fn rvm_prog_hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut feed = |bytes: &[u8]| {
+            for byte in bytes {
+                hash = (hash ^ *byte as u64).wrapping_mul(0x100000001b3);
+            }
+        };
+        // 32-bit programs hash as they did before there was a mode
+        if self.mode == RvmMode::Bits64 {
+            feed(b"%bits 64\n");
+        }
+        feed(&self.start.to_le_bytes());
+        for instr in &self.code {
+            // A superinstruction took the place of its first part
+            feed(format!("{}\n", instr.rvm_unfused().0[0]).as_bytes());
+        }
+        // Programs without data hash as they did before there were data sections
+        if !self.data.is_empty() || self.bss_size != 0 {
+            feed(b"section .data\n");
+            feed(&self.data);
+            feed(&(self.bss_size as u64).to_le_bytes());
         }
+        hash
     }
-}
\ No newline at end of file
+
+    // Write the program back out as assembly, naming jump targets by their labels
+    pub fn rvm_prog_listing(&self, out: &mut dyn Write) -> io::Result<()> {
+        let labels = self.rvm_labels_sorted();
+        let label_of = |idx: i32| labels.iter().find(|(label_idx, _)| *label_idx == idx).map(|(_, name)| name);
+        let mut next_label = labels.iter().peekable();
+        if self.mode == RvmMode::Bits64 {
+            writeln!(out, "%bits 64")?;
+        }
+        for (idx, instr) in self.code.iter().enumerate() {
+            while let Some((_, name)) = next_label.next_if(|(label_idx, _)| *label_idx <= idx as i32) {
+                writeln!(out, "{}:", name)?;
+            }
+            if *instr == RvmInstr::Halt {
+                break;
+            }
+            let mut args: Vec<String> = instr.rvm_args().iter().map(|a| a.rvm_arg_text(self.mode)).collect();
+            if let Some(RvmArg::Val(target)) = instr.rvm_jump_target()
+                && let Some(name) = label_of(target as i32) {
+                *args.last_mut().unwrap() = name.clone();
+            }
+            if args.is_empty() {
+                writeln!(out, "    {}", instr.rvm_mnemonic())?;
+            } else {
+                writeln!(out, "    {} {}", instr.rvm_mnemonic(), args.join(", "))?;
+            }
+        }
+        self.rvm_data_listing(out)
+    }
+
+    /* The data sections as `db` lines, broken at each label and with long
+     * runs of zeros folded into `times`, followed by .bss as `resb` */
+    fn rvm_data_listing(&self, out: &mut dyn Write) -> io::Result<()> {
+        let end = self.data.len() + self.bss_size;
+        let mut labels: Vec<(usize, String)> = self.data_labels.rvm_htab_entries()
+            .into_iter()
+            .map(|(name, addr)| (addr as usize - RVM_DATA_BASE, name))
+            .collect();
+        labels.sort();
+        let mut next_label = labels.iter().peekable();
+        let mut offset = 0;
+        if !self.data.is_empty() {
+            writeln!(out, "section .data")?;
+        }
+        while offset < end {
+            if offset == self.data.len() {
+                writeln!(out, "section .bss")?;
+            }
+            while let Some((_, name)) = next_label.next_if(|(label_offset, _)| *label_offset <= offset) {
+                writeln!(out, "{}:", name)?;
+            }
+            let section_end = if offset < self.data.len() { self.data.len() } else { end };
+            let until = next_label.peek().map_or(section_end, |(label_offset, _)| (*label_offset).min(section_end));
+            if offset >= self.data.len() {
+                writeln!(out, "    resb {}", until - offset)?;
+                offset = until;
+                continue;
+            }
+            let zeros_at = |i: usize| self.data[i..until].iter().take_while(|b| **b == 0).count();
+            while offset < until {
+                let zeros = zeros_at(offset);
+                if zeros >= 16 {
+                    writeln!(out, "    times {} db 0", zeros)?;
+                    offset += zeros;
+                    continue;
+                }
+                let row = (offset + 1..until.min(offset + 16)).find(|i| zeros_at(*i) >= 16).unwrap_or(until.min(offset + 16));
+                let bytes: Vec<String> = self.data[offset..row].iter().map(u8::to_string).collect();
+                writeln!(out, "    db {}", bytes.join(", "))?;
+                offset = row;
+            }
+        }
+        for (_, name) in next_label {
+            writeln!(out, "{}:", name)?;
+        }
+        Ok(())
+    }
+
+    // Which instructions start a basic block: the entry point, labels,
+    // jump targets and whatever follows a jump or ret
+    pub fn rvm_block_leaders(&self) -> Vec<bool> {
+        let mut leaders = vec![false; self.code.len()];
+        let mut mark = |idx: i32| {
+            if let Some(leader) = leaders.get_mut(idx as usize) {
+                *leader = true;
+            }
+        };
+        mark(self.start);
+        for (_, idx) in self.labels.rvm_htab_entries() {
+            mark(idx);
+        }
+        for (idx, instr) in self.code.iter().enumerate() {
+            if let Some(RvmArg::Val(target)) = instr.rvm_jump_target() {
+                mark(target as i32);
+            }
+            if instr.rvm_ends_block() {
+                mark(idx as i32 + 1);
+            }
+        }
+        leaders
+    }
+
+    // Labels ordered by instruction index, then by name
+    pub fn rvm_labels_sorted(&self) -> Vec<(i32, String)> {
+        let mut labels: Vec<(i32, String)> = self.labels.rvm_htab_entries()
+            .into_iter()
+            .map(|(name, idx)| (idx, name))
+            .collect();
+        labels.sort();
+        labels
+    }
+}
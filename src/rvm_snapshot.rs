@@ -0,0 +1,183 @@
+/* Snapshots of a running VM, to resume later or elsewhere.
+ *
+ * A snapshot holds everything rvm_vm_run needs to carry on: the
+ * registers (eip included) with their tags, the float registers, flags,
+ * remainder, exit code,
+ * queued input and the contents of memory. Memory is stored as runs of
+ * 4 KiB pages that are not all zero. The program itself is not stored;
+ * its hash is, and a snapshot is only restored into a VM that loaded
+ * the same program. Gas budgets belong to the host and are not saved.
+ *
+ * The format starts with the magic "RVMS" and a version byte, followed
+ * by little-endian fields:
+ *
+ *   u64 program hash,
+ *   17 * (u8 tag [0 integer, 1 address], i64 value),
+ *   16 * f64,
+ *   u32 flags, i64 remainder, i32 exit code,
+ *   u32 ninput, ninput * i32,
+ *   u64 memory size,
+ *   u32 nruns, nruns * (u64 addr, u64 len, len bytes)
+ */
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use crate::rvm::{RvmCtx, RvmRegisterMap};
+use crate::rvm_memory::RvmRegU;
+
+const RVM_SNAPSHOT_MAGIC: &[u8; 4] = b"RVMS";
+const RVM_SNAPSHOT_VERSION: u8 = 3;
+const RVM_SNAPSHOT_PAGE: usize = 4096;
+
+fn rvm_invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Byte ranges of `mem` made of whole pages that are not all zero, adjacent pages merged
+fn rvm_snapshot_runs(mem: &[u8]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (page, bytes) in mem.chunks(RVM_SNAPSHOT_PAGE).enumerate() {
+        if bytes.iter().all(|byte| *byte == 0) {
+            continue;
+        }
+        let addr = page * RVM_SNAPSHOT_PAGE;
+        match runs.last_mut() {
+            Some((start, len)) if *start + *len == addr => *len += bytes.len(),
+            _ => runs.push((addr, bytes.len())),
+        }
+    }
+    runs
+}
+
+pub fn rvm_snapshot_save(vm: &RvmCtx, out: &mut dyn Write) -> io::Result<()> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(RVM_SNAPSHOT_MAGIC);
+    buf.push(RVM_SNAPSHOT_VERSION);
+    buf.extend_from_slice(&vm.prog.rvm_prog_hash().to_le_bytes());
+    for reg in &vm.mem.registers {
+        let tag: u8 = match reg {
+            RvmRegU::I64(_) => 0,
+            RvmRegU::I64ADDR(_) => 1,
+        };
+        buf.push(tag);
+        buf.extend_from_slice(&reg.rvm_reg_value().to_le_bytes());
+    }
+    for val in &vm.mem.fregs {
+        buf.extend_from_slice(&val.to_le_bytes());
+    }
+    buf.extend_from_slice(&vm.mem.flags.to_le_bytes());
+    buf.extend_from_slice(&vm.mem.remainder.to_le_bytes());
+    buf.extend_from_slice(&vm.exit_code.to_le_bytes());
+    buf.extend_from_slice(&(vm.input.len() as u32).to_le_bytes());
+    for val in &vm.input {
+        buf.extend_from_slice(&val.to_le_bytes());
+    }
+    out.write_all(&buf)?;
+
+    let mem = &vm.mem.mem_space;
+    let runs = rvm_snapshot_runs(mem);
+    out.write_all(&(mem.len() as u64).to_le_bytes())?;
+    out.write_all(&(runs.len() as u32).to_le_bytes())?;
+    for (addr, len) in runs {
+        out.write_all(&(addr as u64).to_le_bytes())?;
+        out.write_all(&(len as u64).to_le_bytes())?;
+        out.write_all(&mem[addr..addr + len])?;
+    }
+    out.flush()
+}
+
+struct RvmSnapshotReader<'a> {
+    input: &'a mut dyn Read,
+}
+
+impl RvmSnapshotReader<'_> {
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.input.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.bytes()?))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes()?))
+    }
+
+    fn f64(&mut self) -> io::Result<f64> {
+        Ok(f64::from_le_bytes(self.bytes()?))
+    }
+}
+
+/* Replace `vm`'s state with a snapshot of the same program. Nothing is
+ * changed unless the whole snapshot could be read. */
+pub fn rvm_snapshot_restore(vm: &mut RvmCtx, input: &mut dyn Read) -> io::Result<()> {
+    let mut r = RvmSnapshotReader { input };
+    if &r.bytes::<4>()? != RVM_SNAPSHOT_MAGIC {
+        return Err(rvm_invalid("not a snapshot"));
+    }
+    if r.bytes::<1>()?[0] != RVM_SNAPSHOT_VERSION {
+        return Err(rvm_invalid("unsupported snapshot version"));
+    }
+    if r.u64()? != vm.prog.rvm_prog_hash() {
+        return Err(rvm_invalid("snapshot was taken from a different program"));
+    }
+
+    let mut registers = Vec::with_capacity(RvmRegisterMap.len());
+    for _ in 0..RvmRegisterMap.len() {
+        let tag = r.bytes::<1>()?[0];
+        let val = r.i64()?;
+        registers.push(match tag {
+            0 => RvmRegU::I64(val),
+            1 => RvmRegU::I64ADDR(val),
+            _ => return Err(rvm_invalid("invalid register tag")),
+        });
+    }
+    let eip = registers[0x8].rvm_reg_value();
+    if eip < 0 || eip as usize >= vm.prog.code.len() {
+        return Err(rvm_invalid("instruction pointer outside the program"));
+    }
+    let fregs = (0..vm.mem.fregs.len()).map(|_| r.f64()).collect::<io::Result<Vec<f64>>>()?;
+    let flags = r.u32()?;
+    let remainder = r.i64()?;
+    let exit_code = r.i32()?;
+    let ninput = r.u32()?;
+    let pending = (0..ninput).map(|_| r.i32()).collect::<io::Result<VecDeque<i32>>>()?;
+
+    if r.u64()? != vm.mem.mem_space.len() as u64 {
+        return Err(rvm_invalid("snapshot has a different memory size"));
+    }
+    let nruns = r.u32()?;
+    let mut runs = Vec::new();
+    for _ in 0..nruns {
+        let addr = r.u64()? as usize;
+        let len = r.u64()? as usize;
+        if addr.checked_add(len).is_none_or(|end| end > vm.mem.mem_space.len()) {
+            return Err(rvm_invalid("memory run outside memory"));
+        }
+        let mut bytes = vec![0; len];
+        r.input.read_exact(&mut bytes)?;
+        runs.push((addr, bytes));
+    }
+
+    vm.mem.registers = registers;
+    vm.mem.fregs = fregs;
+    vm.mem.flags = flags;
+    vm.mem.remainder = remainder;
+    vm.exit_code = exit_code;
+    vm.input = pending;
+    vm.mem.mem_space.fill(0);
+    for (addr, bytes) in runs {
+        vm.mem.mem_space[addr..addr + bytes.len()].copy_from_slice(&bytes);
+    }
+    Ok(())
+}
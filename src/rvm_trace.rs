@@ -0,0 +1,185 @@
+/* Execution traces, one record per executed instruction.
+ *
+ * The JSON Lines format writes one object per line:
+ *
+ *   {"n":4,"idx":2,"op":"inc","args":["eax"],"regs":{"eax":1},"flags":0,"mem":[]}
+ *
+ * where `n` counts executed instructions from 0, `idx` is the
+ * instruction index, `regs` holds the new value of every register the
+ * instruction changed and `mem` lists memory writes as
+ * {"addr":100,"bytes":"05000000"} with the bytes in memory order.
+ *
+ * The binary format starts with the magic "RVMT" and a version byte,
+ * followed by records of little-endian fields:
+ *
+ *   u64 n, u32 idx, u8 opcode,
+ *   u8 nargs, nargs * (u8 kind [0 reg, 1 mem, 2 value, 3 sub-register, 4 register-relative,
+ *                               5 float register, 6 float value],
+ *                     i64 operand),
+ *   u8 nregs, nregs * (u8 reg, i64 value),
+ *   u32 flags,
+ *   u16 nmem, nmem * (u32 addr, u8 len, len bytes)
+ *
+ * A sub-register operand holds the register in its low byte and the
+ * part in the next one: 0 for bits 0-15, 1 for bits 0-7, 2 for bits 8-15,
+ * 3 for bits 0-31. Values are sign-extended from 32 bits in 32-bit mode.
+ * A register-relative operand like [ebp-4] holds the register and is
+ * followed by one more i32, the displacement, and a float value holds 0
+ * and is followed by the f64. Writes to float registers are not recorded.
+ */
+use std::io::{self, BufWriter, Write};
+
+use crate::rvm::{RvmCtx, RvmHook};
+use crate::rvm_memory::RvmAccess;
+use crate::rvm_prog::RvmArg;
+
+const RVM_TRACE_MAGIC: &[u8; 4] = b"RVMT";
+const RVM_TRACE_VERSION: u8 = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RvmTraceFormat {
+    Json,
+    Binary,
+}
+
+pub struct RvmTracer {
+    out: BufWriter<Box<dyn Write>>,
+    format: RvmTraceFormat,
+    /* Only instructions with from <= idx < to are recorded */
+    from: i32,
+    to: i32,
+    executed: u64,
+    /* The first write error, after which tracing stops */
+    pub error: Option<io::Error>,
+}
+
+impl RvmTracer {
+    pub fn new(out: Box<dyn Write>, format: RvmTraceFormat, from: i32, to: i32) -> Self {
+        RvmTracer {
+            out: BufWriter::new(out),
+            format,
+            from,
+            to,
+            executed: 0,
+            error: None
+        }
+    }
+
+    pub fn rvm_trace_begin(&mut self) -> io::Result<()> {
+        if self.format == RvmTraceFormat::Binary {
+            self.out.write_all(RVM_TRACE_MAGIC)?;
+            self.out.write_all(&[RVM_TRACE_VERSION])?;
+        }
+        Ok(())
+    }
+
+    pub fn rvm_trace_finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+
+    // Registers the instruction left with a different value, in first-write order
+    fn register_deltas(vm: &RvmCtx, accesses: &[RvmAccess]) -> Vec<(usize, i64)> {
+        let mut deltas: Vec<(usize, i64)> = Vec::new();
+        for access in accesses {
+            if let RvmAccess::RegWrite { reg, old, .. } = *access
+                && !deltas.iter().any(|(r, _)| *r == reg) {
+                deltas.push((reg, old));
+            }
+        }
+        deltas.into_iter()
+            .map(|(reg, old)| (reg, old, vm.mem.registers[reg].rvm_reg_value()))
+            .filter(|(_, old, new)| old != new)
+            .map(|(reg, _, new)| (reg, new))
+            .collect()
+    }
+
+    fn memory_writes<'a>(vm: &'a RvmCtx, accesses: &[RvmAccess]) -> Vec<(usize, &'a [u8])> {
+        accesses.iter().filter_map(|a| match *a {
+            RvmAccess::MemWrite { addr, len, .. } => Some((addr, &vm.mem.mem_space[addr..addr + len])),
+            _ => None,
+        }).collect()
+    }
+
+    fn write_json(&mut self, vm: &RvmCtx, instr_idx: i32, accesses: &[RvmAccess]) -> io::Result<()> {
+        let instr = vm.prog.code[instr_idx as usize];
+        let args: Vec<String> = instr.rvm_args().iter().map(|a| format!("\"{}\"", a.rvm_arg_text(vm.prog.mode))).collect();
+        let regs: Vec<String> = Self::register_deltas(vm, accesses).iter()
+            .map(|(reg, val)| format!("\"{}\":{}", vm.prog.mode.rvm_reg_name(*reg), val))
+            .collect();
+        let mem: Vec<String> = Self::memory_writes(vm, accesses).iter()
+            .map(|(addr, bytes)| {
+                let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                format!("{{\"addr\":{},\"bytes\":\"{}\"}}", addr, hex)
+            })
+            .collect();
+        writeln!(
+            self.out,
+            "{{\"n\":{},\"idx\":{},\"op\":\"{}\",\"args\":[{}],\"regs\":{{{}}},\"flags\":{},\"mem\":[{}]}}",
+            self.executed, instr_idx, instr.rvm_mnemonic(),
+            args.join(","), regs.join(","), vm.mem.flags, mem.join(",")
+        )
+    }
+
+    fn write_binary(&mut self, vm: &RvmCtx, instr_idx: i32, accesses: &[RvmAccess]) -> io::Result<()> {
+        let mut rec = Vec::with_capacity(64);
+        rec.extend_from_slice(&self.executed.to_le_bytes());
+        rec.extend_from_slice(&(instr_idx as u32).to_le_bytes());
+        let instr = vm.prog.code[instr_idx as usize];
+        rec.push(instr.rvm_opcode() as u8);
+
+        let args = instr.rvm_args();
+        rec.push(args.len() as u8);
+        for arg in args {
+            let (kind, val) = match arg {
+                RvmArg::Reg(reg) => (0u8, reg as i64),
+                RvmArg::Mem(addr) => (1u8, addr as i64),
+                RvmArg::Val(val) => (2u8, val),
+                RvmArg::Sub(reg, part) => (3u8, reg as i64 | (part as i64) << 8),
+                RvmArg::Ptr(reg, _) => (4u8, reg as i64),
+                RvmArg::Freg(reg) => (5u8, reg as i64),
+                RvmArg::Fval(_) => (6u8, 0),
+            };
+            rec.push(kind);
+            rec.extend_from_slice(&val.to_le_bytes());
+            match arg {
+                RvmArg::Ptr(_, disp) => rec.extend_from_slice(&disp.to_le_bytes()),
+                RvmArg::Fval(val) => rec.extend_from_slice(&val.to_le_bytes()),
+                _ => {}
+            }
+        }
+
+        let regs = Self::register_deltas(vm, accesses);
+        rec.push(regs.len() as u8);
+        for (reg, val) in regs {
+            rec.push(reg as u8);
+            rec.extend_from_slice(&val.to_le_bytes());
+        }
+
+        rec.extend_from_slice(&vm.mem.flags.to_le_bytes());
+
+        let mem = Self::memory_writes(vm, accesses);
+        rec.extend_from_slice(&(mem.len() as u16).to_le_bytes());
+        for (addr, bytes) in mem {
+            rec.extend_from_slice(&(addr as u32).to_le_bytes());
+            rec.push(bytes.len() as u8);
+            rec.extend_from_slice(bytes);
+        }
+        self.out.write_all(&rec)
+    }
+}
+
+impl RvmHook for RvmTracer {
+    fn rvm_hook_step(&mut self, vm: &RvmCtx, instr_idx: i32, accesses: &[RvmAccess]) {
+        if self.error.is_none() && instr_idx >= self.from && instr_idx < self.to {
+            let res = match self.format {
+                RvmTraceFormat::Json => self.write_json(vm, instr_idx, accesses),
+                RvmTraceFormat::Binary => self.write_binary(vm, instr_idx, accesses),
+            };
+            self.error = res.err();
+        }
+        self.executed += 1;
+    }
+}
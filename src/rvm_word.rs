@@ -0,0 +1,122 @@
+use std::fmt;
+use std::ops::{BitAnd, BitOr, BitXor, Div, Not, Rem};
+
+use crate::rvm::{rvm_alu, rvm_alu64, RvmAluOp, RvmFault};
+
+/* The integer the interpreter computes with: i32 in the default 32-bit
+ * mode, i64 in 64-bit mode. Registers always hold 64 bits; a 32-bit
+ * program only ever sees their low half, kept sign-extended. Memory
+ * operands and stack slots are BYTES wide.
+ *
+ * The interpreter is generic over this, so each mode gets its own copy
+ * and the 32-bit one does no more work than before there were two. */
+pub trait RvmWord: Copy + PartialEq + PartialOrd + fmt::Display
+    + Not<Output = Self> + BitAnd<Output = Self> + BitOr<Output = Self> + BitXor<Output = Self>
+    + Div<Output = Self> + Rem<Output = Self> {
+    const BYTES: usize;
+    const BITS: u32;
+    const ZERO: Self;
+    const ONE: Self;
+
+    // Truncating from and sign-extending to 64 bits
+    fn rvm_from_i64(val: i64) -> Self;
+    fn rvm_to_i64(self) -> i64;
+
+    // Little-endian, BYTES long
+    fn rvm_from_le(bytes: &[u8]) -> Self;
+    fn rvm_write_le(self, bytes: &mut [u8]);
+
+    // The value as an unsigned address
+    fn rvm_addr(self) -> usize;
+
+    /* The value as an instruction index or interrupt number; one that
+     * does not fit becomes -1, which is never valid either */
+    fn rvm_narrow(self) -> i32;
+
+    fn rvm_wrapping_add(self, other: Self) -> Self;
+    fn rvm_wrapping_sub(self, other: Self) -> Self;
+
+    // rvm_alu at this size
+    fn rvm_alu(op: RvmAluOp, a: Self, b: Self, width: u32) -> (Self, Option<u32>);
+
+    // The fault `a / b` and `a % b` raise, if any
+    fn rvm_divide_fault(a: Self, b: Self) -> Option<RvmFault>;
+
+    // Unsigned division and remainder; the divisor is not zero
+    fn rvm_udiv(self, other: Self) -> Self;
+    fn rvm_urem(self, other: Self) -> Self;
+
+    fn rvm_to_f64(self) -> f64;
+    // Saturating, with NaN as 0
+    fn rvm_from_f64(val: f64) -> Self;
+}
+
+impl RvmWord for i32 {
+    const BYTES: usize = 4;
+    const BITS: u32 = 32;
+    const ZERO: Self = 0;
+    const ONE: Self = 1;
+
+    fn rvm_from_i64(val: i64) -> Self { val as i32 }
+    fn rvm_to_i64(self) -> i64 { self as i64 }
+
+    fn rvm_from_le(bytes: &[u8]) -> Self { i32::from_le_bytes(bytes.try_into().unwrap()) }
+    fn rvm_write_le(self, bytes: &mut [u8]) { bytes.copy_from_slice(&self.to_le_bytes()) }
+
+    fn rvm_addr(self) -> usize { self as u32 as usize }
+    fn rvm_narrow(self) -> i32 { self }
+
+    fn rvm_wrapping_add(self, other: Self) -> Self { self.wrapping_add(other) }
+    fn rvm_wrapping_sub(self, other: Self) -> Self { self.wrapping_sub(other) }
+
+    fn rvm_alu(op: RvmAluOp, a: Self, b: Self, width: u32) -> (Self, Option<u32>) { rvm_alu(op, a, b, width) }
+
+    fn rvm_divide_fault(a: Self, b: Self) -> Option<RvmFault> {
+        match (a, b) {
+            (_, 0) => Some(RvmFault::DivideByZero),
+            (i32::MIN, -1) => Some(RvmFault::DivideOverflow),
+            _ => None,
+        }
+    }
+
+    fn rvm_udiv(self, other: Self) -> Self { (self as u32 / other as u32) as i32 }
+    fn rvm_urem(self, other: Self) -> Self { (self as u32 % other as u32) as i32 }
+
+    fn rvm_to_f64(self) -> f64 { self as f64 }
+    fn rvm_from_f64(val: f64) -> Self { val as i32 }
+}
+
+impl RvmWord for i64 {
+    const BYTES: usize = 8;
+    const BITS: u32 = 64;
+    const ZERO: Self = 0;
+    const ONE: Self = 1;
+
+    fn rvm_from_i64(val: i64) -> Self { val }
+    fn rvm_to_i64(self) -> i64 { self }
+
+    fn rvm_from_le(bytes: &[u8]) -> Self { i64::from_le_bytes(bytes.try_into().unwrap()) }
+    fn rvm_write_le(self, bytes: &mut [u8]) { bytes.copy_from_slice(&self.to_le_bytes()) }
+
+    fn rvm_addr(self) -> usize { self as u64 as usize }
+    fn rvm_narrow(self) -> i32 { i32::try_from(self).unwrap_or(-1) }
+
+    fn rvm_wrapping_add(self, other: Self) -> Self { self.wrapping_add(other) }
+    fn rvm_wrapping_sub(self, other: Self) -> Self { self.wrapping_sub(other) }
+
+    fn rvm_alu(op: RvmAluOp, a: Self, b: Self, width: u32) -> (Self, Option<u32>) { rvm_alu64(op, a, b, width) }
+
+    fn rvm_divide_fault(a: Self, b: Self) -> Option<RvmFault> {
+        match (a, b) {
+            (_, 0) => Some(RvmFault::DivideByZero),
+            (i64::MIN, -1) => Some(RvmFault::DivideOverflow),
+            _ => None,
+        }
+    }
+
+    fn rvm_udiv(self, other: Self) -> Self { (self as u64 / other as u64) as i64 }
+    fn rvm_urem(self, other: Self) -> Self { (self as u64 % other as u64) as i64 }
+
+    fn rvm_to_f64(self) -> f64 { self as f64 }
+    fn rvm_from_f64(val: f64) -> Self { val as i64 }
+}
@@ -0,0 +1,132 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+fn interpret(program: &str) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rusty-vm"))
+        .args(["--no-fuse", program])
+        .output()
+        .unwrap()
+}
+
+// Translate `program` to Rust, build it with rustc and run the result
+fn compile_and_run(program: &str) -> Output {
+    let name = program.trim_end_matches(".vm").replace('/', "_");
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let source = dir.join(format!("{}.rs", name));
+    let binary = dir.join(format!("{}-aot", name));
+
+    let status = Command::new(env!("CARGO_BIN_EXE_rusty-vm"))
+        .arg("aot")
+        .arg("-o")
+        .arg(&source)
+        .arg(program)
+        .status()
+        .unwrap();
+    assert!(status.success(), "{}", program);
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let build = Command::new(rustc)
+        .arg("-O")
+        .arg("-o")
+        .arg(&binary)
+        .arg(&source)
+        .output()
+        .unwrap();
+    assert!(build.status.success(), "{}: {}", program, String::from_utf8_lossy(&build.stderr));
+
+    Command::new(&binary).output().unwrap()
+}
+
+// The translated program has to print exactly what the interpreter prints and exit the same way
+fn assert_same_as_interpreter(program: &str) {
+    let interpreted = interpret(program);
+    let compiled = compile_and_run(program);
+    assert_eq!(
+        String::from_utf8_lossy(&compiled.stdout),
+        String::from_utf8_lossy(&interpreted.stdout),
+        "{}",
+        program
+    );
+    assert_eq!(compiled.status.code(), interpreted.status.code(), "{}", program);
+}
+
+#[test]
+fn aot_arithmetic_and_memory() {
+    assert_same_as_interpreter("tests/programs/alu.vm");
+}
+
+#[test]
+fn aot_conditional_jumps() {
+    assert_same_as_interpreter("tests/programs/branches.vm");
+}
+
+#[test]
+fn aot_overflow_and_flags() {
+    assert_same_as_interpreter("tests/programs/overflow.vm");
+}
+
+#[test]
+fn aot_extended_instructions() {
+    assert_same_as_interpreter("tests/programs/isa.vm");
+}
+
+#[test]
+fn aot_stack_frames() {
+    assert_same_as_interpreter("tests/programs/frames.vm");
+}
+
+#[test]
+fn aot_sub_registers() {
+    assert_same_as_interpreter("tests/programs/subregs.vm");
+}
+
+#[test]
+fn aot_stack() {
+    assert_same_as_interpreter("tests/programs/stack.vm");
+}
+
+#[test]
+fn aot_loops() {
+    assert_same_as_interpreter("tests/programs/loop.vm");
+    assert_same_as_interpreter("benches/loop.vm");
+}
+
+#[test]
+fn aot_indirect_jumps() {
+    assert_same_as_interpreter("tests/programs/indirect.vm");
+    assert_same_as_interpreter("tests/programs/ptr_jump.vm");
+}
+
+#[test]
+fn aot_faults() {
+    assert_same_as_interpreter("tests/programs/div_zero.vm");
+    assert_same_as_interpreter("tests/programs/bad_jump.vm");
+    assert_same_as_interpreter("tests/programs/bad_address.vm");
+}
+
+#[test]
+fn aot_interrupts() {
+    assert_same_as_interpreter("tests/programs/interrupts.vm");
+}
+
+#[test]
+fn aot_loops_and_strings() {
+    assert_same_as_interpreter("tests/programs/strings.vm");
+}
+
+#[test]
+fn aot_floats() {
+    assert_same_as_interpreter("tests/programs/floats.vm");
+}
+
+#[test]
+fn aot_data_sections() {
+    assert_same_as_interpreter("tests/programs/data.vm");
+    assert_same_as_interpreter("tests/programs/literals.vm");
+}
+
+#[test]
+fn aot_number_literals() {
+    assert_same_as_interpreter("tests/programs/numbers.vm");
+}
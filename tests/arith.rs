@@ -0,0 +1,155 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// Run `source` as a program called `name`
+fn run(name: &str, source: &str) -> Output {
+    let program = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("arith_{}.vm", name));
+    fs::write(&program, source).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_rusty-vm"))
+        .arg(&program)
+        .output()
+        .unwrap()
+}
+
+// Values printed by `source`, which has to halt normally
+fn printed(name: &str, source: &str) -> Vec<i32> {
+    let output = run(name, source);
+    assert!(output.status.success(), "{}: {}", name, String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).lines().map(|line| line.parse().unwrap()).collect()
+}
+
+const CARRY: i32 = 0x4;
+const OVERFLOW: i32 = 0x8;
+
+// `instrs` after `setup`, printing the destination and the flags
+fn result_and_flags(name: &str, setup: &str, instrs: &str) -> (i32, i32) {
+    let source = format!("start:\n{}\n{}\npushf\npop ebx\nprn eax\nprn ebx\n", setup, instrs);
+    let values = printed(name, &source);
+    (values[0], values[1])
+}
+
+#[test]
+fn add_wraps_and_sets_overflow_and_carry() {
+    assert_eq!(result_and_flags("add_max", "mov eax, 2147483647", "add eax, 1"), (i32::MIN, OVERFLOW));
+    assert_eq!(result_and_flags("add_neg", "mov eax, -1", "add eax, 1"), (0, CARRY));
+    assert_eq!(result_and_flags("add_min", "mov eax, -2147483648", "add eax, -1"), (i32::MAX, CARRY | OVERFLOW));
+    assert_eq!(result_and_flags("add_plain", "mov eax, 2", "add eax, 3"), (5, 0));
+}
+
+#[test]
+fn sub_wraps_and_sets_borrow_and_overflow() {
+    assert_eq!(result_and_flags("sub_borrow", "mov eax, 0", "sub eax, 1"), (-1, CARRY));
+    assert_eq!(result_and_flags("sub_min", "mov eax, -2147483648", "sub eax, 1"), (i32::MAX, OVERFLOW));
+    assert_eq!(result_and_flags("sub_max", "mov eax, 2147483647", "sub eax, -1"), (i32::MIN, CARRY | OVERFLOW));
+}
+
+#[test]
+fn mul_wraps_and_flags_lost_bits() {
+    assert_eq!(result_and_flags("mul_big", "mov eax, 65536", "mul eax, 65536"), (0, CARRY | OVERFLOW));
+    assert_eq!(result_and_flags("mul_min", "mov eax, -2147483648", "mul eax, -1"), (i32::MIN, CARRY | OVERFLOW));
+    assert_eq!(result_and_flags("mul_neg", "mov eax, -65536", "mul eax, 32767"), (-2147418112, 0));
+}
+
+#[test]
+fn inc_and_dec_wrap_and_keep_carry() {
+    assert_eq!(result_and_flags("inc_max", "mov eax, 2147483647", "inc eax"), (i32::MIN, OVERFLOW));
+    assert_eq!(result_and_flags("dec_min", "mov eax, -2147483648", "dec eax"), (i32::MAX, OVERFLOW));
+    assert_eq!(result_and_flags("inc_carry", "mov eax, -1\nadd eax, 1", "inc eax"), (1, CARRY));
+}
+
+#[test]
+fn shift_counts_are_masked_to_five_bits() {
+    assert_eq!(result_and_flags("shl_33", "mov eax, 3", "shl eax, 33"), (6, 0));
+    assert_eq!(result_and_flags("shl_carry", "mov eax, -1073741824", "shl eax, 1"), (-2147483648, CARRY));
+    assert_eq!(result_and_flags("shl_sign", "mov eax, 1073741824", "shl eax, 1"), (i32::MIN, OVERFLOW));
+    assert_eq!(result_and_flags("shr_33", "mov eax, -8", "shr eax, 33"), (2147483644, OVERFLOW));
+    assert_eq!(result_and_flags("shr_carry", "mov eax, 5", "shr eax, 1"), (2, CARRY));
+    assert_eq!(result_and_flags("sar_33", "mov eax, -8", "sar eax, 33"), (-4, 0));
+    assert_eq!(result_and_flags("sar_carry", "mov eax, -3", "sar eax, 1"), (-2, CARRY));
+    assert_eq!(result_and_flags("shr_al", "mov eax, 80|h", "shr al, 7"), (1, OVERFLOW));
+    // A count of 0 after masking changes neither the value nor the flags
+    assert_eq!(result_and_flags("shl_32", "mov eax, -1\nadd eax, 1\nmov eax, 7", "shl eax, 32"), (7, CARRY));
+}
+
+#[test]
+fn rotations_go_round_at_the_operand_width() {
+    assert_eq!(result_and_flags("rol", "mov eax, -2147483647", "rol eax, 1"), (3, CARRY | OVERFLOW));
+    assert_eq!(result_and_flags("ror", "mov eax, 3", "ror eax, 1"), (i32::MIN + 1, CARRY | OVERFLOW));
+    assert_eq!(result_and_flags("rol_33", "mov eax, 1", "rol eax, 33"), (2, 0));
+    assert_eq!(result_and_flags("rol_al", "mov eax, 1281|h", "rol al, 12"), (0x1218, OVERFLOW));
+    assert_eq!(result_and_flags("ror_ax", "mov eax, 1|h", "ror ax, 4"), (0x1000, 0));
+    assert_eq!(result_and_flags("ror_0", "mov eax, -1\nadd eax, 1\nmov eax, 5", "ror eax, 32"), (5, CARRY));
+}
+
+#[test]
+fn neg_flags_nonzero_and_the_minimum() {
+    assert_eq!(result_and_flags("neg", "mov eax, 5", "neg eax"), (-5, CARRY));
+    assert_eq!(result_and_flags("neg_zero", "mov eax, 0", "neg eax"), (0, 0));
+    assert_eq!(result_and_flags("neg_min", "mov eax, -2147483648", "neg eax"), (i32::MIN, CARRY | OVERFLOW));
+    assert_eq!(result_and_flags("neg_al", "mov eax, 180|h", "neg al"), (0x180, CARRY | OVERFLOW));
+}
+
+#[test]
+fn unsigned_multiply_and_divide() {
+    assert_eq!(result_and_flags("umul", "mov eax, -1", "umul eax, 2"), (-2, CARRY | OVERFLOW));
+    assert_eq!(result_and_flags("umul_fits", "mov eax, 65535", "umul eax, 65537"), (-1, 0));
+    assert_eq!(result_and_flags("mul_signed", "mov eax, -1", "imul eax, 2"), (-2, 0));
+    let values = printed("udiv", "start:\nmov eax, -2\nudiv eax, 16\nprn eax\nmov eax, -2147483648\n\
+        udiv eax, -1\nprn eax\nmov eax, -1\numod eax, 10\nrem eax\nprn eax\nmov eax, -7\nidiv eax, 2\nprn eax\n");
+    assert_eq!(values, [0x0fffffff, 0, 5, -3]);
+    assert_faults("udiv_zero", "start:\nmov eax, 1\nudiv eax, 0\n", "division by zero");
+    assert_faults("umod_zero", "start:\nmov eax, 1\numod eax, 0\n", "division by zero");
+}
+
+#[test]
+fn test_sets_flags_like_cmp_against_zero() {
+    let source = "start:\nmov eax, 6\ntest eax, 1\npushf\npop ebx\nprn ebx\n\
+        test eax, 2\npushf\npop ebx\nprn ebx\nmov eax, -1\ntest eax, eax\npushf\npop ebx\nprn ebx\nprn eax\n";
+    assert_eq!(printed("test", source), [1, 2, 0, -1]);
+    let jump = "start:\nmov eax, 4\ntest eax, 3\nje zero\nprn 1\nzero:\nprn 0\n";
+    assert_eq!(printed("test_je", jump), [0]);
+}
+
+#[test]
+fn xchg_and_lea() {
+    let source = "start:\nmov eax, 1\nmov ebx, 2\nxchg eax, ebx\nprn eax\nprn ebx\n\
+        mov [64], 7\nxchg ecx, [64]\nprn ecx\nprn [64]\nlea edx, [128]\nprn edx\n";
+    assert_eq!(printed("xchg_lea", source), [2, 1, 7, 0, 128]);
+    let output = run("lea_reg", "start:\nlea eax, ebx\n");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("lea needs a memory operand"));
+    let output = run("xchg_imm", "start:\nxchg eax, 1\n");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("cannot store into an immediate"));
+}
+
+#[test]
+fn logic_ops_clear_carry_and_overflow() {
+    let setup = "mov eax, 2147483647\nadd eax, 1";
+    assert_eq!(result_and_flags("and", setup, "and eax, -1"), (i32::MIN, 0));
+    assert_eq!(result_and_flags("or", setup, "or eax, 0"), (i32::MIN, 0));
+    assert_eq!(result_and_flags("xor", setup, "xor eax, eax"), (0, 0));
+}
+
+#[test]
+fn sub_registers_wrap_at_their_width() {
+    assert_eq!(result_and_flags("al_carry", "mov eax, 12345678|h", "add al, 136"), (0x12345600, CARRY));
+    assert_eq!(result_and_flags("al_overflow", "mov eax, 127", "add al, 1"), (128, OVERFLOW));
+    assert_eq!(result_and_flags("ax_borrow", "mov eax, 10000|h", "sub ax, 1"), (0x1ffff, CARRY));
+}
+
+fn assert_faults(name: &str, source: &str, message: &str) {
+    let output = run(name, source);
+    assert_eq!(output.status.code(), Some(4), "{}", name);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(message), "{}: {}", name, stderr);
+}
+
+#[test]
+fn division_faults() {
+    assert_faults("div_zero", "start:\nmov eax, 1\ndiv eax, 0\n", "division by zero");
+    assert_faults("mod_zero", "start:\nmov eax, 1\nmod eax, 0\n", "division by zero");
+    assert_faults("div_min", "start:\nmov eax, -2147483648\ndiv eax, -1\n", "division overflow");
+    assert_faults("mod_min", "start:\nmov eax, -2147483648\nmod eax, -1\n", "division overflow");
+    let values = printed("div_neg", "start:\nmov eax, -7\nmod eax, 2\nrem ebx\ndiv eax, 2\nprn eax\nprn ebx\n");
+    assert_eq!(values, [-3, -1]);
+}
@@ -0,0 +1,56 @@
+/* Helpers shared by the integration tests, which run the rusty-vm binary
+ * on programs in tests/programs or written to the cargo temp dir. Each
+ * test crate uses only some of them. */
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+pub fn rusty_vm(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rusty-vm"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+// Like rusty_vm, with `input` on stdin
+pub fn rusty_vm_with_input(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rusty-vm"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// A file called `name` in the cargo temp dir
+pub fn tmp_path(name: &str) -> String {
+    PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name).to_str().unwrap().to_string()
+}
+
+// Write `source` to a program called `prefix_name` and return its path
+pub fn program(prefix: &str, name: &str, source: &str) -> String {
+    let path = tmp_path(&format!("{}_{}.vm", prefix, name));
+    fs::write(&path, source).unwrap();
+    path
+}
+
+// The lines a successful run printed
+pub fn printed(output: &Output) -> Vec<String> {
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8_lossy(&output.stdout).lines().map(str::to_string).collect()
+}
+
+// The numbers a successful run printed
+pub fn printed_ints(output: &Output) -> Vec<i32> {
+    printed(output).iter().map(|line| line.parse().unwrap()).collect()
+}
+
+pub fn all_output(output: &Output) -> String {
+    format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr))
+}
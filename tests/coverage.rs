@@ -0,0 +1,61 @@
+mod common;
+
+use std::fs;
+
+use common::{rusty_vm, tmp_path, program, printed};
+
+const PREFIX: &str = "coverage";
+
+// Run `path` under coverage, check what it printed and return the tracefile
+fn tracefile(name: &str, path: &str, expected: &[&str]) -> String {
+    let info = &tmp_path(name);
+    assert_eq!(printed(&rusty_vm(&["coverage", "-o", info, path])), expected);
+    fs::read_to_string(info).unwrap()
+}
+
+#[test]
+fn coverage_reports_lines_and_an_untaken_branch() {
+    assert_eq!(tracefile("coverage.info", "tests/programs/coverage.vm", &["1"]), [
+        "TN:",
+        "SF:tests/programs/coverage.vm",
+        // je on line 5: taken once, fell through never
+        "BRDA:5,0,0,1",
+        "BRDA:5,0,1,0",
+        "BRF:2",
+        "BRH:1",
+        "DA:3,1",
+        "DA:4,1",
+        "DA:5,1",
+        "DA:6,0",
+        "DA:8,1",
+        "LF:5",
+        "LH:4",
+        "end_of_record",
+        "",
+    ].join("\n"));
+}
+
+#[test]
+fn coverage_has_a_record_per_included_file() {
+    let lib = program(PREFIX, "lib", "double:\n    add eax, eax\n    jmp back\nunused:\n    prn 0\n");
+    let source = format!("%include {}\nstart:\n    mov eax, 2\n    jmp double\nback:\n    prn eax\n", lib);
+    let main = program(PREFIX, "main", &source);
+    let info = tracefile("coverage_include.info", &main, &["4"]);
+
+    let lib_record = info.split("end_of_record\n")
+        .find(|record| record.contains(&format!("SF:{}\n", lib)))
+        .unwrap_or_else(|| panic!("no record for {}: {}", lib, info));
+    for line in ["DA:2,1", "DA:3,1", "DA:5,0", "LF:3", "LH:2", "BRF:0"] {
+        assert!(lib_record.lines().any(|l| l == line), "{}: {}", line, lib_record);
+    }
+    assert!(info.contains(&format!("SF:{}\nBRF:0\nBRH:0\nDA:3,1\nDA:4,1\nDA:6,1\nLF:3\nLH:3\n", main)), "{}", info);
+}
+
+// Line markers are numbered from 1, like the lines they stand for
+#[test]
+fn line_markers_before_the_first_line_are_rejected() {
+    let marked = &program(PREFIX, "line0", "start:\n%line 0 x\nprn 1\n");
+    let output = rusty_vm(&["coverage", marked]);
+    assert!(!output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Error: line 2: invalid %line marker\n");
+}
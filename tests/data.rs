@@ -0,0 +1,57 @@
+mod common;
+
+use std::fs;
+
+use common::{rusty_vm, tmp_path, program, printed};
+
+const PREFIX: &str = "data";
+
+#[test]
+fn data_is_laid_out_before_the_program_runs() {
+    let expected = ["28", "100000", "-1", "2147483647", "65535", "-2", "8", "24", "9", "258", "4", "0", "707406378"];
+    assert_eq!(printed(&rusty_vm(&["tests/programs/data.vm"])), expected);
+}
+
+#[test]
+fn data_listing_round_trips() {
+    let listing = &tmp_path("data_listing.vm");
+    assert!(rusty_vm(&["asm", "-o", listing, "tests/programs/data.vm"]).status.success());
+    let text = fs::read_to_string(listing).unwrap();
+    for line in ["section .data", "primes:", "db 2, 3, 5, 7, 11, 0, 0, 0", "section .bss", "resb 16"] {
+        assert!(text.contains(line), "{}: {}", line, text);
+    }
+    assert_eq!(printed(&rusty_vm(&[listing])), printed(&rusty_vm(&["tests/programs/data.vm"])));
+
+    let zeros = program(PREFIX, "zeros", "section .data\nbig:\ntimes 1000 db 0\ndb 1\nsection .text\nstart:\nprn [big+1000]\n");
+    let text = String::from_utf8_lossy(&rusty_vm(&["asm", &zeros]).stdout).to_string();
+    assert!(text.contains("times 1000 db 0"), "{}", text);
+}
+
+#[test]
+fn misplaced_directives_are_refused() {
+    for (name, source, expected) in [
+        ("instr", "section .data\nadd eax, 1\n", "Error: line 2: instructions belong in .text"),
+        ("text", "start:\ndd 1\n", "Error: line 2: dd belongs in a data section"),
+        ("bss", "section .bss\ndb 1\n", "Error: line 2: db cannot initialize .bss"),
+        ("range", "section .data\ndw 65536\n", "Error: line 2: 65536 does not fit in dw"),
+        ("align", "section .data\nalign 0\n", "Error: line 2: cannot align to 0 bytes"),
+        ("section", "section .rodata\n", "Error: line 1: unknown section .rodata"),
+        ("size", "section .bss\ntimes 100000 resd 1000\n", "Error: line 2: data sections do not fit in memory"),
+        ("duplicate", "section .data\nx: db 1\nsection .text\nx:\n", "Error: Duplicate label found: x"),
+    ] {
+        let output = rusty_vm(&[&program(PREFIX, name, source)]);
+        assert!(!output.status.success(), "{}", name);
+        assert!(String::from_utf8_lossy(&output.stdout).contains(expected), "{}", name);
+    }
+}
+
+#[test]
+fn snapshot_of_other_data_is_refused() {
+    let first = program(PREFIX, "first", "section .data\nx: dd 1\nsection .text\nstart:\nprn [x]\nprn [x]\n");
+    let second = program(PREFIX, "second", "section .data\nx: dd 2\nsection .text\nstart:\nprn [x]\nprn [x]\n");
+    let snapshot = &tmp_path("data.snap");
+
+    assert_eq!(rusty_vm(&["--gas", "1", "--save", snapshot, &first]).status.code(), Some(2));
+    assert!(!rusty_vm(&["--restore", snapshot, &second]).status.success());
+    assert_eq!(printed(&rusty_vm(&["--restore", snapshot, &first])), ["1"]);
+}
@@ -0,0 +1,157 @@
+mod common;
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use common::program;
+
+const PREFIX: &str = "debugger";
+
+// What the debugger printed running `script` against `source`, prompts dropped
+fn debug(name: &str, source: &str, script: &str) -> String {
+    let mut debugger = Command::new(env!("CARGO_BIN_EXE_rusty-vm"))
+        .args(["debug", &program(PREFIX, name, source)])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    debugger.stdin.take().unwrap().write_all(script.as_bytes()).unwrap();
+    let output = debugger.wait_with_output().unwrap();
+    String::from_utf8_lossy(&output.stdout).replace("(rvm) ", "")
+}
+
+// Stepping back over `int 2` puts the value back for the next read, and refunds the gas
+#[test]
+fn reverse_step_restores_input_and_gas() {
+    let source = "start:\nint 2\nadd eax, 5\nprn eax\nint 0\n";
+    let script = "input 37\ngas 100\nstep\nstep\ngas\nrs\nrs\ngas\ncontinue\nquit\n";
+    let output = debug("input", source, script);
+    let expected = "eip = 1: add eax, 5\n\
+        eip = 2: prn eax\n\
+        98 gas left, 2 used by 2 instructions\n\
+        eip = 1: add eax, 5\n\
+        eip = 0: int 2\n\
+        100 gas left, 0 used by 0 instructions\n\
+        42\n\
+        Program halted with exit code 42\n";
+    assert_eq!(output, expected);
+}
+
+// reverse-continue with a location stops at the instruction that last wrote it
+#[test]
+fn reverse_continue_stops_at_the_last_write() {
+    let source = "start:\nmov ecx, 1\nmov [100], 7\nmov ecx, 2\nmov eax, 3\nmov [104], 9\nprn ecx\n";
+    let output = debug("last_write", source, "continue\nrc ecx\nrc [100]\nrc [104] 4\n");
+    let expected = "2\n\
+        Program halted\n\
+        Last write to ecx\n\
+        eip = 2: mov ecx, 2\n\
+        Last write to [100..104]\n\
+        eip = 1: mov [100], 7\n\
+        No more reverse-execution history\n\
+        eip = 0: mov ecx, 1\n";
+    assert_eq!(output, expected);
+}
+
+/* Only the last 100000 instructions are kept: 1 + 3 * 60000 + 1 ran, so
+ * history starts at the inc of the 26668th pass */
+#[test]
+fn reverse_execution_history_drops_the_oldest_instructions() {
+    let source = "start:\nmov ecx, 0\nl:\ninc ecx\ncmp ecx, 60000\njl l\nprn ecx\n";
+    let output = debug("ring", source, "continue\nrc\nrs\nwatch ecx\nstep\n");
+    let expected = "60000\n\
+        Program halted\n\
+        No more reverse-execution history\n\
+        eip = 1: inc ecx\n\
+        No more reverse-execution history\n\
+        eip = 1: inc ecx\n\
+        Watchpoint 1: ecx\n\
+        Watchpoint 1: ecx: ecx 26667 -> 26668\n\
+        eip = 2: cmp ecx, 60000\n";
+    assert_eq!(output, expected);
+}
+
+#[test]
+fn register_watchpoints_stop_on_a_change() {
+    let output = debug("reg", "start:\nmov ecx, 0\nmov eax, 1\nmov ecx, 7\nprn ecx\n", "watch ecx\ncontinue\ncontinue\n");
+    let expected = "Watchpoint 1: ecx\n\
+        Watchpoint 1: ecx: ecx 0 -> 7\n\
+        eip = 3: prn ecx\n\
+        7\n\
+        Program halted\n";
+    assert_eq!(output, expected);
+}
+
+// A write watchpoint catches writes overlapping its range; a read one only reads
+#[test]
+fn memory_watchpoints_stop_on_writes_and_reads() {
+    let source = "start:\nmov [100], 5\nmov eax, [100]\nmov [102], 1\nmov ebx, [200]\nprn eax\n";
+    let output = debug("mem", source, "watch [100] 4\nrwatch [100]\ncontinue\ncontinue\ncontinue\ncontinue\n");
+    let expected = "Watchpoint 1: write [100..104]\n\
+        Watchpoint 2: read [100..104]\n\
+        Watchpoint 1: write [100..104]: write at 100, value 5\n\
+        eip = 1: mov eax, [100]\n\
+        Watchpoint 2: read [100..104]: read at 100\n\
+        eip = 2: mov [102], 1\n\
+        Watchpoint 1: write [100..104]: write at 102, value 1\n\
+        eip = 3: mov ebx, [200]\n\
+        5\n\
+        Program halted\n";
+    assert_eq!(output, expected);
+}
+
+// A faulting instruction is not paid for, and refills stop at the largest budget
+#[test]
+fn faults_cost_no_gas() {
+    let script = "gas 10\ncontinue\ngas\ngas 18446744073709551615\ngas\n";
+    let output = debug("fault", "start:\nmov eax, 1\ndiv eax, 0\nprn eax\n", script);
+    let expected = "Fault: division by zero\n\
+        eip = 1: div eax, 0\n\
+        9 gas left, 1 used by 1 instructions\n\
+        18446744073709551615 gas left, 1 used by 1 instructions\n";
+    assert_eq!(output, expected);
+}
+
+// Memory ranges have to be non-empty and end inside memory
+#[test]
+fn memory_ranges_outside_memory_are_rejected() {
+    let script = "watch [100] -1\nwatch [100] 0\nawatch [67108862]\nrc [67108860] 8\nwatch [67108860]\ncontinue\n";
+    let output = debug("range", "start:\nprn 1\n", script);
+    let expected = "Invalid length: -1\n\
+        Invalid length: 0\n\
+        Range [67108862] 4 is outside memory\n\
+        Range [67108860] 8 is outside memory\n\
+        Watchpoint 1: write [67108860..67108864]\n\
+        1\n\
+        Program halted\n";
+    assert_eq!(output, expected);
+}
+
+// A condition stops the program when it becomes true, not while it stays true
+#[test]
+fn condition_watchpoints_stop_when_they_become_true() {
+    let output = debug("cond", "start:\nmov eax, 0\nloop:\ninc eax\nprn eax\ncmp eax, 5\njl loop\n", "watch eax > 3\ncontinue\ncontinue\n");
+    let expected = "Watchpoint 1: eax > 3\n\
+        1\n2\n3\n\
+        Watchpoint 1: eax > 3: eax = 4\n\
+        eip = 2: prn eax\n\
+        4\n5\n\
+        Program halted\n";
+    assert_eq!(output, expected);
+}
+
+// Unfused, every instruction costs one, and adding gas lets the program carry on
+#[test]
+fn gas_refills_resume_a_stopped_program() {
+    let source = "start:\nmov ecx, 3\nloop:\nprn ecx\ndec ecx\ncmp ecx, 0\njg loop\n";
+    let output = debug("gas", source, "gas 6\ncontinue\ngas\ngas 10\ncontinue\ngas\n");
+    let expected = "3\n\
+        2\n\
+        Out of gas\n\
+        eip = 2: dec ecx\n\
+        0 gas left, 6 used by 6 instructions\n\
+        1\n\
+        Program halted\n\
+        3 gas left, 13 used by 13 instructions\n";
+    assert_eq!(output, expected);
+}
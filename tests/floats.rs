@@ -0,0 +1,51 @@
+mod common;
+
+use common::{rusty_vm, tmp_path, program, printed};
+
+const PREFIX: &str = "floats";
+
+#[test]
+fn float_arithmetic_and_conversions() {
+    let expected = [
+        "1.4142135623730951", "28.274333882308138", "28", "2.9289682539682538", "2.154434690031884",
+        "2", "4", "NaN", "-2147483648", "-2", "-7.5",
+    ];
+    assert_eq!(printed(&rusty_vm(&["tests/programs/floats.vm"])), expected);
+}
+
+#[test]
+fn operands_must_match_the_instruction() {
+    for (name, source) in [
+        ("mov", "start:\nmov eax, f0\n"),
+        ("add", "start:\nadd eax, 1.5\n"),
+        ("fadd", "start:\nfadd eax, 1.0\n"),
+        ("fst", "start:\nfst f0, f1\n"),
+        ("itof", "start:\nitof f0, f1\n"),
+    ] {
+        let output = rusty_vm(&[&program(PREFIX, name, source)]);
+        assert!(!output.status.success());
+        let expected = format!("Error: line 2: wrong operand types for {}", name);
+        assert!(String::from_utf8_lossy(&output.stdout).contains(&expected), "{}", name);
+    }
+}
+
+#[test]
+fn float_registers_survive_a_snapshot() {
+    let source = "start:\nfld f3, 0.1\nfadd f3, 0.2\nmov eax, 1\nmov eax, 2\nfprn f3\n";
+    let path = program(PREFIX, "snapshot", source);
+    let snapshot = &tmp_path("floats.snap");
+
+    let part = rusty_vm(&["--gas", "3", "--save", snapshot, &path]);
+    assert_eq!(part.status.code(), Some(2));
+    assert_eq!(printed(&rusty_vm(&["--restore", snapshot, &path])), ["0.30000000000000004"]);
+}
+
+#[test]
+fn float_literals_round_trip() {
+    let source = "start:\nfld f0, 1.5\nfmul f15, -2e-3\nfcmp f1, [ebp-8]\nfprn 100.0\n";
+    let output = rusty_vm(&["asm", &program(PREFIX, "asm", source)]);
+    let listing = String::from_utf8_lossy(&output.stdout);
+    for line in ["fld f0, 1.5", "fmul f15, -0.002", "fcmp f1, [ebp-8]", "fprn 100.0"] {
+        assert!(listing.contains(line), "{}: {}", line, listing);
+    }
+}
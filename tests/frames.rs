@@ -0,0 +1,38 @@
+mod common;
+
+use common::{rusty_vm, program, printed_ints};
+
+const PREFIX: &str = "frames";
+
+#[test]
+fn recursive_functions_with_locals() {
+    let expected = [3628800, 144, 2097152, 2097152, 2097144, 0];
+    assert_eq!(printed_ints(&rusty_vm(&["tests/programs/frames.vm"])), expected);
+    assert_eq!(printed_ints(&rusty_vm(&["--no-fuse", "tests/programs/frames.vm"])), expected);
+}
+
+#[test]
+fn ret_continues_after_the_call() {
+    let source = "start:\ncall f\nprn 2\ncall f\nprn 4\njmp end\nf:\nprn 1\nret\nend:\n";
+    assert_eq!(printed_ints(&rusty_vm(&[&program(PREFIX, "call", source)])), [1, 2, 1, 4]);
+}
+
+#[test]
+fn enter_leave_and_ret_n_restore_the_stack() {
+    let source = "start:\nmov ebp, 77\npush 1\npush 2\ncall f\nprn eax\nprn esp\nprn ebp\njmp end\n\
+        f:\nenter 8\nmov [ebp-8], 5\nmov eax, [ebp+8]\nadd eax, [ebp+12]\nadd eax, [ebp-8]\nprn esp\nprn ebp\n\
+        leave\nret 8\nend:\n";
+    // The frame: two arguments, the return address, the saved ebp and 8 bytes of locals
+    let top = 2 * 1024 * 1024;
+    assert_eq!(printed_ints(&rusty_vm(&[&program(PREFIX, "enter", source)])), [top - 24, top - 16, 8, top, 77]);
+}
+
+#[test]
+fn register_relative_operands_round_trip() {
+    let source = "start:\nmov eax, [ebp-4]\nmov [esp+8], eax\nlea ebx, [ebp]\n";
+    let output = rusty_vm(&["asm", &program(PREFIX, "asm", source)]);
+    let listing = String::from_utf8_lossy(&output.stdout);
+    assert!(listing.contains("mov eax, [ebp-4]"), "{}", listing);
+    assert!(listing.contains("mov [esp+8], eax"), "{}", listing);
+    assert!(listing.contains("lea ebx, [ebp]"), "{}", listing);
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use std::fs;
+use std::process::Output;
+
+use common::{rusty_vm, tmp_path, program, all_output};
+
+const PREFIX: &str = "fuse";
+
+// What a run printed and how it exited, fused and with --no-fuse
+fn both_ways(args: &[&str]) -> (String, String) {
+    let describe = |output: Output| format!("{}exit {:?}", all_output(&output), output.status.code());
+    let unfused: Vec<&str> = ["--no-fuse"].iter().chain(args).copied().collect();
+    (describe(rusty_vm(args)), describe(rusty_vm(&unfused)))
+}
+
+fn assert_same(args: &[&str]) {
+    let (fused, unfused) = both_ways(args);
+    assert_eq!(fused, unfused, "{:?}", args);
+}
+
+#[test]
+fn fused_programs_run_like_unfused_ones() {
+    for name in ["loop", "jumps", "branches", "stack", "frames", "strings", "alu", "overflow", "indirect",
+                 "bad_jump", "bad_address", "div_zero"] {
+        assert_same(&[&format!("tests/programs/{}.vm", name)]);
+    }
+}
+
+/* Computed jumps bypass the label check, landing on the jcc of a cmp+jcc,
+ * the cmp of an inc+cmp+jcc and the pop of a push+pop */
+#[test]
+fn jumps_into_the_middle_of_a_superinstruction() {
+    let source = "start:
+    push 7
+    mov ecx, 5
+    mov eax, 5
+    jmp ecx
+    cmp eax, 5
+    je skip
+    prn 1
+skip:
+    mov ecx, 10
+    jmp ecx
+    inc eax
+    cmp eax, 5
+    jl skip
+    prn eax
+    mov ecx, 16
+    jmp ecx
+    push eax
+    pop ebx
+    prn ebx
+";
+    let path = program(PREFIX, "middle", source);
+    let (fused, _) = both_ways(&[&path]);
+    // The je sees the flags push left, the cmp runs without the inc and the pop without the push
+    assert_eq!(fused, "1\n5\n7\nexit Some(0)", "{}", fused);
+    assert_same(&[&path]);
+}
+
+// A fault in a later part stops at that part, after the earlier parts ran
+#[test]
+fn faults_inside_a_superinstruction() {
+    for (name, source, expected) in [
+        ("cmp", "start:\nmov ebx, 99999999\nmov eax, 1\ninc eax\ncmp eax, [ebx]\nje start\n",
+         "Fault at instruction 3: access to invalid address 99999999"),
+        ("jump", "start:\nmov eax, 1\ninc eax\ncmp eax, 2\nje 100\n",
+         "Fault at instruction 3: jump to invalid instruction 100"),
+        ("target", "start:\nmov ebx, 99999999\nmov eax, 1\ncmp eax, 1\nje [ebx]\n",
+         "Fault at instruction 3: access to invalid address 99999999"),
+        ("pop", "start:\nmov ebx, 99999999\npush 1\npop [ebx]\n",
+         "Fault at instruction 2: access to invalid address 99999999"),
+    ] {
+        let path = program(PREFIX, name, source);
+        let (fused, unfused) = both_ways(&[&path]);
+        assert!(fused.contains(expected), "{}: {}", name, fused);
+        assert_eq!(fused, unfused, "{}", name);
+    }
+}
+
+// A fault inside a superinstruction leaves the VM as the unfused program would
+#[test]
+fn resuming_after_a_fault_inside_a_superinstruction() {
+    let path = program(PREFIX, "resume", "start:\nmov eax, 1\ninc eax\ncmp eax, 2\nje 100\nprn eax\n");
+    let snapshot = &tmp_path("fuse.snap");
+    assert_eq!(rusty_vm(&["--save", snapshot, &path]).status.code(), Some(4));
+    let unfused = &tmp_path("fuse_unfused.snap");
+    assert_eq!(rusty_vm(&["--no-fuse", "--save", unfused, &path]).status.code(), Some(4));
+    assert_eq!(fs::read(snapshot).unwrap(), fs::read(unfused).unwrap());
+}
+
+// Running out of gas partway through a superinstruction stops at the part that could not be paid for
+#[test]
+fn gas_stops_inside_a_superinstruction() {
+    let source = "start:\nmov ecx, 0\nl:\ninc ecx\ncmp ecx, 3\njl l\npush ecx\npop eax\nprn eax\n";
+    let path = program(PREFIX, "gas", source);
+    let costs = &tmp_path("fuse_costs.txt");
+    fs::write(costs, "jl 4\npop 3\n").unwrap();
+    for gas in 1..=30 {
+        let gas = gas.to_string();
+        assert_same(&["--gas", &gas, &path]);
+        assert_same(&["--gas", &gas, "--gas-costs", costs, &path]);
+    }
+    let (fused, _) = both_ways(&["--gas", "3", &path]);
+    assert!(fused.contains("Out of gas at instruction 3 after 3 instructions"), "{}", fused);
+}
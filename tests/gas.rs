@@ -0,0 +1,80 @@
+mod common;
+
+use std::fs;
+
+use common::{rusty_vm, rusty_vm_with_input, tmp_path, program, printed, all_output};
+
+const PREFIX: &str = "gas";
+const SPIN: &str = "start:\nloop:\ninc eax\njmp loop\n";
+
+// A cost table called `name` holding `text`
+fn costs(name: &str, text: &str) -> String {
+    let path = tmp_path(&format!("gas_{}.txt", name));
+    fs::write(&path, text).unwrap();
+    path
+}
+
+#[test]
+fn infinite_loops_run_out_of_gas() {
+    let spin = &program(PREFIX, "spin", SPIN);
+    for args in [&["--gas", "1000", spin][..], &["--no-fuse", "--gas", "1000", spin]] {
+        let output = rusty_vm(args);
+        assert_eq!(output.status.code(), Some(2));
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "Out of gas at instruction 0 after 1000 instructions (1000 gas)\n");
+    }
+}
+
+// Each pass of the loop costs 3 for inc and 2 for jmp; what is left over never buys an inc
+#[test]
+fn cost_tables_price_each_opcode() {
+    let spin = &program(PREFIX, "priced", SPIN);
+    let table = &costs("priced", "# loop costs\ninc 3\njmp 2  # back to the top\n");
+    for budget in ["50", "52"] {
+        let output = rusty_vm(&["--gas", budget, "--gas-costs", table, spin]);
+        assert_eq!(output.status.code(), Some(2));
+        assert_eq!(String::from_utf8_lossy(&output.stderr), "Out of gas at instruction 0 after 20 instructions (50 gas)\n");
+    }
+    let output = rusty_vm(&["--gas", "53", "--gas-costs", table, spin]);
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "Out of gas at instruction 1 after 21 instructions (53 gas)\n");
+}
+
+// `int 2` waiting for input is only paid for once it has read a value
+#[test]
+fn waiting_for_input_costs_nothing() {
+    let reader = &program(PREFIX, "input", "start:\nint 2\nprn eax\nint 2\nprn eax\n");
+    let output = rusty_vm_with_input(&["--gas", "4", reader], "5\n6\n");
+    assert_eq!(printed(&output), ["5", "6"]);
+    let output = rusty_vm_with_input(&["--gas", "3", reader], "5\n6\n");
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "Out of gas at instruction 3 after 3 instructions (3 gas)\n");
+}
+
+#[test]
+fn bad_cost_tables_are_rejected() {
+    let spin = &program(PREFIX, "rejected", SPIN);
+    for (name, text, expected) in [
+        ("unknown", "bogus 3\n", "gas_unknown.txt:1: unknown instruction bogus"),
+        ("invalid", "inc 3\njmp x\n", "gas_invalid.txt:2: invalid cost x"),
+        ("shape", "inc\n", "gas_shape.txt:1: expected `mnemonic cost`"),
+    ] {
+        let output = rusty_vm(&["--gas", "5", "--gas-costs", &costs(name, text), spin]);
+        assert_eq!(output.status.code(), Some(1), "{}", name);
+        assert!(all_output(&output).contains(expected), "{}: {}", name, all_output(&output));
+    }
+    let output = rusty_vm(&["--gas-costs", &costs("unknown", "bogus 3\n"), spin]);
+    assert!(all_output(&output).contains("--gas-costs needs a --gas budget"));
+}
+
+// A run stopped for gas carries on from its snapshot with a new budget
+#[test]
+fn runs_resume_with_more_gas() {
+    let snapshot = &tmp_path("gas_resume.snap");
+    let part = rusty_vm(&["--gas", "7", "--save", snapshot, "tests/programs/loop.vm"]);
+    assert_eq!(part.status.code(), Some(2));
+    assert_eq!(String::from_utf8_lossy(&part.stderr), "Out of gas at instruction 3 after 7 instructions (7 gas)\n");
+
+    let rest = rusty_vm(&["--restore", snapshot, "--gas", "100", "tests/programs/loop.vm"]);
+    let mut resumed: Vec<String> = String::from_utf8_lossy(&part.stdout).lines().map(str::to_string).collect();
+    resumed.extend(printed(&rest));
+    assert_eq!(resumed, printed(&rusty_vm(&["tests/programs/loop.vm"])));
+}
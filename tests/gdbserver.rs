@@ -0,0 +1,195 @@
+mod common;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use common::{rusty_vm, tmp_path, program};
+
+struct Client {
+    stream: TcpStream,
+}
+
+// Serve `program` and connect to it
+fn gdbserver(program: &str) -> (Child, Client) {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_rusty-vm"))
+        .args(["gdbserver", "127.0.0.1:0", program])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut banner = String::new();
+    BufReader::new(server.stderr.take().unwrap()).read_line(&mut banner).unwrap();
+    let addr = banner.trim().strip_prefix("Listening on ").unwrap();
+    let stream = TcpStream::connect(addr).unwrap();
+    // Fail rather than hang when a reply never comes
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    (server, Client { stream })
+}
+
+impl Client {
+    fn send(&mut self, data: &str) {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", data, checksum).unwrap();
+    }
+
+    fn request(&mut self, data: &str) -> String {
+        self.send(data);
+        self.reply()
+    }
+
+    fn reply(&mut self) -> String {
+        let mut reply = Vec::new();
+        let mut byte = [0u8; 1];
+        loop {
+            self.stream.read_exact(&mut byte).unwrap();
+            match byte[0] {
+                b'+' if reply.is_empty() => continue,
+                b'$' => reply.clear(),
+                b'#' => break,
+                b => reply.push(b),
+            }
+        }
+        let mut checksum = [0u8; 2];
+        self.stream.read_exact(&mut checksum).unwrap();
+        self.stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+}
+
+#[test]
+fn gdbserver_breakpoints_registers_and_memory() {
+    let (mut server, mut gdb) = gdbserver("tests/programs/loop.vm");
+
+    assert!(gdb.request("qSupported").contains("qXfer:features:read+"));
+    assert_eq!(gdb.request("?"), "S05");
+
+    // Stop at the top of the loop after the first iteration
+    assert_eq!(gdb.request("Z0,2,1"), "OK");
+    assert_eq!(gdb.request("c"), "T05swbreak:;");
+    assert_eq!(gdb.request("c"), "T05swbreak:;");
+    assert_eq!(gdb.request("p0"), "01000000");
+    assert_eq!(gdb.request("p8"), "02000000");
+    assert_eq!(gdb.request("z0,2,1"), "OK");
+
+    // Single step and step back again
+    assert_eq!(gdb.request("s"), "S05");
+    assert_eq!(gdb.request("p0"), "02000000");
+    assert_eq!(gdb.request("bs"), "S05");
+    assert_eq!(gdb.request("p0"), "01000000");
+
+    // The store to [100] trips a write watchpoint
+    assert_eq!(gdb.request("Z2,64,4"), "OK");
+    assert_eq!(gdb.request("c"), "T05watch:64;");
+    assert_eq!(gdb.request("m64,4"), "05000000");
+    assert_eq!(gdb.request("M64,4:09000000"), "OK");
+    assert_eq!(gdb.request("m64,4"), "09000000");
+
+    assert_eq!(gdb.request("P2=07000000"), "OK");
+    assert_eq!(gdb.request("g").len(), 19 * 8);
+    assert_eq!(gdb.request("c"), "W00");
+    assert_eq!(gdb.request("D"), "OK");
+
+    let mut output = String::new();
+    server.stdout.take().unwrap().read_to_string(&mut output).unwrap();
+    assert_eq!(output, "1\n2\n3\n4\n5\n5\n");
+    assert!(server.wait().unwrap().success());
+}
+
+#[test]
+fn gdbserver_keeps_eip_in_the_program() {
+    let (mut server, mut gdb) = gdbserver("tests/programs/loop.vm");
+
+    // loop.vm has 10 instructions and the end of the program at 10
+    assert_eq!(gdb.request("P8=0b000000"), "E01");
+    assert_eq!(gdb.request("P8=ffffffff"), "E01");
+    // Nothing is written when eip is out of range, not even eax
+    let regs = gdb.request("g");
+    let bad = format!("G07000000{}64000000{}", &regs[8..64], &regs[72..]);
+    assert_eq!(gdb.request(&bad), "E01");
+    assert_eq!(gdb.request("g"), regs);
+
+    assert_eq!(gdb.request("P8=0a000000"), "OK");
+    assert_eq!(gdb.request("c"), "W00");
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn gdbserver_rejects_points_and_reads_out_of_range() {
+    let (mut server, mut gdb) = gdbserver("tests/programs/loop.vm");
+
+    // Breakpoints on instructions past the end, watchpoints past the end of memory or of no length
+    assert_eq!(gdb.request("Z0,b,1"), "E01");
+    assert_eq!(gdb.request("Z0,100000000,1"), "E01");
+    assert_eq!(gdb.request("Z2,64,ffffffffffffffff"), "E01");
+    assert_eq!(gdb.request("Z3,3fffffe,4"), "E01");
+    assert_eq!(gdb.request("Z4,64,0"), "E01");
+    assert_eq!(gdb.request("qXfer:features:read:target.xml:10,ffffffffffffffff"), "E01");
+    assert_eq!(gdb.request("Z2,3fffffc,4"), "OK");
+    assert_eq!(gdb.request("c"), "W00");
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn gdbserver_only_replaces_a_stale_socket() {
+    let path = tmp_path("gdbserver_not_a_socket");
+    fs::write(&path, "keep me").unwrap();
+    let output = rusty_vm(&["gdbserver", &format!("unix:{}", path), "tests/programs/loop.vm"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stderr), format!("gdbserver: {} is not a socket\n", path));
+    assert_eq!(fs::read_to_string(&path).unwrap(), "keep me");
+
+    // A socket left behind by an earlier server is bound again
+    let path = tmp_path("gdbserver_stale.sock");
+    let _ = fs::remove_file(&path);
+    drop(UnixListener::bind(&path).unwrap());
+    let mut server = Command::new(env!("CARGO_BIN_EXE_rusty-vm"))
+        .args(["gdbserver", &format!("unix:{}", path), "tests/programs/loop.vm"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut banner = String::new();
+    BufReader::new(server.stderr.take().unwrap()).read_line(&mut banner).unwrap();
+    assert_eq!(banner, format!("Listening on unix:{}\n", path));
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn gdbserver_answers_packets_sent_while_running() {
+    let (mut server, mut gdb) = gdbserver(&program("gdbserver", "spin", "start:\nloop:\ninc eax\njmp loop\n"));
+
+    // A packet that arrives while the program runs is answered once the ^C stops it
+    gdb.send("c");
+    gdb.send("qC");
+    gdb.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(gdb.reply(), "S02");
+    assert_eq!(gdb.reply(), "QC1");
+    assert_eq!(gdb.request("?"), "S05");
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
+
+#[test]
+fn gdbserver_reverse_step_takes_back_the_exit_code() {
+    let source = "start:\nmov eax, 5\ncmp eax, ebx\nje done\nint 0\ndone:\n";
+    let (mut server, mut gdb) = gdbserver(&program("gdbserver", "exit", source));
+
+    assert_eq!(gdb.request("c"), "W05");
+    // Back to the je, then take it to run off the end instead
+    assert_eq!(gdb.request("bs"), "S05");
+    assert_eq!(gdb.request("bs"), "S05");
+    assert_eq!(gdb.request("p8"), "02000000");
+    assert_eq!(gdb.request("P11=01000000"), "OK");
+    assert_eq!(gdb.request("c"), "W00");
+
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
@@ -0,0 +1,101 @@
+#![cfg(all(feature = "jit", target_arch = "x86_64", target_os = "linux"))]
+
+use std::process::{Command, Output};
+
+fn run(mode: &str, program: &str) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rusty-vm"))
+        .args([mode, program])
+        .output()
+        .unwrap()
+}
+
+// The JIT has to print exactly what the interpreter prints and exit the same way
+fn assert_same_as_interpreter(program: &str) {
+    let interpreted = run("--no-fuse", program);
+    let compiled = run("--jit", program);
+    assert_eq!(
+        String::from_utf8_lossy(&compiled.stdout),
+        String::from_utf8_lossy(&interpreted.stdout),
+        "{}",
+        program
+    );
+    assert_eq!(compiled.status.code(), interpreted.status.code(), "{}", program);
+}
+
+#[test]
+fn jit_arithmetic_and_memory() {
+    assert_same_as_interpreter("tests/programs/alu.vm");
+}
+
+#[test]
+fn jit_conditional_jumps() {
+    assert_same_as_interpreter("tests/programs/branches.vm");
+}
+
+#[test]
+fn jit_overflow_and_flags() {
+    assert_same_as_interpreter("tests/programs/overflow.vm");
+}
+
+#[test]
+fn jit_extended_instructions() {
+    assert_same_as_interpreter("tests/programs/isa.vm");
+}
+
+#[test]
+fn jit_stack_frames() {
+    assert_same_as_interpreter("tests/programs/frames.vm");
+}
+
+#[test]
+fn jit_sub_registers() {
+    assert_same_as_interpreter("tests/programs/subregs.vm");
+}
+
+#[test]
+fn jit_stack() {
+    assert_same_as_interpreter("tests/programs/stack.vm");
+}
+
+#[test]
+fn jit_loops_and_prn_fallback() {
+    assert_same_as_interpreter("tests/programs/loop.vm");
+    assert_same_as_interpreter("benches/loop.vm");
+}
+
+#[test]
+fn jit_falls_back_on_faults() {
+    let compiled = run("--jit", "tests/programs/div_zero.vm");
+    assert!(!compiled.status.success());
+    assert_same_as_interpreter("tests/programs/div_zero.vm");
+    assert_same_as_interpreter("tests/programs/bad_jump.vm");
+    assert_same_as_interpreter("tests/programs/bad_address.vm");
+}
+
+#[test]
+fn jit_interrupts() {
+    assert_same_as_interpreter("tests/programs/interrupts.vm");
+}
+
+#[test]
+fn jit_loops_and_strings() {
+    assert_same_as_interpreter("tests/programs/strings.vm");
+}
+
+#[test]
+fn jit_floats() {
+    assert_same_as_interpreter("tests/programs/floats.vm");
+}
+
+#[test]
+fn jit_data_sections() {
+    assert_same_as_interpreter("tests/programs/data.vm");
+    assert_same_as_interpreter("tests/programs/literals.vm");
+}
+
+#[test]
+fn jit_refuses_wide_programs() {
+    let compiled = run("--jit", "tests/programs/wide.vm");
+    assert!(!compiled.status.success());
+    assert!(String::from_utf8_lossy(&compiled.stderr).contains("64-bit programs are not supported"));
+}
@@ -0,0 +1,21 @@
+mod common;
+
+use common::{rusty_vm, printed};
+
+// je, jne, jg, jge, jl and jle after comparing 1, 2 and 3 against 2
+#[test]
+fn conditional_jumps_match_tinyvm() {
+    let expected = [
+        "0", "1", "0", "0", "1", "1",
+        "1", "0", "0", "1", "0", "1",
+        "0", "1", "1", "1", "0", "0",
+    ];
+    assert_eq!(printed(&rusty_vm(&["tests/programs/jumps.vm"])), expected);
+    assert_eq!(printed(&rusty_vm(&["--no-fuse", "tests/programs/jumps.vm"])), expected);
+}
+
+// Through the stack with jmp [esp], then through a register into the middle of a block
+#[test]
+fn indirect_jumps_land_mid_block() {
+    assert_eq!(printed(&rusty_vm(&["tests/programs/indirect.vm"])), ["7", "2", "4", "6", "8", "10", "10"]);
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use common::{rusty_vm, program, printed};
+
+const PREFIX: &str = "literals";
+
+#[test]
+fn literals_in_data_and_operands() {
+    let greeting = "hello, world # not a comment".bytes().map(|b| b.to_string());
+    let mut expected: Vec<String> = greeting.collect();
+    for val in ["9", "34", "113", "34", "92", "65", "0", "122", "6513249", "120", "32", "1684234849", "-1", "1"] {
+        expected.push(val.to_string());
+    }
+    assert_eq!(printed(&rusty_vm(&["tests/programs/literals.vm"])), expected);
+}
+
+#[test]
+fn quoted_separators_stay_in_the_literal() {
+    let source = "start:\nprn ','\nprn ' ' # a comment's quote\nprn '#'\nprn \":\"\n";
+    assert_eq!(printed(&rusty_vm(&[&program(PREFIX, "separators", source)])), ["44", "32", "35", "58"]);
+}
+
+#[test]
+fn malformed_literals_are_refused() {
+    for (name, source, expected) in [
+        ("unterminated", "start:\nmov al, \"abc\n", "Error: line 2: unterminated literal"),
+        ("char", "start:\nmov al, 'ab'\n", "Error: line 2: character literal 'ab' must be one byte"),
+        ("escape", "start:\nmov al, '\\q'\n", "Error: line 2: invalid escape \\q in '\\q'"),
+        ("hex", "start:\nmov al, '\\x4'\n", "Error: line 2: invalid escape \\x4 in '\\x4'"),
+        ("trailing", "start:\nmov al, \"a\"b\n", "Error: line 2: malformed literal \"a\"b"),
+    ] {
+        let output = rusty_vm(&[&program(PREFIX, name, source)]);
+        assert!(!output.status.success(), "{}", name);
+        assert!(String::from_utf8_lossy(&output.stdout).contains(expected), "{}", name);
+    }
+}
@@ -0,0 +1,51 @@
+mod common;
+
+use common::{rusty_vm, program, all_output};
+
+const PREFIX: &str = "memory";
+
+// Memory is 64 MiB; the last word starts at 67108860
+#[test]
+fn accesses_outside_memory_fault() {
+    for (name, source, instr_idx, addr) in [
+        ("load", "start:\nmov ebx, 100000000\nmov eax, [ebx+0]\n", 1, 100000000u64),
+        ("store", "start:\nmov ebx, -4\nmov [ebx+0], 1\n", 1, 4294967292),
+        ("straddle", "start:\nmov ebx, 67108862\nmov eax, [ebx+0]\n", 1, 67108862),
+        ("push", "start:\nmov esp, 0\npush 1\n", 1, 4294967292),
+        ("call", "start:\nmov esp, 0\ncall start\n", 1, 4294967292),
+        ("pop", "start:\nmov esp, 67108864\npop eax\n", 1, 67108864),
+        ("string", "start:\nmov edi, 67108864\nstosb\n", 1, 67108864),
+        ("float", "start:\nmov ebx, 67108860\nfld f0, [ebx+0]\n", 1, 67108860),
+        ("wide", "%bits 64\nstart:\nmov rbx, 5000000000\nmov rax, [rbx+0]\n", 1, 5000000000),
+    ] {
+        let output = rusty_vm(&[&program(PREFIX, name, source)]);
+        assert_eq!(output.status.code(), Some(4), "{}: {}", name, all_output(&output));
+        let expected = format!("Fault at instruction {}: access to invalid address {}", instr_idx, addr);
+        assert!(all_output(&output).contains(&expected), "{}: {}", name, all_output(&output));
+    }
+}
+
+#[test]
+fn absolute_addresses_outside_memory_do_not_assemble() {
+    for (name, source, expected) in [
+        ("absolute", "start:\nmov eax, [70000000]\n", "Error: line 2: address 70000000 is outside memory"),
+        ("straddle", "start:\nmov [67108862], 1\n", "Error: line 2: address 67108862 is outside memory"),
+        ("negative", "start:\nprn [-4]\n", "Error: line 2: address 4294967292 is outside memory"),
+        ("label", "section .data\nx:\ndd 1\nsection .text\nstart:\nprn [x+67000000]\n",
+            "Error: line 6: address 69097152 is outside memory"),
+        ("wide", "%bits 64\nstart:\nmov rax, [67108860]\n", "Error: line 3: address 67108860 is outside memory"),
+    ] {
+        let output = rusty_vm(&[&program(PREFIX, name, source)]);
+        assert!(all_output(&output).contains(expected), "{}: {}", name, all_output(&output));
+    }
+    let output = rusty_vm(&[&program(PREFIX, "last", "start:\nmov [67108860], 5\nprn [67108860]\n")]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "5\n");
+}
+
+#[test]
+fn the_last_word_of_memory_is_usable() {
+    let output = rusty_vm(&["tests/programs/bad_address.vm"]);
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "7\n");
+    assert!(all_output(&output).contains("Fault at instruction 4: access to invalid address 67108862"));
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::{rusty_vm, program, printed};
+
+const PREFIX: &str = "numbers";
+
+#[test]
+fn every_spelling_of_a_number() {
+    let expected = [
+        "1000000", "31", "31", "-16", "170", "511", "255", "-16", "255", "5", "-1", "-2147483648",
+        "97", "65", "-1", "1000", "255", "8",
+    ];
+    assert_eq!(printed(&rusty_vm(&["tests/programs/numbers.vm"])), expected);
+}
+
+#[test]
+fn wide_numbers_in_64_bit_mode() {
+    let source = "%bits 64\nstart:\nprn 0xffff_ffff_ffff_ffff\nprn -0x8000000000000000\nprn \"abcdefgh\"\n";
+    let expected = ["-1", "-9223372036854775808", "7523094288207667809"];
+    assert_eq!(printed(&rusty_vm(&[&program(PREFIX, "wide", source)])), expected);
+}
+
+#[test]
+fn malformed_numbers_stop_assembly() {
+    for (name, source, expected) in [
+        ("suffix", "start:\nprn 12z\n", "Error: line 2: malformed number 12z"),
+        ("prefix", "start:\nnop\nprn 0x\n", "Error: line 3: malformed number 0x"),
+        ("digit", "start:\nprn 0b102\n", "Error: line 2: malformed number 0b102"),
+        ("separator", "start:\nprn 1__\n", "Error: line 2: malformed number 1__"),
+        ("tinyvm", "start:\nprn 1|z\n", "Error: line 2: malformed number 1|z"),
+        ("range", "start:\nprn 4294967296\n", "Error: line 2: 4294967296 does not fit in 32 bits"),
+        ("name", "start:\njmp nowhere\n", "Error: line 2: unknown name nowhere"),
+        ("address", "start:\nprn [ebp+0xg]\n", "Error: line 2: malformed number 0xg"),
+        ("data", "section .data\ndd 1, 2h3\n", "Error: line 2: malformed number 2h3"),
+        ("string", "start:\nprn \"123456789\"\n", "Error: line 2: \"123456789\" is longer than 8 bytes"),
+    ] {
+        let output = rusty_vm(&[&program(PREFIX, name, source)]);
+        assert!(!output.status.success(), "{}", name);
+        assert!(String::from_utf8_lossy(&output.stdout).contains(expected), "{}: {}", name,
+            String::from_utf8_lossy(&output.stdout));
+    }
+}
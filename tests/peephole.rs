@@ -0,0 +1,43 @@
+mod common;
+
+use common::{rusty_vm, tmp_path, program, printed, all_output};
+
+const PREFIX: &str = "peephole";
+
+// `asm -O` a program, run the listing and compare it with running the program; returns the report
+fn assert_optimized_runs_the_same(path: &str) -> String {
+    let name = path.rsplit('/').next().unwrap();
+    let optimized = &tmp_path(&format!("peephole_optimized_{}", name));
+    let asm = rusty_vm(&["asm", "-O", "-o", optimized, path]);
+    assert!(asm.status.success(), "{}: {}", path, all_output(&asm));
+    assert_eq!(printed(&rusty_vm(&[optimized])), printed(&rusty_vm(&[path])), "{}", path);
+    String::from_utf8_lossy(&asm.stderr).into_owned()
+}
+
+#[test]
+fn optimized_programs_print_the_same() {
+    for path in ["alu", "branches", "loop", "indirect", "stack", "frames", "jumps", "strings", "data"] {
+        assert_optimized_runs_the_same(&format!("tests/programs/{}.vm", path));
+    }
+}
+
+#[test]
+fn dead_instructions_are_dropped() {
+    let source = "start:\nmov eax, 2\nadd eax, 0\nmov eax, eax\njmp next\nnext:\nprn eax\n";
+    let report = assert_optimized_runs_the_same(&program(PREFIX, "dead", source));
+    assert!(report.contains("peephole: 5 -> 2 instructions"), "{}", report);
+}
+
+// Code addresses the program computes with keep every instruction where it was
+#[test]
+fn computed_code_addresses_keep_their_instructions() {
+    for (name, source) in [
+        ("push_jmp", "start:\npush target\nadd eax, 0\njmp [esp]\nprn 1\ntarget:\nprn 5\n"),
+        ("push_ret", "start:\npush target\nadd eax, 0\nret\nprn 1\ntarget:\nprn 5\n"),
+        ("label_value", "start:\nadd eax, 0\ntarget:\nprn target\n"),
+        ("sub_register", "start:\nmov eax, target\nadd ebx, 0\njmp ax\nprn 1\ntarget:\nprn 5\n"),
+    ] {
+        let report = assert_optimized_runs_the_same(&program(PREFIX, name, source));
+        assert!(report.contains("removed instructions were replaced by nop"), "{}: {}", name, report);
+    }
+}
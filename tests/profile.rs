@@ -0,0 +1,62 @@
+mod common;
+
+use std::fs;
+
+use common::{rusty_vm, tmp_path, program, printed};
+
+const PROGRAM: &str = "tests/programs/profile.vm";
+
+#[test]
+fn profile_counts_instructions_and_orders_by_cycles() {
+    let report = &tmp_path("profile.txt");
+    assert_eq!(printed(&rusty_vm(&["profile", "-o", report, PROGRAM])), ["110"]);
+    let report = fs::read_to_string(report).unwrap();
+    let lines: Vec<&str> = report.lines().collect();
+
+    assert_eq!(lines[0], "Executed 46 instructions, 99 cycles");
+    // prn runs once but costs the most; the loop body ties and keeps program order
+    let hottest: Vec<(&str, &str, &str, &str)> = lines[4..14].iter()
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            (fields[0], fields[2], fields[3], fields[4])
+        })
+        .collect();
+    assert_eq!(hottest, [
+        ("7", "prn", "1", "50"),
+        ("1", "add", "10", "10"),
+        ("2", "dec", "10", "10"),
+        ("3", "cmp", "10", "10"),
+        ("4", "jg", "10", "10"),
+        ("5", "call", "1", "3"),
+        ("6", "mul", "1", "3"),
+        ("0", "mov", "1", "1"),
+        ("8", "xor", "1", "1"),
+        ("9", "int", "1", "1"),
+    ], "{}", report);
+
+    for line in [
+        "f                                       4           55  55.56%",
+        "loop                                   41           43  43.43%",
+        "start                                 0           44           99 100.00%",
+        "f                                     1           55           55  55.56%",
+    ] {
+        assert!(lines.contains(&line), "{}: {}", line, report);
+    }
+}
+
+#[test]
+fn folded_stacks_split_self_cycles_by_caller() {
+    let folded = &tmp_path("profile.folded");
+    assert_eq!(printed(&rusty_vm(&["profile", "-o", &tmp_path("profile_report.txt"), "--folded", folded, PROGRAM])), ["110"]);
+    assert_eq!(fs::read_to_string(folded).unwrap(), "start 44\nstart;f 55\n");
+}
+
+// The unsigned forms cost what their signed counterparts do
+#[test]
+fn unsigned_multiply_and_divide_cost_like_signed() {
+    let report = &tmp_path("profile_unsigned.txt");
+    let source = "start:\nmov eax, 7\numul eax, 3\nudiv eax, 2\numod eax, 4\nprn eax\n";
+    assert_eq!(printed(&rusty_vm(&["profile", "-o", report, &program("profile", "unsigned", source)])), ["10"]);
+    let report = fs::read_to_string(report).unwrap();
+    assert_eq!(report.lines().next(), Some("Executed 5 instructions, 94 cycles"), "{}", report);
+}
@@ -0,0 +1,34 @@
+mod common;
+
+use common::{rusty_vm, tmp_path};
+
+// Stopping a run every few instructions and resuming it from its snapshot prints what one run prints
+#[test]
+fn snapshot_resumes_where_it_stopped() {
+    let program = "tests/programs/loop.vm";
+    let snapshot = &tmp_path("loop.snap");
+
+    let whole = rusty_vm(&[program]);
+    let mut stdout = Vec::new();
+    let mut part = rusty_vm(&["--gas", "5", "--save", snapshot, program]);
+    while part.status.code() == Some(2) {
+        stdout.extend_from_slice(&part.stdout);
+        part = rusty_vm(&["--gas", "5", "--restore", snapshot, "--save", snapshot, program]);
+    }
+    stdout.extend_from_slice(&part.stdout);
+
+    assert!(whole.status.success());
+    assert_eq!(part.status.code(), whole.status.code());
+    assert_eq!(String::from_utf8_lossy(&stdout), String::from_utf8_lossy(&whole.stdout));
+}
+
+#[test]
+fn snapshot_of_another_program_is_refused() {
+    let snapshot = &tmp_path("stack.snap");
+
+    let saved = rusty_vm(&["--gas", "3", "--save", snapshot, "tests/programs/stack.vm"]);
+    assert_eq!(saved.status.code(), Some(2));
+    let restored = rusty_vm(&["--restore", snapshot, "tests/programs/alu.vm"]);
+    assert_eq!(restored.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&restored.stderr).contains("different program"));
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use common::{rusty_vm, tmp_path, program, printed_ints};
+
+const PREFIX: &str = "strings";
+
+#[test]
+fn loops_and_string_instructions() {
+    let expected = [1010, 0, 1010, 168364039, 5, 1005, 2, 1008, -1, 65535, 1, 5, 95, 3, 1000];
+    assert_eq!(printed_ints(&rusty_vm(&["tests/programs/strings.vm"])), expected);
+    assert_eq!(printed_ints(&rusty_vm(&["--no-fuse", "tests/programs/strings.vm"])), expected);
+}
+
+#[test]
+fn repeated_instructions_resume_where_they_stopped() {
+    let source = "start:\nmov ecx, 100\nmov edi, 1000\nmov eax, 7\nrep stosb\nprn ecx\nprn edi\nprn [1096]\n";
+    let path = program(PREFIX, "resume", source);
+    let snapshot = &tmp_path("strings_resume.snap");
+
+    // Each iteration costs gas, so the budget runs out halfway through
+    let part = rusty_vm(&["--gas", "20", "--save", snapshot, &path]);
+    assert_eq!(part.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&part.stderr).contains("Out of gas at instruction 3"));
+    let rest = rusty_vm(&["--restore", snapshot, &path]);
+    assert_eq!(printed_ints(&rest), [0, 1100, 0x07070707]);
+}
+
+#[test]
+fn rep_needs_a_string_instruction() {
+    for (name, source, error) in [
+        ("add", "start:\nrep add eax, 1\n", "Error: line 2: rep cannot prefix add"),
+        ("movs", "start:\nrepe movsb\n", "Error: line 2: repe cannot prefix movsb"),
+        ("alone", "start:\nrepnz\n", "Error: line 2: repne needs a string instruction"),
+    ] {
+        let output = rusty_vm(&[&program(PREFIX, name, source)]);
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains(error), "{}", name);
+    }
+}
+
+#[test]
+fn prefixes_round_trip() {
+    let source = "start:\nrep movsw\nrepz cmpsb\nrepnz scasw\nrep scasb\nloope start\nloopne start\n";
+    let output = rusty_vm(&["asm", &program(PREFIX, "asm", source)]);
+    let listing = String::from_utf8_lossy(&output.stdout);
+    for line in ["rep movsw", "repe cmpsb", "repne scasw", "repe scasb", "loopz start", "loopnz start"] {
+        assert!(listing.contains(line), "{}: {}", line, listing);
+    }
+}
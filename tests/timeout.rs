@@ -0,0 +1,41 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::{rusty_vm, tmp_path, program, printed, all_output};
+
+const PREFIX: &str = "timeout";
+
+// --timeout runs through rvm_vm_run_for, whose watchdog thread raises the
+// interrupt handle while the interpreter is busy on the main thread
+#[test]
+fn timeouts_end_infinite_loops() {
+    let path = program(PREFIX, "spin", "start:\nloop:\ninc eax\njmp loop\n");
+    let started = Instant::now();
+    let output = rusty_vm(&["--timeout", "100", &path]);
+    let elapsed = started.elapsed();
+
+    assert_eq!(output.status.code(), Some(3), "{}", all_output(&output));
+    assert!(all_output(&output).contains("Timed out at instruction "), "{}", all_output(&output));
+    assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+}
+
+#[test]
+fn programs_that_finish_are_not_held_up_by_the_watchdog() {
+    let path = program(PREFIX, "quick", "start:\nmov eax, 7\nprn eax\n");
+    let started = Instant::now();
+    assert_eq!(printed(&rusty_vm(&["--timeout", "60000", &path])), ["7"]);
+    assert!(started.elapsed() < Duration::from_secs(10), "{:?}", started.elapsed());
+}
+
+#[test]
+fn timed_out_runs_resume_from_a_snapshot() {
+    let path = program(PREFIX, "count", "start:\nmov ecx, 5000000\nl:\ndec ecx\ncmp ecx, 0\njg l\nprn ecx\nprn 1\n");
+    let snapshot = &tmp_path("timeout.snap");
+
+    let part = rusty_vm(&["--timeout", "10", "--save", snapshot, &path]);
+    assert_eq!(part.status.code(), Some(3), "{}", all_output(&part));
+    assert!(part.stdout.is_empty());
+    assert_eq!(printed(&rusty_vm(&["--restore", snapshot, &path])), ["0", "1"]);
+}
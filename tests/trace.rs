@@ -0,0 +1,119 @@
+mod common;
+
+use std::fs;
+
+use common::{rusty_vm, tmp_path, printed, all_output};
+
+const PROGRAM: &str = "tests/programs/trace.vm";
+
+fn json_trace(name: &str, flags: &[&str]) -> Vec<String> {
+    let path = &tmp_path(name);
+    let mut args = vec!["trace", "-o", path];
+    args.extend_from_slice(flags);
+    args.push(PROGRAM);
+    assert_eq!(printed(&rusty_vm(&args)), ["6"]);
+    fs::read_to_string(path).unwrap().lines().map(str::to_string).collect()
+}
+
+#[test]
+fn json_trace_records_registers_and_memory_writes() {
+    assert_eq!(json_trace("trace.jsonl", &[]), [
+        r#"{"n":0,"idx":0,"op":"mov","args":["eax","5"],"regs":{"eax":5},"flags":0,"mem":[]}"#,
+        r#"{"n":1,"idx":1,"op":"mov","args":["[100]","eax"],"regs":{},"flags":0,"mem":[{"addr":100,"bytes":"05000000"}]}"#,
+        r#"{"n":2,"idx":2,"op":"inc","args":["eax"],"regs":{"eax":6},"flags":0,"mem":[]}"#,
+        r#"{"n":3,"idx":3,"op":"add","args":["ebx","eax"],"regs":{"ebx":6},"flags":0,"mem":[]}"#,
+        r#"{"n":4,"idx":4,"op":"push","args":["ebx"],"regs":{"esp":2097148},"flags":0,"mem":[{"addr":2097148,"bytes":"06000000"}]}"#,
+        r#"{"n":5,"idx":5,"op":"cmp","args":["ebx","5"],"regs":{},"flags":2,"mem":[]}"#,
+        r#"{"n":6,"idx":6,"op":"prn","args":["ebx"],"regs":{},"flags":2,"mem":[]}"#,
+    ]);
+}
+
+#[test]
+fn trace_filters_by_label_range() {
+    let trace = json_trace("trace_range.jsonl", &["--from", "middle", "--to", "end"]);
+    assert_eq!(trace.len(), 3, "{:?}", trace);
+    assert!(trace[0].starts_with(r#"{"n":3,"idx":3,"op":"add""#), "{}", trace[0]);
+    assert!(trace[2].starts_with(r#"{"n":5,"idx":5,"op":"cmp""#), "{}", trace[2]);
+
+    let output = rusty_vm(&["trace", "--from", "nowhere", PROGRAM]);
+    assert!(!output.status.success());
+    assert!(all_output(&output).contains("unknown name nowhere"));
+}
+
+/* One decoded binary record: index, opcode, operands as (kind, value),
+ * register deltas, flags and memory writes */
+#[derive(Debug, PartialEq)]
+struct Record {
+    n: u64,
+    idx: u32,
+    opcode: u8,
+    args: Vec<(u8, i64)>,
+    regs: Vec<(u8, i64)>,
+    flags: u32,
+    mem: Vec<(u32, Vec<u8>)>,
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> &[u8] {
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        head
+    }
+
+    fn u8(&mut self) -> u8 { self.take(1)[0] }
+    fn u16(&mut self) -> u16 { u16::from_le_bytes(self.take(2).try_into().unwrap()) }
+    fn u32(&mut self) -> u32 { u32::from_le_bytes(self.take(4).try_into().unwrap()) }
+    fn u64(&mut self) -> u64 { u64::from_le_bytes(self.take(8).try_into().unwrap()) }
+    fn i64(&mut self) -> i64 { self.u64() as i64 }
+
+    // Only the operand kinds trace.vm uses: registers, addresses and values
+    fn record(&mut self) -> Record {
+        let (n, idx, opcode) = (self.u64(), self.u32(), self.u8());
+        let args = (0..self.u8()).map(|_| (self.u8(), self.i64())).collect();
+        let regs = (0..self.u8()).map(|_| (self.u8(), self.i64())).collect();
+        let flags = self.u32();
+        let mem = (0..self.u16()).map(|_| {
+            let addr = self.u32();
+            let len = self.u8() as usize;
+            (addr, self.take(len).to_vec())
+        }).collect();
+        Record { n, idx, opcode, args, regs, flags, mem }
+    }
+}
+
+#[test]
+fn binary_trace_matches_the_documented_layout() {
+    let path = &tmp_path("trace.bin");
+    assert_eq!(printed(&rusty_vm(&["trace", "--format", "bin", "-o", path, PROGRAM])), ["6"]);
+    let bytes = fs::read(path).unwrap();
+    assert_eq!(&bytes[..5], b"RVMT\x02");
+
+    let mut reader = Reader(&bytes[5..]);
+    let mut records = Vec::new();
+    while !reader.0.is_empty() {
+        records.push(reader.record());
+    }
+
+    let (reg, mem, val) = (0, 1, 2);
+    let (eax, ebx, esp) = (0, 1, 6);
+    let record = |n: u64, opcode, args: &[(u8, i64)], regs: &[(u8, i64)], flags, mem: &[(u32, &[u8])]| Record {
+        n,
+        idx: n as u32,
+        opcode,
+        args: args.to_vec(),
+        regs: regs.to_vec(),
+        flags,
+        mem: mem.iter().map(|(addr, bytes)| (*addr, bytes.to_vec())).collect(),
+    };
+    assert_eq!(records, [
+        record(0, 0x2, &[(reg, eax), (val, 5)], &[(eax as u8, 5)], 0, &[]),
+        record(1, 0x2, &[(mem, 100), (reg, eax)], &[], 0, &[(100, &[5, 0, 0, 0])]),
+        record(2, 0x7, &[(reg, eax)], &[(eax as u8, 6)], 0, &[]),
+        record(3, 0x9, &[(reg, ebx), (reg, eax)], &[(ebx as u8, 6)], 0, &[]),
+        record(4, 0x3, &[(reg, ebx)], &[(esp as u8, 2097148)], 0, &[(2097148, &[6, 0, 0, 0])]),
+        record(5, 0x15, &[(reg, ebx), (val, 5)], &[], 2, &[]),
+        record(6, 0x1F, &[(reg, ebx)], &[], 2, &[]),
+    ]);
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use common::{rusty_vm, tmp_path, program, printed, all_output};
+
+const PREFIX: &str = "wide";
+
+#[test]
+fn wide_registers_memory_and_stack() {
+    let expected = [
+        "10000000000", "5", "4294967295", "-1", "8", "-9223372036854775808", "1099511627776",
+        "-9223372036854775808", "9223372036854775807", "8", "9223372036854775807", "4294967295",
+        "14000000000", "28000000000",
+    ];
+    assert_eq!(printed(&rusty_vm(&["tests/programs/wide.vm"])), expected);
+}
+
+#[test]
+fn wide_listing_round_trips() {
+    let listing = rusty_vm(&["asm", "tests/programs/wide.vm"]);
+    let text = String::from_utf8_lossy(&listing.stdout);
+    for line in ["%bits 64", "mov rax, 5000000000", "movsx rcx, ebx", "mov rax, [rbp+16]"] {
+        assert!(text.contains(line), "{}: {}", line, text);
+    }
+
+    let path = &tmp_path("wide_listing.vm");
+    assert!(rusty_vm(&["asm", "-o", path, "tests/programs/wide.vm"]).status.success());
+    assert_eq!(printed(&rusty_vm(&[path])), printed(&rusty_vm(&["tests/programs/wide.vm"])));
+}
+
+#[test]
+fn wide_names_and_values_need_64_bit_mode() {
+    for (name, source, expected) in [
+        ("reg", "start:\nmov rax, 1\n", "Error: line 2: unknown name rax"),
+        ("value", "start:\nmov eax, 5000000000\n", "Error: line 2: 5000000000 does not fit in 32 bits"),
+        ("width", "%bits 16\nstart:\nmov eax, 1\n", "Unsupported width"),
+    ] {
+        let output = rusty_vm(&[&program(PREFIX, name, source)]);
+        assert!(all_output(&output).contains(expected), "{}: {}", name, all_output(&output));
+    }
+}
+
+#[test]
+fn wide_registers_survive_a_snapshot() {
+    let source = "%bits 64\nstart:\nmov r9, 5000000000\nadd r9, r9\nmov eax, 1\nprn r9\n";
+    let path = program(PREFIX, "snapshot", source);
+    let snapshot = &tmp_path("wide.snap");
+
+    let part = rusty_vm(&["--gas", "2", "--save", snapshot, &path]);
+    assert_eq!(part.status.code(), Some(2));
+    assert_eq!(printed(&rusty_vm(&["--restore", snapshot, &path])), ["10000000000"]);
+}
+
+#[test]
+fn aot_refuses_wide_programs() {
+    let output = rusty_vm(&["aot", "tests/programs/wide.vm"]);
+    assert!(!output.status.success());
+    assert!(all_output(&output).contains("64-bit programs cannot be translated"));
+}